@@ -0,0 +1,510 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    io::{self, AsyncRead, AsyncWrite, ReadBuf},
+    net::{TcpSocket, TcpStream, ToSocketAddrs},
+    sync::mpsc,
+    time::{self, Instant},
+};
+
+/// Counts a server's connections and the bytes they transfer.
+#[derive(Debug, Default)]
+pub struct ConnMetrics {
+    accepted: AtomicU64,
+    closed: AtomicU64,
+    read_bytes: AtomicU64,
+    write_bytes: AtomicU64,
+}
+
+/// Records an accepted connection's IO in `ConnMetrics`.
+#[derive(Debug)]
+pub struct CountedIo<T> {
+    io: T,
+    metrics: Arc<ConnMetrics>,
+}
+
+/// Configures clients to report how each of their connections closes, e.g. to test how proxies
+/// time out idle connections.
+#[derive(Clone, Debug)]
+pub struct ConnWatch {
+    tcp_keepalive: Option<Duration>,
+    closes: mpsc::UnboundedSender<Closed>,
+}
+
+/// Binds client connections to local IP addresses, rotating across them, so that load may use
+/// more ephemeral ports than a single address provides. By default, connections are bound by the
+/// OS.
+#[derive(Clone, Debug, Default)]
+pub struct Bind {
+    addrs: Arc<[IpAddr]>,
+    next: Arc<AtomicUsize>,
+}
+
+/// Records the latency and outcome of each connection that a client establishes, e.g. for clients
+/// that connect lazily as requests are issued.
+pub trait RecordConnect: Send + Sync + 'static {
+    /// Records that a connection is being attempted, before its outcome is known.
+    fn record_attempt(&self) {}
+
+    fn record_connect(
+        &self,
+        latency: Duration,
+        result: Result<(), &(dyn std::error::Error + 'static)>,
+    );
+}
+
+/// Describes a client connection that closed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Closed {
+    pub local: SocketAddr,
+    pub peer: SocketAddr,
+    pub lifetime: Duration,
+    pub reason: CloseReason,
+}
+
+/// Describes how a connection closed.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CloseReason {
+    /// The peer closed its write side.
+    Eof,
+    /// Reading from or writing to the connection failed.
+    Error(io::ErrorKind),
+    /// The client dropped the connection, e.g. because its HTTP/2 keepalive timed out.
+    Dropped,
+}
+
+/// Reports how a client connection closes to a `ConnWatch`.
+#[derive(Debug)]
+pub struct WatchedIo<T> {
+    io: T,
+    opened: Instant,
+    reason: Option<CloseReason>,
+    watch: Option<(SocketAddr, SocketAddr, mpsc::UnboundedSender<Closed>)>,
+}
+
+// === impl ConnMetrics ===
+
+impl ConnMetrics {
+    pub fn accepted(&self) -> u64 {
+        self.accepted.load(Ordering::Relaxed)
+    }
+
+    pub fn open(&self) -> u64 {
+        // Load `closed` first so that the result never underflows.
+        let closed = self.closed.load(Ordering::Relaxed);
+        self.accepted().saturating_sub(closed)
+    }
+
+    pub fn read_bytes(&self) -> u64 {
+        self.read_bytes.load(Ordering::Relaxed)
+    }
+
+    pub fn write_bytes(&self) -> u64 {
+        self.write_bytes.load(Ordering::Relaxed)
+    }
+}
+
+// === impl CountedIo ===
+
+impl<T> CountedIo<T> {
+    pub fn new(io: T, metrics: Arc<ConnMetrics>) -> Self {
+        metrics.accepted.fetch_add(1, Ordering::Relaxed);
+        Self { io, metrics }
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.io
+    }
+}
+
+impl<T> Drop for CountedIo<T> {
+    fn drop(&mut self) {
+        self.metrics.closed.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+// === impl ConnWatch ===
+
+impl ConnWatch {
+    /// Creates a watch that enables TCP keepalive probes at the given interval. Closed connections
+    /// are reported on the returned receiver.
+    pub fn new(tcp_keepalive: Option<Duration>) -> (Self, mpsc::UnboundedReceiver<Closed>) {
+        let (closes, rx) = mpsc::unbounded_channel();
+        (
+            Self {
+                tcp_keepalive,
+                closes,
+            },
+            rx,
+        )
+    }
+
+    /// Configures keepalive on a newly-established connection and watches it until it closes.
+    pub fn watch(&self, stream: TcpStream) -> io::Result<WatchedIo<TcpStream>> {
+        if let Some(interval) = self.tcp_keepalive {
+            let keepalive = socket2::TcpKeepalive::new()
+                .with_time(interval)
+                .with_interval(interval);
+            socket2::SockRef::from(&stream).set_tcp_keepalive(&keepalive)?;
+        }
+        let addrs = (stream.local_addr()?, stream.peer_addr()?);
+        Ok(WatchedIo {
+            io: stream,
+            opened: Instant::now(),
+            reason: None,
+            watch: Some((addrs.0, addrs.1, self.closes.clone())),
+        })
+    }
+}
+
+// === impl Bind ===
+
+impl Bind {
+    pub fn new(addrs: Vec<IpAddr>) -> Self {
+        Self {
+            addrs: addrs.into(),
+            next: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Connects to the first reachable address that `dst` resolves to. Each connection is bound
+    /// to the next local address of the same family; when there is none, the connection is bound
+    /// by the OS.
+    pub async fn connect(&self, dst: impl ToSocketAddrs) -> io::Result<TcpStream> {
+        let mut error = None;
+        for addr in tokio::net::lookup_host(dst).await? {
+            match self.connect_addr(addr).await {
+                Ok(stream) => return Ok(stream),
+                Err(e) => error = Some(e),
+            }
+        }
+        Err(error.unwrap_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no addresses found")))
+    }
+
+    /// Connects like [`Bind::connect`], failing with `TimedOut` if the connection is not
+    /// established within `timeout`. Each attempt and its outcome is recorded in `connects`.
+    pub async fn connect_timeout(
+        &self,
+        dst: impl ToSocketAddrs,
+        timeout: Duration,
+        connects: Option<&dyn RecordConnect>,
+    ) -> io::Result<TcpStream> {
+        if let Some(connects) = connects {
+            connects.record_attempt();
+        }
+        let t0 = Instant::now();
+        let res = time::timeout(timeout, self.connect(dst))
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "connect timed out"))
+            .and_then(|res| res);
+        if let Some(connects) = connects {
+            let result = res
+                .as_ref()
+                .map(|_| ())
+                .map_err(|e| e as &(dyn std::error::Error + 'static));
+            connects.record_connect(t0.elapsed(), result);
+        }
+        res
+    }
+
+    async fn connect_addr(&self, addr: SocketAddr) -> io::Result<TcpStream> {
+        let socket = if addr.is_ipv4() {
+            TcpSocket::new_v4()?
+        } else {
+            TcpSocket::new_v6()?
+        };
+        socket.set_reuseaddr(true)?;
+        if let Some(local) = self.next_local(addr) {
+            socket
+                .bind(SocketAddr::new(local, 0))
+                .map_err(|e| bind_error(local, e))?;
+        }
+        socket.connect(addr).await
+    }
+
+    fn next_local(&self, dst: SocketAddr) -> Option<IpAddr> {
+        let n = self.addrs.len();
+        if n == 0 {
+            return None;
+        }
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        (0..n)
+            .map(|i| self.addrs[(start + i) % n])
+            .find(|a| a.is_ipv4() == dst.is_ipv4())
+    }
+}
+
+/// Binding to port 0 allocates an ephemeral port before connecting, so exhaustion is reported by
+/// `bind` as `EADDRINUSE`. It is reported as `EADDRNOTAVAIL`, as it is by `connect`, so that it is
+/// classified consistently. Binding fails with `EADDRNOTAVAIL` when the address is not local,
+/// which is a configuration error rather than exhaustion.
+fn bind_error(local: IpAddr, error: io::Error) -> io::Error {
+    match error.kind() {
+        io::ErrorKind::AddrInUse => io::Error::new(
+            io::ErrorKind::AddrNotAvailable,
+            format!("no ephemeral ports available on {}: {}", local, error),
+        ),
+        io::ErrorKind::AddrNotAvailable => io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("cannot bind to {}: {}", local, error),
+        ),
+        _ => error,
+    }
+}
+
+/// Indicates whether an error (or any of its sources) was caused by the client running out of
+/// ephemeral ports, i.e. `EADDRNOTAVAIL`.
+pub fn is_ports_exhausted(error: &(dyn std::error::Error + 'static)) -> bool {
+    let mut next = Some(error);
+    while let Some(e) = next {
+        if let Some(e) = e.downcast_ref::<io::Error>() {
+            if e.kind() == io::ErrorKind::AddrNotAvailable {
+                return true;
+            }
+        }
+        next = e.source();
+    }
+    false
+}
+
+// === impl WatchedIo ===
+
+impl<T> WatchedIo<T> {
+    /// Wraps a connection without reporting how it closes.
+    pub fn unwatched(io: T) -> Self {
+        Self {
+            io,
+            opened: Instant::now(),
+            reason: None,
+            watch: None,
+        }
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.io
+    }
+
+    fn record<U>(&mut self, res: &io::Result<U>) {
+        if let Err(error) = res {
+            self.reason.get_or_insert(CloseReason::Error(error.kind()));
+        }
+    }
+}
+
+impl<T> Drop for WatchedIo<T> {
+    fn drop(&mut self) {
+        if let Some((local, peer, closes)) = self.watch.take() {
+            let _ = closes.send(Closed {
+                local,
+                peer,
+                lifetime: self.opened.elapsed(),
+                reason: self.reason.unwrap_or(CloseReason::Dropped),
+            });
+        }
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for WatchedIo<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        let res = futures::ready!(Pin::new(&mut self.io).poll_read(cx, buf));
+        if res.is_ok() && buf.filled().len() == filled && buf.remaining() > 0 {
+            self.reason.get_or_insert(CloseReason::Eof);
+        }
+        self.record(&res);
+        Poll::Ready(res)
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for WatchedIo<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let res = futures::ready!(Pin::new(&mut self.io).poll_write(cx, buf));
+        self.record(&res);
+        Poll::Ready(res)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let res = futures::ready!(Pin::new(&mut self.io).poll_flush(cx));
+        self.record(&res);
+        Poll::Ready(res)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_shutdown(cx)
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for CountedIo<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        let res = Pin::new(&mut self.io).poll_read(cx, buf);
+        let n = buf.filled().len() - filled;
+        self.metrics
+            .read_bytes
+            .fetch_add(n as u64, Ordering::Relaxed);
+        res
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for CountedIo<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let res = Pin::new(&mut self.io).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = res {
+            self.metrics
+                .write_bytes
+                .fetch_add(n as u64, Ordering::Relaxed);
+        }
+        res
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// Watches `io` as if it were a connection from 127.0.0.1:1 to 127.0.0.1:2.
+    fn watched<T>(io: T) -> (WatchedIo<T>, mpsc::UnboundedReceiver<Closed>) {
+        let (closes, rx) = mpsc::unbounded_channel();
+        let local = SocketAddr::from(([127, 0, 0, 1], 1));
+        let peer = SocketAddr::from(([127, 0, 0, 1], 2));
+        let io = WatchedIo {
+            io,
+            opened: Instant::now(),
+            reason: None,
+            watch: Some((local, peer, closes)),
+        };
+        (io, rx)
+    }
+
+    /// Fails every read as if the peer reset the connection.
+    struct Reset;
+
+    impl AsyncRead for Reset {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            _: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            Poll::Ready(Err(io::ErrorKind::ConnectionReset.into()))
+        }
+    }
+
+    #[tokio::test]
+    async fn watch_peer_close() {
+        let (client, server) = io::duplex(64);
+        let (mut io, mut closes) = watched(client);
+        drop(server);
+        let mut buf = [0u8; 8];
+        assert_eq!(io.read(&mut buf).await.expect("must read"), 0);
+        // The first reason is reported, even if the connection later fails.
+        assert!(io.write_all(b"hello").await.is_err());
+        drop(io);
+
+        let closed = closes.try_recv().expect("must report close");
+        assert_eq!(closed.local, SocketAddr::from(([127, 0, 0, 1], 1)));
+        assert_eq!(closed.peer, SocketAddr::from(([127, 0, 0, 1], 2)));
+        assert_eq!(closed.reason, CloseReason::Eof);
+    }
+
+    #[tokio::test]
+    async fn watch_errors() {
+        let (mut io, mut closes) = watched(Reset);
+        let mut buf = [0u8; 8];
+        assert!(io.read(&mut buf).await.is_err());
+        drop(io);
+        let closed = closes.try_recv().expect("must report close");
+        assert_eq!(
+            closed.reason,
+            CloseReason::Error(io::ErrorKind::ConnectionReset)
+        );
+
+        let (client, server) = io::duplex(64);
+        let (mut io, mut closes) = watched(client);
+        drop(server);
+        assert!(io.write_all(b"hello").await.is_err());
+        drop(io);
+        let closed = closes.try_recv().expect("must report close");
+        assert_eq!(closed.reason, CloseReason::Error(io::ErrorKind::BrokenPipe));
+    }
+
+    #[tokio::test]
+    async fn watch_local_drop() {
+        let (client, mut server) = io::duplex(64);
+        let (mut io, mut closes) = watched(client);
+        io.write_all(b"hello").await.expect("must write");
+        let mut buf = [0u8; 5];
+        server.read_exact(&mut buf).await.expect("must read");
+        drop(io);
+
+        let closed = closes.try_recv().expect("must report close");
+        assert_eq!(closed.reason, CloseReason::Dropped);
+        // The peer sees the connection close.
+        assert_eq!(server.read(&mut buf).await.expect("must read"), 0);
+    }
+
+    #[test]
+    fn bind_rotates_within_family() {
+        let bind = Bind::new(vec![
+            [10, 0, 0, 1].into(),
+            "fd00::1".parse().unwrap(),
+            [10, 0, 0, 2].into(),
+        ]);
+        let v4 = SocketAddr::from(([10, 1, 0, 1], 80));
+        let v6 = "[fd00::2]:80".parse().unwrap();
+        assert_eq!(bind.next_local(v4), Some([10, 0, 0, 1].into()));
+        assert_eq!(bind.next_local(v4), Some([10, 0, 0, 2].into()));
+        assert_eq!(bind.next_local(v6), Some("fd00::1".parse().unwrap()));
+        assert_eq!(bind.next_local(v4), Some([10, 0, 0, 1].into()));
+
+        let bind = Bind::new(vec![[10, 0, 0, 1].into()]);
+        assert_eq!(bind.next_local(v6), None);
+    }
+
+    #[test]
+    fn ports_exhausted() {
+        let error: crate::Error = io::Error::from(io::ErrorKind::AddrNotAvailable).into();
+        assert!(is_ports_exhausted(&*error));
+        let error: crate::Error = io::Error::from(io::ErrorKind::ConnectionRefused).into();
+        assert!(!is_ports_exhausted(&*error));
+
+        // Binding reports exhaustion as `EADDRINUSE`.
+        let local = [10, 0, 0, 1].into();
+        let error: crate::Error = bind_error(local, io::ErrorKind::AddrInUse.into()).into();
+        assert!(is_ports_exhausted(&*error));
+        let error: crate::Error = bind_error(local, io::ErrorKind::AddrNotAvailable.into()).into();
+        assert!(!is_ports_exhausted(&*error));
+    }
+}
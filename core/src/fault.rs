@@ -0,0 +1,320 @@
+use crate::{Error, Reply};
+use rand::RngCore;
+use std::{
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU8, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    io::{self, AsyncRead, AsyncWrite, ReadBuf},
+    net::TcpStream,
+};
+
+/// A fault that a server injects instead of replying normally.
+///
+/// Servers inject connection-level faults into the connection on which the request was received
+/// via an [`Injector`]. Once injected, such a fault affects all subsequent writes on the
+/// connection. [`Fault::Hang`] is scoped to a single request and is never injected into the
+/// connection.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Fault {
+    /// Writes part of the response before resetting the connection (with a TCP RST).
+    Reset,
+    /// Accepts the request but never replies to it. Other requests on the same connection are
+    /// unaffected.
+    Hang,
+    /// Closes the write side of the connection instead of writing a response.
+    HalfClose,
+    /// Writes part of the response before closing the connection.
+    Truncate,
+    /// Writes random bytes in place of the response.
+    Garbage,
+}
+
+/// Injects faults into a [`FaultIo`].
+#[derive(Clone, Debug, Default)]
+pub struct Injector(Arc<AtomicU8>);
+
+/// A server-side connection into which faults may be injected.
+#[derive(Debug)]
+pub struct FaultIo {
+    io: TcpStream,
+    injector: Injector,
+    state: State,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum State {
+    Ok,
+    Faulted(Fault),
+    /// The write side has been shut down and all further writes are discarded.
+    Discarding,
+    /// The connection has been torn down and all further IO fails.
+    Closed,
+}
+
+// === impl Fault ===
+
+impl Fault {
+    fn to_u8(self) -> u8 {
+        match self {
+            Self::Reset => 1,
+            Self::Hang => 2,
+            Self::HalfClose => 3,
+            Self::Truncate => 4,
+            Self::Garbage => 5,
+        }
+    }
+
+    fn from_u8(v: u8) -> Option<Self> {
+        match v {
+            1 => Some(Self::Reset),
+            2 => Some(Self::Hang),
+            3 => Some(Self::HalfClose),
+            4 => Some(Self::Truncate),
+            5 => Some(Self::Garbage),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for Fault {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Reset => write!(f, "injected connection reset"),
+            Self::Hang => write!(f, "injected hang"),
+            Self::HalfClose => write!(f, "injected half-close"),
+            Self::Truncate => write!(f, "injected truncation"),
+            Self::Garbage => write!(f, "injected garbage"),
+        }
+    }
+}
+
+impl std::error::Error for Fault {}
+
+// === impl Injector ===
+
+impl Injector {
+    /// Injects a fault into the connection. The first injected fault wins.
+    ///
+    /// Hangs apply only to the request that sampled them, so they are ignored.
+    pub fn inject(&self, fault: Fault) {
+        if fault == Fault::Hang {
+            return;
+        }
+        let _ = self
+            .0
+            .compare_exchange(0, fault.to_u8(), Ordering::AcqRel, Ordering::Acquire);
+    }
+
+    /// If the error is a [`Fault`], injects it and returns a placeholder reply of the given size
+    /// so that the server writes a response for the fault to act on. Other errors are returned.
+    pub fn recover(&self, error: Error, response_size: usize) -> Result<Reply, Error> {
+        let fault = error.downcast::<Fault>()?;
+        self.inject(*fault);
+        Ok(Reply {
+            data: vec![0; response_size].into(),
+            instance: None,
+            hops: vec![],
+        })
+    }
+
+    fn get(&self) -> Option<Fault> {
+        Fault::from_u8(self.0.load(Ordering::Acquire))
+    }
+}
+
+// === impl FaultIo ===
+
+impl FaultIo {
+    pub fn new(io: TcpStream) -> Self {
+        Self {
+            io,
+            injector: Injector::default(),
+            state: State::Ok,
+        }
+    }
+
+    pub fn injector(&self) -> &Injector {
+        &self.injector
+    }
+
+    pub fn get_ref(&self) -> &TcpStream {
+        &self.io
+    }
+
+    fn closed() -> io::Error {
+        io::Error::new(io::ErrorKind::ConnectionReset, "connection closed by fault")
+    }
+
+    fn update(&mut self) -> State {
+        if self.state == State::Ok {
+            if let Some(fault) = self.injector.get() {
+                self.state = State::Faulted(fault);
+            }
+        }
+        self.state
+    }
+}
+
+impl AsyncRead for FaultIo {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        match this.state {
+            State::Closed => Poll::Ready(Err(Self::closed())),
+            _ => Pin::new(&mut this.io).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for FaultIo {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        match this.update() {
+            // Hangs are never injected into the connection.
+            State::Ok | State::Faulted(Fault::Hang) => Pin::new(&mut this.io).poll_write(cx, buf),
+            State::Closed => Poll::Ready(Err(Self::closed())),
+            State::Discarding => Poll::Ready(Ok(buf.len())),
+            State::Faulted(Fault::Reset) => {
+                let sz = (buf.len() / 2).max(1).min(buf.len());
+                match Pin::new(&mut this.io).poll_write(cx, &buf[..sz]) {
+                    Poll::Pending => return Poll::Pending,
+                    Poll::Ready(res) => {
+                        // Closing a socket with a zero linger timeout causes a RST to be sent.
+                        let _ = this.io.set_linger(Some(Duration::from_secs(0)));
+                        this.state = State::Closed;
+                        Poll::Ready(res)
+                    }
+                }
+            }
+            State::Faulted(Fault::HalfClose) => {
+                match Pin::new(&mut this.io).poll_shutdown(cx) {
+                    Poll::Pending => return Poll::Pending,
+                    Poll::Ready(res) => res?,
+                }
+                this.state = State::Discarding;
+                Poll::Ready(Ok(buf.len()))
+            }
+            State::Faulted(Fault::Truncate) => {
+                let sz = (buf.len() / 2).max(1).min(buf.len());
+                match Pin::new(&mut this.io).poll_write(cx, &buf[..sz]) {
+                    Poll::Pending => return Poll::Pending,
+                    Poll::Ready(res) => {
+                        this.state = State::Closed;
+                        Poll::Ready(res)
+                    }
+                }
+            }
+            State::Faulted(Fault::Garbage) => {
+                let mut garbage = vec![0u8; buf.len()];
+                rand::thread_rng().fill_bytes(&mut garbage);
+                Pin::new(&mut this.io).poll_write(cx, &garbage)
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        match this.state {
+            State::Closed => Poll::Ready(Err(Self::closed())),
+            State::Discarding => Poll::Ready(Ok(())),
+            _ => Pin::new(&mut this.io).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        match this.state {
+            State::Closed => Poll::Ready(Err(Self::closed())),
+            State::Discarding => Poll::Ready(Ok(())),
+            _ => Pin::new(&mut this.io).poll_shutdown(cx),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    async fn pair() -> (FaultIo, TcpStream) {
+        let lis = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(lis.local_addr().unwrap()).await.unwrap();
+        let (server, _) = lis.accept().await.unwrap();
+        (FaultIo::new(server), client)
+    }
+
+    #[tokio::test]
+    async fn truncate() {
+        let (mut server, mut client) = pair().await;
+        server.injector().inject(Fault::Truncate);
+        let n = server.write(b"abcdefgh").await.expect("must write");
+        assert_eq!(n, 4);
+        assert!(server.write(b"ijkl").await.is_err());
+        drop(server);
+
+        let mut buf = Vec::new();
+        client.read_to_end(&mut buf).await.expect("must read");
+        assert_eq!(buf, b"abcd");
+    }
+
+    #[tokio::test]
+    async fn reset() {
+        let (mut server, mut client) = pair().await;
+        server.injector().inject(Fault::Reset);
+        let n = server.write(b"abcdefgh").await.expect("must write");
+        assert_eq!(n, 4);
+        assert!(server.write(b"ijkl").await.is_err());
+
+        let mut buf = [0u8; 4];
+        client.read_exact(&mut buf).await.expect("must read");
+        assert_eq!(&buf, b"abcd");
+        drop(server);
+
+        let err = client.read(&mut [0u8; 4]).await.expect_err("must be reset");
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+    }
+
+    #[tokio::test]
+    async fn hangs_are_not_injected() {
+        let (mut server, mut client) = pair().await;
+        server.injector().inject(Fault::Hang);
+        server.write_all(b"abcd").await.expect("must write");
+
+        let mut buf = [0u8; 4];
+        client.read_exact(&mut buf).await.expect("must read");
+        assert_eq!(&buf, b"abcd");
+    }
+
+    #[tokio::test]
+    async fn half_close() {
+        let (mut server, mut client) = pair().await;
+        server.injector().inject(Fault::HalfClose);
+        server.write_all(b"abcd").await.expect("must write");
+
+        let mut buf = Vec::new();
+        client.read_to_end(&mut buf).await.expect("must read");
+        assert!(buf.is_empty());
+
+        // The read side remains open.
+        client.write_all(b"ping").await.expect("must write");
+        let mut buf = [0u8; 4];
+        server.read_exact(&mut buf).await.expect("must read");
+        assert_eq!(&buf, b"ping");
+    }
+}
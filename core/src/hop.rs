@@ -0,0 +1,92 @@
+use std::{fmt, str::FromStr, time::Duration};
+
+/// A request that a server relayed to an upstream server before replying, with the latency
+/// observed by the relaying server.
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "deser", derive(serde::Serialize, serde::Deserialize))]
+pub struct Hop {
+    /// The upstream server's instance ID, if it is known.
+    pub instance: Option<String>,
+    pub latency: Duration,
+}
+
+/// A list of hops, formatted as comma-separated `<latency-micros>:<instance>` entries, e.g.
+/// `1520:server-000,310:`. The instance is empty when it is not known.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Hops(pub Vec<Hop>);
+
+#[derive(Copy, Clone, Debug)]
+pub struct InvalidHops(());
+
+// === impl Hops ===
+
+impl fmt::Display for Hops {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, hop) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, ",")?;
+            }
+            write!(
+                f,
+                "{}:{}",
+                hop.latency.as_micros(),
+                hop.instance.as_deref().unwrap_or_default()
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Hops {
+    type Err = InvalidHops;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut hops = Vec::new();
+        for hop in s.split(',').filter(|h| !h.is_empty()) {
+            let mut parts = hop.splitn(2, ':');
+            let (micros, instance) = match (parts.next(), parts.next()) {
+                (Some(micros), Some(instance)) => (micros, instance),
+                _ => return Err(InvalidHops(())),
+            };
+            let micros = micros.parse::<u64>().map_err(|_| InvalidHops(()))?;
+            hops.push(Hop {
+                instance: Some(instance).filter(|i| !i.is_empty()).map(Into::into),
+                latency: Duration::from_micros(micros),
+            });
+        }
+        Ok(Self(hops))
+    }
+}
+
+impl fmt::Display for InvalidHops {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid hops; expected <latency-micros>:<instance>,...")
+    }
+}
+
+impl std::error::Error for InvalidHops {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let hops = Hops(vec![
+            Hop {
+                instance: Some("server-000".into()),
+                latency: Duration::from_micros(1520),
+            },
+            Hop {
+                instance: None,
+                latency: Duration::from_micros(310),
+            },
+        ]);
+        let s = hops.to_string();
+        assert_eq!(s, "1520:server-000,310:");
+        assert_eq!(s.parse::<Hops>().expect("must parse"), hops);
+        assert_eq!("".parse::<Hops>().expect("must parse"), Hops::default());
+        assert!("fast:server".parse::<Hops>().is_err());
+        assert!("10".parse::<Hops>().is_err());
+    }
+}
@@ -1,12 +1,23 @@
 #![deny(warnings, rust_2018_idioms)]
 
+mod conn;
 mod distribution;
+pub mod fault;
+mod hop;
 pub mod latency;
 pub mod limit;
+mod pacing;
 
 pub use self::{
+    conn::{
+        is_ports_exhausted, Bind, CloseReason, Closed, ConnMetrics, ConnWatch, CountedIo,
+        RecordConnect, WatchedIo,
+    },
     distribution::Distribution,
+    fault::Fault,
+    hop::{Hop, Hops, InvalidHops},
     latency::{parse_duration, InvalidDuration, Latency},
+    pacing::{PacedIo, Pacing},
 };
 use bytes::Bytes;
 use std::time::Duration;
@@ -29,10 +40,90 @@ pub type Error = Box<dyn std::error::Error + Send + Sync + 'static>;
 pub struct Spec {
     pub latency: Duration,
     pub response_size: usize,
+    pub stream: StreamSpec,
+}
+
+/// Describes the messages exchanged by streaming requests. Protocols that do not support streaming
+/// ignore it.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "deser", derive(serde::Serialize, serde::Deserialize))]
+pub struct StreamSpec {
+    pub messages: usize,
+    pub interval: Duration,
 }
 
 #[derive(Clone, Debug, Default, PartialEq)]
 #[cfg_attr(feature = "deser", derive(serde::Serialize, serde::Deserialize))]
 pub struct Reply {
     pub data: Bytes,
+    /// Identifies the server instance that produced the reply, if it is known.
+    pub instance: Option<String>,
+    /// The requests that the server relayed to upstream servers before replying, in the order in
+    /// which they were issued. Hops relayed by upstream servers follow their own hop.
+    pub hops: Vec<Hop>,
+}
+
+/// The name of the header (or gRPC metadata key) that carries a server's instance ID.
+pub const INSTANCE_HEADER: &str = "ort-instance";
+
+/// The name of the header that carries a reply's hops, formatted as [`Hops`].
+pub const HOPS_HEADER: &str = "ort-hops";
+
+/// An error that instructs a server to fail a request.
+///
+/// The status is interpreted by each protocol (e.g. as an HTTP status or a gRPC code). When it is
+/// unset, the protocol's default failure status is used.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Failure {
+    pub status: Option<u16>,
+}
+
+/// An error with which a server instance responded, so that it may be attributed to the instance.
+#[derive(Debug)]
+pub struct InstanceError {
+    instance: String,
+    inner: Error,
+}
+
+// === impl Failure ===
+
+impl std::fmt::Display for Failure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.status {
+            Some(status) => write!(f, "server failure with status {}", status),
+            None => write!(f, "server failure"),
+        }
+    }
+}
+
+impl std::error::Error for Failure {}
+
+// === impl InstanceError ===
+
+impl InstanceError {
+    pub fn new(instance: String, inner: Error) -> Self {
+        Self { instance, inner }
+    }
+
+    /// The ID of the server instance that responded.
+    pub fn instance(&self) -> &str {
+        &self.instance
+    }
+
+    /// The error with which the instance responded.
+    pub fn inner(&self) -> &Error {
+        &self.inner
+    }
+}
+
+impl std::fmt::Display for InstanceError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "instance {}: {}", self.instance, self.inner)
+    }
+}
+
+impl std::error::Error for InstanceError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&*self.inner)
+    }
 }
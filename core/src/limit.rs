@@ -14,6 +14,30 @@ pub struct Limit<A, M> {
     inner: M,
 }
 
+/// Like `Limit`, but fails requests with `Overloaded` when the acquirer rejects them.
+#[derive(Clone)]
+pub struct Shed<A, M> {
+    acquire: A,
+    inner: M,
+}
+
+/// Limits concurrency to a fixed number of permits. At most `queue` requests wait for a permit;
+/// requests beyond that are rejected immediately.
+///
+/// The default value does not limit concurrency.
+#[derive(Clone, Debug, Default)]
+pub struct Bounded(Option<Arc<Queue>>);
+
+#[derive(Debug)]
+struct Queue {
+    permits: Arc<Semaphore>,
+    waiters: Arc<Semaphore>,
+}
+
+/// An error indicating that a request was rejected because too many requests were in flight.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Overloaded(());
+
 // === impl Limit ===
 
 impl<A, T> Limit<A, T> {
@@ -53,6 +77,78 @@ where
     }
 }
 
+// === impl Shed ===
+
+impl<A, T> Shed<A, T> {
+    pub fn new(acquire: A, inner: T) -> Self {
+        Self { acquire, inner }
+    }
+}
+
+#[async_trait::async_trait]
+impl<A, H, O> crate::Ort for Shed<A, O>
+where
+    A: Acquire<Handle = Result<H, Overloaded>>,
+    H: Send + Sync + 'static,
+    O: crate::Ort,
+{
+    async fn ort(&mut self, spec: crate::Spec) -> Result<crate::Reply, crate::Error> {
+        let permit = self.acquire.acquire().await?;
+        let reply = self.inner.ort(spec).await;
+        drop(permit);
+        reply
+    }
+}
+
+// === impl Bounded ===
+
+impl Bounded {
+    pub fn new(limit: usize, queue: usize) -> Self {
+        Self(Some(Arc::new(Queue {
+            permits: Arc::new(Semaphore::new(limit)),
+            waiters: Arc::new(Semaphore::new(queue)),
+        })))
+    }
+}
+
+#[async_trait::async_trait]
+impl Acquire for Bounded {
+    type Handle = Result<Option<OwnedSemaphorePermit>, Overloaded>;
+
+    async fn acquire(&self) -> Self::Handle {
+        let queue = match self.0.as_ref() {
+            Some(queue) => queue,
+            None => return Ok(None),
+        };
+        if let Ok(permit) = queue.permits.clone().try_acquire_owned() {
+            return Ok(Some(permit));
+        }
+        let waiting = queue
+            .waiters
+            .clone()
+            .try_acquire_owned()
+            .map_err(|_| Overloaded(()))?;
+        let permit = queue
+            .permits
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("Semaphore must not be closed");
+        drop(waiting);
+        Ok(Some(permit))
+    }
+}
+
+// === impl Overloaded ===
+
+impl std::fmt::Display for Overloaded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "too many requests in flight")
+    }
+}
+
+impl std::error::Error for Overloaded {}
+
 // === impl Acquire ===
 
 #[async_trait::async_trait]
@@ -87,3 +183,33 @@ impl Acquire for Arc<Semaphore> {
             .expect("Semaphore must not be closed")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn bounded_sheds() {
+        let bounded = Bounded::new(1, 1);
+        let p0 = bounded.acquire().await.expect("must acquire");
+        let queued = tokio::spawn({
+            let bounded = bounded.clone();
+            async move { bounded.acquire().await.map(|_| ()) }
+        });
+        tokio::task::yield_now().await;
+        assert_eq!(bounded.acquire().await.err(), Some(Overloaded(())));
+
+        drop(p0);
+        queued
+            .await
+            .expect("task must complete")
+            .expect("queued request must acquire");
+    }
+
+    #[tokio::test]
+    async fn unbounded() {
+        let bounded = Bounded::default();
+        let _p0 = bounded.acquire().await.expect("must acquire");
+        let _p1 = bounded.acquire().await.expect("must acquire");
+    }
+}
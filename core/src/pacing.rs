@@ -0,0 +1,176 @@
+use bytes::Bytes;
+use futures::prelude::*;
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    io::{self, AsyncRead, AsyncWrite, ReadBuf},
+    time,
+};
+
+/// Describes how reply bodies are trickled: in chunks of at most `chunk_size` bytes, each
+/// separated by `interval`.
+///
+/// The first chunk is written as soon as the reply is ready, so pacing only affects the time to
+/// the last byte.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Pacing {
+    pub chunk_size: usize,
+    pub interval: Duration,
+}
+
+/// Paces writes to an underlying IO.
+#[derive(Debug)]
+pub struct PacedIo<T> {
+    io: T,
+    pacing: Option<Pacing>,
+    sleep: Pin<Box<time::Sleep>>,
+    ready: bool,
+}
+
+// === impl Pacing ===
+
+impl Pacing {
+    /// Paces chunks so that, on average, at most `bytes_per_sec` bytes are written each second.
+    pub fn from_rate(chunk_size: usize, bytes_per_sec: u64) -> Self {
+        let secs = chunk_size as f64 / bytes_per_sec.max(1) as f64;
+        Self {
+            chunk_size,
+            interval: Duration::from_secs_f64(secs),
+        }
+    }
+
+    /// Splits `data` into a stream of paced chunks.
+    pub fn chunks(self, mut data: Bytes) -> impl Stream<Item = Bytes> + Send + 'static {
+        let chunk_size = self.chunk_size.max(1);
+        stream::unfold(true, move |first| {
+            let chunk = if data.is_empty() {
+                None
+            } else {
+                Some(data.split_to(chunk_size.min(data.len())))
+            };
+            async move {
+                let chunk = chunk?;
+                if !first {
+                    time::sleep(self.interval).await;
+                }
+                Some((chunk, false))
+            }
+        })
+    }
+
+    /// The time it takes to write `size` bytes.
+    pub fn duration(&self, size: usize) -> Duration {
+        let chunks = (size + self.chunk_size.max(1) - 1) / self.chunk_size.max(1);
+        self.interval * (chunks.saturating_sub(1) as u32)
+    }
+}
+
+// === impl PacedIo ===
+
+impl<T> PacedIo<T> {
+    pub fn new(io: T, pacing: Option<Pacing>) -> Self {
+        Self {
+            io,
+            pacing,
+            sleep: Box::pin(time::sleep(Duration::from_secs(0))),
+            ready: true,
+        }
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.io
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for PacedIo<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_read(cx, buf)
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for PacedIo<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        let Pacing {
+            chunk_size,
+            interval,
+        } = match this.pacing {
+            Some(pacing) => pacing,
+            None => return Pin::new(&mut this.io).poll_write(cx, buf),
+        };
+
+        // Wait for the previous chunk's interval to elapse before writing the next chunk.
+        if !this.ready {
+            futures::ready!(this.sleep.as_mut().poll(cx));
+            this.ready = true;
+        }
+
+        let sz = buf.len().min(chunk_size.max(1));
+        let n = futures::ready!(Pin::new(&mut this.io).poll_write(cx, &buf[..sz]))?;
+        if n > 0 {
+            // Chunks must be flushed as they are written.
+            let _ = Pin::new(&mut this.io).poll_flush(cx);
+            this.ready = false;
+            this.sleep.as_mut().reset(time::Instant::now() + interval);
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn chunks() {
+        let pacing = Pacing {
+            chunk_size: 4,
+            interval: Duration::from_millis(1),
+        };
+        let chunks = pacing
+            .chunks(Bytes::from_static(b"abcdefghij"))
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(chunks, vec!["abcd", "efgh", "ij"]);
+        assert_eq!(pacing.duration(10), Duration::from_millis(2));
+        assert_eq!(pacing.duration(0), Duration::from_millis(0));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn paced_io() {
+        let (client, mut server) = io::duplex(1024);
+        let pacing = Pacing {
+            chunk_size: 4,
+            interval: Duration::from_secs(1),
+        };
+        let mut io = PacedIo::new(client, Some(pacing));
+
+        let t0 = time::Instant::now();
+        io.write_all(b"abcdefghij").await.expect("must write");
+        assert_eq!(t0.elapsed(), Duration::from_secs(2));
+
+        let mut buf = [0u8; 10];
+        server.read_exact(&mut buf).await.expect("must read");
+        assert_eq!(&buf, b"abcdefghij");
+    }
+}
@@ -4,9 +4,12 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     let build_client = std::env::var_os("CARGO_FEATURE_CLIENT").is_some();
     let build_server = std::env::var_os("CARGO_FEATURE_SERVER").is_some();
+    let descriptors =
+        std::path::PathBuf::from(std::env::var("OUT_DIR")?).join("ort_descriptor.bin");
     tonic_build::configure()
         .build_client(build_client)
         .build_server(build_server)
+        .file_descriptor_set_path(descriptors)
         .compile(files, dirs)?;
 
     // recompile protobufs only if any of the proto files changes.
@@ -1,58 +1,645 @@
-use crate::proto::{ort_client, response_spec as spec, ResponseSpec};
-use ort_core::{Error, MakeOrt, Ort, Reply, Spec};
+use crate::proto::{ort_client, response_spec as spec, ResponseReply, ResponseSpec};
+use futures::prelude::*;
+use ort_core::{
+    Bind, ConnWatch, Error, Hop, InstanceError, MakeOrt, Ort, Reply, Spec, WatchedIo,
+    INSTANCE_HEADER,
+};
+use rand::{distributions::Distribution, thread_rng, Rng};
+use std::{
+    convert::TryInto,
+    pin::Pin,
+    str::FromStr,
+    sync::Arc,
+    task::{Context, Poll},
+};
+use tokio::{io, net::TcpStream, time};
+use tonic::{
+    codegen::Service,
+    metadata::{AsciiMetadataKey, AsciiMetadataValue},
+    Code,
+};
 use tracing::trace;
 
 #[derive(Clone)]
 pub struct MakeGrpc {
-    window_size: u32,
+    settings: Arc<Settings>,
+    mode: Mode,
+    request_timeout: Option<time::Duration>,
+    metadata: Arc<[Metadata]>,
+    errors: Arc<ErrorCodes>,
 }
 
 #[derive(Clone)]
-pub struct Grpc(ort_client::OrtClient<tonic::transport::Channel>);
+pub struct Grpc {
+    client: ort_client::OrtClient<tonic::transport::Channel>,
+    mode: Mode,
+    request_timeout: Option<time::Duration>,
+    metadata: Arc<[Metadata]>,
+    errors: Arc<ErrorCodes>,
+}
 
-impl Default for MakeGrpc {
-    fn default() -> Self {
+/// Configures the channels used to issue requests.
+#[derive(Clone, Debug)]
+pub struct Settings {
+    pub connect_timeout: Option<time::Duration>,
+    pub initial_stream_window_size: Option<u32>,
+    pub initial_connection_window_size: Option<u32>,
+    pub http2_adaptive_window: bool,
+    pub http2_keep_alive_interval: Option<time::Duration>,
+    pub keep_alive_timeout: Option<time::Duration>,
+    pub keep_alive_while_idle: bool,
+    /// Limits the number of requests in flight on each endpoint.
+    pub concurrency_limit: Option<usize>,
+    pub compression: Option<Compression>,
+    /// When set, the target's host is resolved and requests are balanced over all of its
+    /// addresses. Otherwise, a single connection is established to the target.
+    pub balance: bool,
+    /// Reports how each connection closes. Balanced channels are not watched.
+    pub watch: Option<ConnWatch>,
+    /// Binds each connection to one of several local addresses. Balanced channels cannot be bound,
+    /// so it must not be set with `balance`.
+    pub bind: Option<Bind>,
+}
+
+/// Establishes bound or watched connections.
+#[derive(Clone)]
+struct Connect {
+    bind: Bind,
+    watch: Option<ConnWatch>,
+}
+
+/// Determines how request and response messages are compressed.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Compression {
+    Gzip,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct InvalidCompression(());
+
+/// Determines which RPC is used to issue requests.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Mode {
+    Unary,
+    ServerStream,
+    ClientStream,
+    Bidi,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct InvalidMode(());
+
+/// A `key=value` pair that is set on every request.
+#[derive(Clone, Debug)]
+pub struct Metadata {
+    key: AsciiMetadataKey,
+    value: AsciiMetadataValue,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct InvalidMetadata(());
+
+/// Status codes that the server is asked to fail requests with, each with the rate at which it
+/// should be requested, e.g. `unavailable=0.01,internal=0.001`.
+#[derive(Clone, Debug, Default)]
+pub struct ErrorCodes(Vec<(f64, Code)>);
+
+#[derive(Copy, Clone, Debug)]
+pub struct InvalidErrorCodes(());
+
+// === impl MakeGrpc ===
+
+impl MakeGrpc {
+    pub fn new(
+        settings: Settings,
+        mode: Mode,
+        request_timeout: Option<time::Duration>,
+        metadata: Vec<Metadata>,
+        errors: ErrorCodes,
+    ) -> Self {
         Self {
-            window_size: 2u32.pow(31) - 1,
+            settings: Arc::new(settings),
+            mode,
+            request_timeout,
+            metadata: metadata.into(),
+            errors: Arc::new(errors),
         }
     }
 }
 
+impl Default for MakeGrpc {
+    fn default() -> Self {
+        Self::new(
+            Settings::default(),
+            Mode::Unary,
+            None,
+            vec![],
+            ErrorCodes::default(),
+        )
+    }
+}
+
 #[async_trait::async_trait]
 impl MakeOrt<http::Uri> for MakeGrpc {
     type Ort = Grpc;
 
     async fn make_ort(&mut self, target: http::Uri) -> Result<Grpc, Error> {
-        let chan = tonic::transport::Channel::builder(target)
-            .initial_connection_window_size(self.window_size);
-        let c = ort_client::OrtClient::connect(chan).await?;
-        Ok(Grpc(c))
+        let chan = if self.settings.balance {
+            if self.settings.bind.is_some() {
+                return Err("balanced channels cannot be bound to local addresses".into());
+            }
+            let endpoints = self.settings.resolve(&target).await?;
+            tonic::transport::Channel::balance_list(endpoints.into_iter())
+        } else if self.settings.watch.is_some() || self.settings.bind.is_some() {
+            let connect = Connect {
+                bind: self.settings.bind.clone().unwrap_or_default(),
+                watch: self.settings.watch.clone(),
+            };
+            self.settings
+                .endpoint(target)
+                .connect_with_connector(connect)
+                .await?
+        } else {
+            self.settings.endpoint(target).connect().await?
+        };
+
+        let mut client = ort_client::OrtClient::new(chan);
+        if let Some(Compression::Gzip) = self.settings.compression {
+            client = client.send_gzip().accept_gzip();
+        }
+
+        Ok(Grpc {
+            client,
+            mode: self.mode,
+            request_timeout: self.request_timeout,
+            metadata: self.metadata.clone(),
+            errors: self.errors.clone(),
+        })
+    }
+}
+
+// === impl Settings ===
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            connect_timeout: None,
+            initial_stream_window_size: None,
+            initial_connection_window_size: Some(2u32.pow(31) - 1),
+            http2_adaptive_window: false,
+            http2_keep_alive_interval: None,
+            keep_alive_timeout: None,
+            keep_alive_while_idle: false,
+            concurrency_limit: None,
+            compression: None,
+            balance: false,
+            watch: None,
+            bind: None,
+        }
+    }
+}
+
+impl Settings {
+    fn endpoint(&self, target: http::Uri) -> tonic::transport::Endpoint {
+        let mut endpoint = tonic::transport::Channel::builder(target)
+            .initial_stream_window_size(self.initial_stream_window_size)
+            .initial_connection_window_size(self.initial_connection_window_size)
+            .http2_adaptive_window(self.http2_adaptive_window)
+            .keep_alive_while_idle(self.keep_alive_while_idle);
+        if let Some(timeout) = self.connect_timeout {
+            endpoint = endpoint.connect_timeout(timeout);
+        }
+        if let Some(interval) = self.http2_keep_alive_interval {
+            endpoint = endpoint.http2_keep_alive_interval(interval);
+        }
+        if let Some(timeout) = self.keep_alive_timeout {
+            endpoint = endpoint.keep_alive_timeout(timeout);
+        }
+        if let Some(limit) = self.concurrency_limit {
+            endpoint = endpoint.concurrency_limit(limit);
+        }
+        endpoint
+    }
+
+    /// Resolves the target's host to an endpoint for each of its addresses.
+    async fn resolve(&self, target: &http::Uri) -> Result<Vec<tonic::transport::Endpoint>, Error> {
+        let host = target.host().unwrap_or_default();
+        let port = target.port_u16().unwrap_or(80);
+        let addrs = tokio::net::lookup_host((host, port)).await?;
+
+        let mut endpoints = Vec::new();
+        for addr in addrs {
+            let mut parts = target.clone().into_parts();
+            parts.authority = Some(addr.to_string().parse()?);
+            endpoints.push(self.endpoint(http::Uri::from_parts(parts)?));
+        }
+        trace!(%target, endpoints = endpoints.len(), "Resolved");
+
+        if endpoints.is_empty() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("no addresses found for {}", target),
+            )
+            .into());
+        }
+        Ok(endpoints)
+    }
+}
+
+// === impl Connect ===
+
+impl Service<http::Uri> for Connect {
+    type Response = WatchedIo<TcpStream>;
+    type Error = io::Error;
+    type Future = Pin<Box<dyn Future<Output = io::Result<WatchedIo<TcpStream>>> + Send + 'static>>;
+
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, dst: http::Uri) -> Self::Future {
+        let Self { bind, watch } = self.clone();
+        Box::pin(async move {
+            let host = dst.host().unwrap_or_default();
+            let port = dst.port_u16().unwrap_or(80);
+            let stream = bind.connect((host, port)).await?;
+            stream.set_nodelay(true)?;
+            match watch {
+                Some(watch) => watch.watch(stream),
+                None => Ok(WatchedIo::unwatched(stream)),
+            }
+        })
     }
 }
 
+// === impl Grpc ===
+
 #[async_trait::async_trait]
 impl Ort for Grpc {
-    async fn ort(
-        &mut self,
-        Spec {
-            latency,
-            response_size,
-        }: Spec,
-    ) -> Result<Reply, Error> {
-        let req = ResponseSpec {
-            latency: Some(latency.into()),
-            result: Some(spec::Result::Success(spec::Success {
-                size: response_size as i64,
-            })),
-            ..ResponseSpec::default()
+    async fn ort(&mut self, spec: Spec) -> Result<Reply, Error> {
+        let error = self.errors.sample(&mut thread_rng());
+        trace!(mode = ?self.mode, ?error, "Issuing request");
+        let (instance, replies) = match self.mode {
+            Mode::Unary => {
+                let req = self.request(request(spec, error, 0));
+                let rsp = self.client.get(req).await.map_err(failed)?;
+                (server_instance(&rsp), vec![rsp.into_inner()])
+            }
+            Mode::ServerStream => {
+                let req = self.request(request(spec, error, 0));
+                let rsp = self.client.get_stream(req).await.map_err(failed)?;
+                let instance = server_instance(&rsp);
+                let replies = collect(rsp.into_inner(), instance.as_deref()).await?;
+                (instance, replies)
+            }
+            Mode::ClientStream => {
+                let req = self.request(requests(spec, error));
+                let rsp = self.client.put_stream(req).await.map_err(failed)?;
+                (server_instance(&rsp), vec![rsp.into_inner()])
+            }
+            Mode::Bidi => {
+                let req = self.request(requests(spec, error));
+                let rsp = self.client.bidi(req).await.map_err(failed)?;
+                let instance = server_instance(&rsp);
+                let replies = collect(rsp.into_inner(), instance.as_deref()).await?;
+                (instance, replies)
+            }
         };
+        trace!(?instance, replies = replies.len(), "Received response");
+
+        // Streamed replies are concatenated.
+        let mut data = Vec::new();
+        let mut hops = Vec::new();
+        for reply in replies {
+            data.extend(reply.data);
+            hops.extend(reply.hops.into_iter().map(|h| {
+                Hop {
+                    instance: Some(h.instance).filter(|i| !i.is_empty()),
+                    latency: h
+                        .latency
+                        .and_then(|l| l.try_into().ok())
+                        .unwrap_or_default(),
+                }
+            }));
+        }
 
-        trace!("Issuing request");
-        let res = self.0.get(req).await;
-        trace!("Received response");
-        let rsp = res?.into_inner();
         Ok(Reply {
-            data: rsp.data.into(),
+            data: data.into(),
+            instance,
+            hops,
         })
     }
 }
+
+impl Grpc {
+    /// Sets the request's deadline and metadata.
+    fn request<T>(&self, msg: T) -> tonic::Request<T> {
+        let mut req = tonic::Request::new(msg);
+        if let Some(timeout) = self.request_timeout {
+            req.set_timeout(timeout);
+        }
+        for Metadata { key, value } in self.metadata.iter() {
+            req.metadata_mut().append(key.clone(), value.clone());
+        }
+        req
+    }
+}
+
+/// Reads the server's instance ID from the response's metadata.
+fn server_instance<T>(rsp: &tonic::Response<T>) -> Option<String> {
+    instance_metadata(rsp.metadata())
+}
+
+fn instance_metadata(metadata: &tonic::metadata::MetadataMap) -> Option<String> {
+    metadata
+        .get(INSTANCE_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string)
+}
+
+/// Attributes a failed call to the server instance named in the status's metadata, if any.
+fn failed(status: tonic::Status) -> Error {
+    match instance_metadata(status.metadata()) {
+        Some(instance) => InstanceError::new(instance, status.into()).into(),
+        None => status.into(),
+    }
+}
+
+fn request(
+    Spec {
+        latency,
+        response_size,
+        stream,
+    }: Spec,
+    error: Option<Code>,
+    request_size: usize,
+) -> ResponseSpec {
+    let result = match error {
+        Some(code) => spec::Result::Error(spec::Error {
+            code: code as i32,
+            message: format!("Client requested {:?}", code),
+        }),
+        None => spec::Result::Success(spec::Success {
+            size: response_size as i64,
+        }),
+    };
+    ResponseSpec {
+        latency: Some(latency.into()),
+        result: Some(result),
+        stream: Some(spec::Stream {
+            messages: stream.messages as i64,
+            interval: Some(stream.interval.into()),
+        }),
+        data: vec![0; request_size],
+    }
+}
+
+/// Builds a stream of `spec.stream.messages` requests, each separated by the stream's interval.
+///
+/// Only the first request carries the requested latency. Each request's data is padded to the
+/// response size.
+fn requests(
+    spec: Spec,
+    error: Option<Code>,
+) -> impl Stream<Item = ResponseSpec> + Send + Sync + 'static {
+    let messages = spec.stream.messages.max(1);
+    stream::unfold(0, move |n| async move {
+        if n == messages {
+            return None;
+        }
+        let req = if n == 0 {
+            request(spec, error, spec.response_size)
+        } else {
+            time::sleep(spec.stream.interval).await;
+            let spec = Spec {
+                latency: time::Duration::from_secs(0),
+                ..spec
+            };
+            request(spec, error, spec.response_size)
+        };
+        Some((req, n + 1))
+    })
+}
+
+/// Reads a response stream. Failures are attributed to the instance that began the response.
+async fn collect(
+    mut rsps: tonic::Streaming<ResponseReply>,
+    instance: Option<&str>,
+) -> Result<Vec<ResponseReply>, Error> {
+    let mut replies = Vec::new();
+    loop {
+        match rsps.message().await {
+            Ok(Some(rsp)) => replies.push(rsp),
+            Ok(None) => return Ok(replies),
+            Err(status) => {
+                return Err(match instance {
+                    Some(instance) => {
+                        InstanceError::new(instance.to_string(), status.into()).into()
+                    }
+                    None => status.into(),
+                })
+            }
+        }
+    }
+}
+
+// === impl Compression ===
+
+impl FromStr for Compression {
+    type Err = InvalidCompression;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "gzip" => Ok(Self::Gzip),
+            _ => Err(InvalidCompression(())),
+        }
+    }
+}
+
+impl std::fmt::Display for InvalidCompression {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid compression; expected: gzip")
+    }
+}
+
+impl std::error::Error for InvalidCompression {}
+
+// === impl Mode ===
+
+impl FromStr for Mode {
+    type Err = InvalidMode;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "unary" => Ok(Self::Unary),
+            "server-stream" => Ok(Self::ServerStream),
+            "client-stream" => Ok(Self::ClientStream),
+            "bidi" => Ok(Self::Bidi),
+            _ => Err(InvalidMode(())),
+        }
+    }
+}
+
+impl std::fmt::Display for InvalidMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "invalid mode; expected one of: unary, server-stream, client-stream, bidi"
+        )
+    }
+}
+
+impl std::error::Error for InvalidMode {}
+
+// === impl Metadata ===
+
+impl FromStr for Metadata {
+    type Err = InvalidMetadata;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut kv = s.splitn(2, '=');
+        match (kv.next(), kv.next()) {
+            (Some(k), Some(v)) => {
+                let key = k.parse().map_err(|_| InvalidMetadata(()))?;
+                let value = v.parse().map_err(|_| InvalidMetadata(()))?;
+                Ok(Self { key, value })
+            }
+            _ => Err(InvalidMetadata(())),
+        }
+    }
+}
+
+impl std::fmt::Display for InvalidMetadata {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid metadata; expected an ASCII key=value pair")
+    }
+}
+
+impl std::error::Error for InvalidMetadata {}
+
+// === impl ErrorCodes ===
+
+impl Distribution<Option<Code>> for ErrorCodes {
+    fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> Option<Code> {
+        if self.0.is_empty() {
+            return None;
+        }
+
+        let mut p = rng.gen::<f64>();
+        for (rate, code) in self.0.iter() {
+            if p < *rate {
+                return Some(*code);
+            }
+            p -= rate;
+        }
+        None
+    }
+}
+
+impl FromStr for ErrorCodes {
+    type Err = InvalidErrorCodes;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut codes = Vec::new();
+        let mut total = 0.0;
+        for cr in s.split(',').filter(|cr| !cr.is_empty()) {
+            let mut cr = cr.splitn(2, '=');
+            let (code, rate) = match (cr.next(), cr.next()) {
+                (Some(c), Some(r)) => {
+                    let code = parse_code(c).ok_or(InvalidErrorCodes(()))?;
+                    let rate = r.parse::<f64>().map_err(|_| InvalidErrorCodes(()))?;
+                    (code, rate)
+                }
+                _ => return Err(InvalidErrorCodes(())),
+            };
+            if code == Code::Ok || !(0.0..=1.0).contains(&rate) {
+                return Err(InvalidErrorCodes(()));
+            }
+            total += rate;
+            codes.push((rate, code));
+        }
+        if total > 1.0 {
+            return Err(InvalidErrorCodes(()));
+        }
+        Ok(Self(codes))
+    }
+}
+
+/// Parses a status code from either its numeric value or its name (e.g. `14`, `UNAVAILABLE`, or
+/// `unavailable`).
+fn parse_code(s: &str) -> Option<Code> {
+    if let Ok(n) = s.parse::<i32>() {
+        return (0..=16).contains(&n).then(|| Code::from_i32(n));
+    }
+
+    let code = match s.to_ascii_lowercase().replace('-', "_").as_str() {
+        "ok" => Code::Ok,
+        "cancelled" => Code::Cancelled,
+        "unknown" => Code::Unknown,
+        "invalid_argument" => Code::InvalidArgument,
+        "deadline_exceeded" => Code::DeadlineExceeded,
+        "not_found" => Code::NotFound,
+        "already_exists" => Code::AlreadyExists,
+        "permission_denied" => Code::PermissionDenied,
+        "resource_exhausted" => Code::ResourceExhausted,
+        "failed_precondition" => Code::FailedPrecondition,
+        "aborted" => Code::Aborted,
+        "out_of_range" => Code::OutOfRange,
+        "unimplemented" => Code::Unimplemented,
+        "internal" => Code::Internal,
+        "unavailable" => Code::Unavailable,
+        "data_loss" => Code::DataLoss,
+        "unauthenticated" => Code::Unauthenticated,
+        _ => return None,
+    };
+    Some(code)
+}
+
+impl std::fmt::Display for InvalidErrorCodes {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "invalid error codes; expected comma-separated code=rate pairs with rates summing to at most 1"
+        )
+    }
+}
+
+impl std::error::Error for InvalidErrorCodes {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_error_codes() {
+        let ErrorCodes(codes) = "unavailable=0.1,13=0.2,RESOURCE_EXHAUSTED=0.3"
+            .parse()
+            .expect("must parse");
+        assert_eq!(
+            codes,
+            vec![
+                (0.1, Code::Unavailable),
+                (0.2, Code::Internal),
+                (0.3, Code::ResourceExhausted)
+            ]
+        );
+
+        assert!("".parse::<ErrorCodes>().unwrap().0.is_empty());
+        assert!("ok=0.1".parse::<ErrorCodes>().is_err());
+        assert!("17=0.1".parse::<ErrorCodes>().is_err());
+        assert!("bogus=0.1".parse::<ErrorCodes>().is_err());
+        assert!("internal=0.6,unavailable=0.6"
+            .parse::<ErrorCodes>()
+            .is_err());
+        assert!("internal".parse::<ErrorCodes>().is_err());
+    }
+
+    #[test]
+    fn sample_error_codes() {
+        let codes = "unavailable=1".parse::<ErrorCodes>().unwrap();
+        assert_eq!(codes.sample(&mut thread_rng()), Some(Code::Unavailable));
+
+        let codes = ErrorCodes::default();
+        assert_eq!(codes.sample(&mut thread_rng()), None);
+    }
+}
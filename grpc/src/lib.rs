@@ -8,4 +8,7 @@ pub mod server;
 
 pub mod proto {
     tonic::include_proto!("ort.olix0r.net");
+
+    /// The encoded descriptors for the `ort.olix0r.net` package, used by server reflection.
+    pub const FILE_DESCRIPTOR_SET: &[u8] = tonic::include_file_descriptor_set!("ort_descriptor");
 }
@@ -1,37 +1,130 @@
 //use ort_core::{Spec, Reply};
-use crate::proto::{ort_server, response_spec as spec, ResponseReply, ResponseSpec};
+use crate::proto::{self, ort_server, response_spec as spec, ResponseReply, ResponseSpec};
 use drain::Watch as Drain;
 use futures::prelude::*;
-use ort_core::{Error, Ort, Reply, Spec};
-use std::convert::TryInto;
+use ort_core::{
+    fault::{FaultIo, Injector},
+    latency,
+    limit::Overloaded,
+    ConnMetrics, CountedIo, Error, Failure, Hop, Ort, PacedIo, Pacing, Reply, Spec, StreamSpec,
+    INSTANCE_HEADER,
+};
+use rand::{distributions::Distribution, thread_rng};
+use std::{
+    convert::TryInto,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+use tokio::{
+    io::{self, AsyncRead, AsyncWrite, ReadBuf},
+    time,
+};
 
 #[derive(Clone)]
 pub struct Server<O> {
     inner: O,
+    instance: Option<tonic::metadata::AsciiMetadataValue>,
     window_size: u32,
+    gzip: bool,
+    stream_intervals: latency::Distribution,
+    pacing: Option<Pacing>,
+    conns: Arc<ConnMetrics>,
 }
 
+/// A connection into which faults may be injected and whose writes may be paced. Its `Injector` is
+/// exposed to handlers via request extensions.
+struct Conn(CountedIo<PacedIo<FaultIo>>);
+
+type ReplyStream =
+    Pin<Box<dyn Stream<Item = Result<ResponseReply, tonic::Status>> + Send + 'static>>;
+
 impl<O: Ort + Sync> Server<O> {
-    pub fn new(inner: O) -> Self {
+    /// Creates a server. When `gzip` is set, replies are compressed for clients that accept gzip;
+    /// compressed requests are always accepted.
+    ///
+    /// Streamed replies are separated by at least an interval sampled from `stream_intervals`,
+    /// regardless of the interval requested by the client. When pacing is configured, all writes
+    /// on each connection are trickled. Connections are recorded in `conns`.
+    ///
+    /// Every response, including failed unary responses, carries `instance` in its `ort-instance`
+    /// metadata.
+    ///
+    /// # Panics
+    ///
+    /// If the instance ID is not a valid ASCII metadata value.
+    pub fn new(
+        inner: O,
+        instance: Option<String>,
+        gzip: bool,
+        stream_intervals: latency::Distribution,
+        pacing: Option<Pacing>,
+        conns: Arc<ConnMetrics>,
+    ) -> Self {
+        let instance = instance.map(|i| {
+            tonic::metadata::MetadataValue::from_str(&i)
+                .expect("instance ID must be a valid metadata value")
+        });
         Self {
             inner,
+            instance,
             window_size: 2u32.pow(31) - 1,
+            gzip,
+            stream_intervals,
+            pacing,
+            conns,
         }
     }
 
     pub async fn serve(self, addr: std::net::SocketAddr, drain: Drain) -> Result<(), Error> {
         let (close, closed) = tokio::sync::oneshot::channel();
 
+        let (mut health, health_svc) = tonic_health::server::health_reporter();
+        health.set_serving::<ort_server::OrtServer<Self>>().await;
+
+        let reflection = tonic_reflection::server::Builder::configure()
+            .register_encoded_file_descriptor_set(proto::FILE_DESCRIPTOR_SET)
+            .build()?;
+
+        let window_size = self.window_size;
+        let gzip = self.gzip;
+        let pacing = self.pacing;
+        let conns = self.conns.clone();
+        let mut ort = ort_server::OrtServer::new(self).accept_gzip();
+        if gzip {
+            ort = ort.send_gzip();
+        }
+
+        let lis = tokio::net::TcpListener::bind(addr).await?;
+        let incoming = stream::unfold(lis, move |lis| {
+            let conns = conns.clone();
+            async move {
+                let conn = lis.accept().await.map(|(sock, _)| {
+                    let io = PacedIo::new(FaultIo::new(sock), pacing);
+                    Conn(CountedIo::new(io, conns))
+                });
+                Some((conn, lis))
+            }
+        });
+
         tokio::pin! {
             let srv = tonic::transport::Server::builder()
-                .initial_connection_window_size(self.window_size)
-                .add_service(ort_server::OrtServer::new(self))
-                .serve_with_shutdown(addr, closed.map(|_| ()));
+                .initial_connection_window_size(window_size)
+                .add_service(health_svc)
+                .add_service(reflection)
+                .add_service(ort)
+                .serve_with_incoming_shutdown(incoming, closed.map(|_| ()));
         }
         tracing::info!("Listening on {}", addr);
         tokio::select! {
             _ = (&mut srv) => {}
             handle = drain.signaled() => {
+                // Health checks on connections that remain open while draining report that
+                // the server is no longer serving.
+                health
+                    .set_service_status("", tonic_health::ServingStatus::NotServing)
+                    .await;
+                health.set_not_serving::<ort_server::OrtServer<Self>>().await;
                 let _ = close.send(());
                 handle.release_after(srv).await?;
             }
@@ -42,58 +135,355 @@ impl<O: Ort + Sync> Server<O> {
 
 #[tonic::async_trait]
 impl<O: Ort + Sync> ort_server::Ort for Server<O> {
+    type GetStreamStream = ReplyStream;
+    type BidiStream = ReplyStream;
+
     async fn get(
         &self,
         req: tonic::Request<ResponseSpec>,
     ) -> Result<tonic::Response<ResponseReply>, tonic::Status> {
-        let ResponseSpec {
-            latency,
-            result,
-            data: _,
-        } = req.into_inner();
-
-        let latency = latency.and_then(|l| l.try_into().ok()).unwrap_or_default();
-
-        let response_size = match result {
-            None => 0,
-            Some(spec::Result::Success(spec::Success { size })) => size as usize,
-            Some(spec::Result::Error(spec::Error { code, message })) => {
-                let code = match code {
-                    1 => tonic::Code::Cancelled,
-                    2 => tonic::Code::Unknown,
-                    3 => tonic::Code::InvalidArgument,
-                    4 => tonic::Code::DeadlineExceeded,
-                    5 => tonic::Code::NotFound,
-                    6 => tonic::Code::AlreadyExists,
-                    7 => tonic::Code::PermissionDenied,
-                    8 => tonic::Code::ResourceExhausted,
-                    9 => tonic::Code::FailedPrecondition,
-                    10 => tonic::Code::Aborted,
-                    11 => tonic::Code::OutOfRange,
-                    12 => tonic::Code::Unimplemented,
-                    13 => tonic::Code::Internal,
-                    14 => tonic::Code::Unavailable,
-                    15 => tonic::Code::DataLoss,
-                    16 => tonic::Code::Unauthenticated,
-                    _ => tonic::Code::InvalidArgument,
+        let deadline = deadline(&req);
+        let injector = injector(&req);
+        let spec = parse_spec(req.into_inner())?;
+        let reply = until(deadline, reply(self.inner.clone(), injector, spec))
+            .await
+            .map_err(|s| self.failed(s))?;
+        Ok(self.response(reply))
+    }
+
+    async fn get_stream(
+        &self,
+        req: tonic::Request<ResponseSpec>,
+    ) -> Result<tonic::Response<ReplyStream>, tonic::Status> {
+        let deadline = deadline(&req);
+        let injector = injector(&req);
+        let spec = parse_spec(req.into_inner())?;
+        let inner = self.inner.clone();
+        let intervals = self.stream_intervals.clone();
+
+        // Only the first reply is delayed by the requested latency. Subsequent replies are
+        // separated by the stream's interval.
+        let replies = stream::unfold(0, move |n| {
+            let inner = inner.clone();
+            let injector = injector.clone();
+            let intervals = intervals.clone();
+            async move {
+                if n == spec.stream.messages.max(1) {
+                    return None;
+                }
+                let spec = if n == 0 {
+                    spec
+                } else {
+                    let interval = spec
+                        .stream
+                        .interval
+                        .max(intervals.sample(&mut thread_rng()));
+                    time::sleep(interval).await;
+                    Spec {
+                        latency: time::Duration::from_secs(0),
+                        ..spec
+                    }
                 };
-                return Err(tonic::Status::new(code, message));
+                Some((reply(inner, injector, spec).await, n + 1))
             }
-        };
-
-        let spec = Spec {
-            latency,
-            response_size,
-        };
-        let mut inner = self.inner.clone();
-        inner
-            .ort(spec)
-            .await
-            .map(|Reply { data }| {
-                tonic::Response::new(ResponseReply {
-                    data: data.into_iter().collect(),
-                })
+        });
+
+        Ok(self.response(stream_until(deadline, replies)))
+    }
+
+    async fn put_stream(
+        &self,
+        req: tonic::Request<tonic::Streaming<ResponseSpec>>,
+    ) -> Result<tonic::Response<ResponseReply>, tonic::Status> {
+        let deadline = deadline(&req);
+        let injector = injector(&req);
+        let mut specs = req.into_inner();
+        let inner = self.inner.clone();
+
+        let reply = until(deadline, async move {
+            // Read the entire request stream before replying.
+            let mut spec = None;
+            while let Some(msg) = specs.message().await? {
+                if spec.is_none() {
+                    spec = Some(parse_spec(msg)?);
+                }
+            }
+            reply(inner, injector, spec.unwrap_or_default()).await
+        })
+        .await
+        .map_err(|s| self.failed(s))?;
+        Ok(self.response(reply))
+    }
+
+    async fn bidi(
+        &self,
+        req: tonic::Request<tonic::Streaming<ResponseSpec>>,
+    ) -> Result<tonic::Response<ReplyStream>, tonic::Status> {
+        let deadline = deadline(&req);
+        let injector = injector(&req);
+        let inner = self.inner.clone();
+        let replies = req.into_inner().and_then(move |msg| {
+            let inner = inner.clone();
+            let injector = injector.clone();
+            async move {
+                let spec = parse_spec(msg)?;
+                reply(inner, injector, spec).await
+            }
+        });
+
+        Ok(self.response(stream_until(deadline, replies)))
+    }
+}
+
+impl<O> Server<O> {
+    /// Builds a response that carries the server's instance ID.
+    fn response<T>(&self, msg: T) -> tonic::Response<T> {
+        let mut rsp = tonic::Response::new(msg);
+        if let Some(instance) = self.instance.clone() {
+            rsp.metadata_mut().insert(INSTANCE_HEADER, instance);
+        }
+        rsp
+    }
+
+    /// Stamps the server's instance ID into a failed response's status.
+    fn failed(&self, mut status: tonic::Status) -> tonic::Status {
+        if let Some(instance) = self.instance.clone() {
+            status.metadata_mut().insert(INSTANCE_HEADER, instance);
+        }
+        status
+    }
+}
+
+async fn reply<O: Ort>(
+    mut inner: O,
+    injector: Injector,
+    spec: Spec,
+) -> Result<ResponseReply, tonic::Status> {
+    let Reply { data, hops, .. } = match inner.ort(spec).await {
+        Ok(reply) => reply,
+        Err(error) => injector
+            .recover(error, spec.response_size)
+            .map_err(status)?,
+    };
+    Ok(ResponseReply {
+        data: data.into_iter().collect(),
+        hops: hops
+            .into_iter()
+            .map(|Hop { instance, latency }| proto::Hop {
+                instance: instance.unwrap_or_default(),
+                latency: Some(latency.into()),
             })
-            .map_err(|e| tonic::Status::internal(e.to_string()))
+            .collect(),
+    })
+}
+
+fn status(error: Error) -> tonic::Status {
+    let error = match error.downcast::<tonic::Status>() {
+        Ok(status) => return *status,
+        Err(error) => error,
+    };
+    let error = match error.downcast::<Failure>() {
+        Ok(failure) => {
+            let code = failure
+                .status
+                .map(|s| tonic::Code::from_i32(s as i32))
+                .unwrap_or(tonic::Code::Unavailable);
+            return tonic::Status::new(code, failure.to_string());
+        }
+        Err(error) => error,
+    };
+    match error.downcast::<Overloaded>() {
+        Ok(overloaded) => tonic::Status::resource_exhausted(overloaded.to_string()),
+        Err(error) => tonic::Status::internal(error.to_string()),
+    }
+}
+
+/// Gets the fault injector for the connection on which the request was received.
+fn injector<T>(req: &tonic::Request<T>) -> Injector {
+    req.extensions()
+        .get::<Injector>()
+        .cloned()
+        .unwrap_or_default()
+}
+
+/// Determines the request's deadline from its `grpc-timeout` header.
+fn deadline<T>(req: &tonic::Request<T>) -> Option<time::Instant> {
+    let timeout = req.metadata().get("grpc-timeout")?.to_str().ok()?;
+    time::Instant::now().checked_add(parse_timeout(timeout)?)
+}
+
+/// Parses a `grpc-timeout` value, which is at most 8 digits followed by a unit.
+fn parse_timeout(timeout: &str) -> Option<time::Duration> {
+    if timeout.is_empty() {
+        return None;
+    }
+    let (value, unit) = timeout.split_at(timeout.len() - 1);
+    if value.is_empty() || value.len() > 8 || !value.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let value = value.parse::<u64>().ok()?;
+    let timeout = match unit {
+        "H" => time::Duration::from_secs(value.checked_mul(60 * 60)?),
+        "M" => time::Duration::from_secs(value.checked_mul(60)?),
+        "S" => time::Duration::from_secs(value),
+        "m" => time::Duration::from_millis(value),
+        "u" => time::Duration::from_micros(value),
+        "n" => time::Duration::from_nanos(value),
+        _ => return None,
+    };
+    Some(timeout)
+}
+
+/// Fails with `DEADLINE_EXCEEDED` if the future does not complete before the deadline, dropping
+/// any in-flight work.
+async fn until<T>(
+    deadline: Option<time::Instant>,
+    f: impl Future<Output = Result<T, tonic::Status>>,
+) -> Result<T, tonic::Status> {
+    match deadline {
+        None => f.await,
+        Some(deadline) => time::timeout_at(deadline, f)
+            .await
+            .map_err(|_| tonic::Status::deadline_exceeded("Deadline exceeded"))?,
+    }
+}
+
+/// Ends the stream with `DEADLINE_EXCEEDED` if it does not complete before the deadline, dropping
+/// any in-flight work.
+fn stream_until<S>(deadline: Option<time::Instant>, replies: S) -> ReplyStream
+where
+    S: Stream<Item = Result<ResponseReply, tonic::Status>> + Send + 'static,
+{
+    let deadline = match deadline {
+        Some(deadline) => deadline,
+        None => return Box::pin(replies),
+    };
+
+    Box::pin(stream::unfold(
+        Some(Box::pin(replies)),
+        move |replies| async move {
+            let mut replies = replies?;
+            match time::timeout_at(deadline, replies.next()).await {
+                Ok(Some(rsp)) => Some((rsp, Some(replies))),
+                Ok(None) => None,
+                Err(_) => {
+                    let status = tonic::Status::deadline_exceeded("Deadline exceeded");
+                    Some((Err(status), None))
+                }
+            }
+        },
+    ))
+}
+
+// === impl Conn ===
+
+impl tonic::transport::server::Connected for Conn {
+    type ConnectInfo = Injector;
+
+    fn connect_info(&self) -> Injector {
+        self.0.get_ref().get_ref().injector().clone()
+    }
+}
+
+impl AsyncRead for Conn {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Conn {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_shutdown(cx)
+    }
+}
+
+fn parse_spec(
+    ResponseSpec {
+        latency,
+        result,
+        stream,
+        data: _,
+    }: ResponseSpec,
+) -> Result<Spec, tonic::Status> {
+    let latency = latency.and_then(|l| l.try_into().ok()).unwrap_or_default();
+
+    let response_size = match result {
+        None => 0,
+        Some(spec::Result::Success(spec::Success { size })) => size as usize,
+        Some(spec::Result::Error(spec::Error { code, message })) => {
+            let code = match code {
+                1 => tonic::Code::Cancelled,
+                2 => tonic::Code::Unknown,
+                3 => tonic::Code::InvalidArgument,
+                4 => tonic::Code::DeadlineExceeded,
+                5 => tonic::Code::NotFound,
+                6 => tonic::Code::AlreadyExists,
+                7 => tonic::Code::PermissionDenied,
+                8 => tonic::Code::ResourceExhausted,
+                9 => tonic::Code::FailedPrecondition,
+                10 => tonic::Code::Aborted,
+                11 => tonic::Code::OutOfRange,
+                12 => tonic::Code::Unimplemented,
+                13 => tonic::Code::Internal,
+                14 => tonic::Code::Unavailable,
+                15 => tonic::Code::DataLoss,
+                16 => tonic::Code::Unauthenticated,
+                _ => tonic::Code::InvalidArgument,
+            };
+            return Err(tonic::Status::new(code, message));
+        }
+    };
+
+    let stream = stream
+        .map(|spec::Stream { messages, interval }| StreamSpec {
+            messages: messages.max(0) as usize,
+            interval: interval.and_then(|i| i.try_into().ok()).unwrap_or_default(),
+        })
+        .unwrap_or_default();
+
+    Ok(Spec {
+        latency,
+        response_size,
+        stream,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_timeouts() {
+        assert_eq!(parse_timeout("10S"), Some(time::Duration::from_secs(10)));
+        assert_eq!(
+            parse_timeout("250m"),
+            Some(time::Duration::from_millis(250))
+        );
+        assert_eq!(
+            parse_timeout("99999999H"),
+            Some(time::Duration::from_secs(99_999_999 * 60 * 60))
+        );
+        assert_eq!(parse_timeout(""), None);
+        assert_eq!(parse_timeout("S"), None);
+        assert_eq!(parse_timeout("+1S"), None);
+        assert_eq!(parse_timeout("100000000n"), None);
+        assert_eq!(parse_timeout("18446744073709551615H"), None);
+        assert_eq!(parse_timeout("10x"), None);
     }
 }
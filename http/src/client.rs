@@ -1,24 +1,84 @@
-use ort_core::{Error, MakeOrt, Ort, Reply, Spec};
-use std::convert::TryFrom;
-use tokio::time::Duration;
+use crate::Encoding;
+use hyper::{
+    client::connect::{Connected, Connection},
+    service::Service,
+};
+use ort_core::{
+    Bind, ConnWatch, Error, Failure, Hops, InstanceError, MakeOrt, Ort, RecordConnect, Reply, Spec,
+    WatchedIo, HOPS_HEADER, INSTANCE_HEADER,
+};
+use std::{
+    convert::TryFrom,
+    future::Future,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+use tokio::{
+    io::{self, AsyncRead, AsyncWrite, ReadBuf},
+    net::TcpStream,
+    time::{self, Duration},
+};
 
 #[derive(Clone)]
 pub struct MakeHttp {
     concurrency: Option<usize>,
     connect_timeout: Duration,
+    first_write_delay: Option<Duration>,
+    encoding: Option<Encoding>,
+    watch: Option<ConnWatch>,
+    bind: Option<Bind>,
+    connects: Option<Arc<dyn RecordConnect>>,
 }
 
 #[derive(Clone)]
 pub struct Http {
-    client: hyper::Client<hyper::client::HttpConnector>,
+    client: hyper::Client<Connect>,
     target: http::Uri,
+    encoding: Option<Encoding>,
 }
 
+/// Establishes connections, optionally waiting before the connection is used so that the
+/// client's first write is delayed.
+#[derive(Clone)]
+struct Connect {
+    bind: Bind,
+    connect_timeout: Duration,
+    first_write_delay: Option<Duration>,
+    watch: Option<ConnWatch>,
+    connects: Option<Arc<dyn RecordConnect>>,
+}
+
+/// A connection, which may be watched.
+struct Conn(WatchedIo<TcpStream>);
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
 impl MakeHttp {
-    pub fn new(concurrency: Option<usize>, connect_timeout: Duration) -> Self {
+    /// Creates a client factory. When a first-write delay is configured, each new connection
+    /// waits before the request is written. When an encoding is configured, clients advertise it
+    /// via `accept-encoding` and decode replies that use it. When a watch is configured, idle
+    /// connections are held open until they are closed by the server (or a proxy) and each
+    /// connection's close is reported. When a bind is configured, connections rotate across its
+    /// local addresses. Clients connect lazily, so each connection's latency is recorded in
+    /// `connects` as it is established.
+    pub fn new(
+        concurrency: Option<usize>,
+        connect_timeout: Duration,
+        first_write_delay: Option<Duration>,
+        encoding: Option<Encoding>,
+        watch: Option<ConnWatch>,
+        bind: Option<Bind>,
+        connects: Option<Arc<dyn RecordConnect>>,
+    ) -> Self {
         Self {
             concurrency,
             connect_timeout,
+            first_write_delay,
+            encoding,
+            watch,
+            bind,
+            connects,
         }
     }
 }
@@ -28,18 +88,103 @@ impl MakeOrt<http::Uri> for MakeHttp {
     type Ort = Http;
 
     async fn make_ort(&mut self, target: http::Uri) -> Result<Http, Error> {
-        let mut connect = hyper::client::HttpConnector::new();
-        connect.set_connect_timeout(Some(self.connect_timeout));
-        connect.set_nodelay(true);
-        connect.set_reuse_address(true);
+        let connect = Connect {
+            bind: self.bind.clone().unwrap_or_default(),
+            connect_timeout: self.connect_timeout,
+            first_write_delay: self.first_write_delay,
+            watch: self.watch.clone(),
+            connects: self.connects.clone(),
+        };
 
         let mut builder = hyper::Client::builder();
         if let Some(c) = self.concurrency {
             builder.pool_max_idle_per_host(c);
         }
+        if self.watch.is_some() {
+            builder.pool_idle_timeout(None);
+        }
         let client = builder.build(connect);
 
-        Ok(Http { client, target })
+        Ok(Http {
+            client,
+            target,
+            encoding: self.encoding,
+        })
+    }
+}
+
+// === impl Connect ===
+
+impl Service<http::Uri> for Connect {
+    type Response = Conn;
+    type Error = BoxError;
+    type Future = Pin<Box<dyn Future<Output = Result<Conn, BoxError>> + Send + 'static>>;
+
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), BoxError>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, dst: http::Uri) -> Self::Future {
+        let bind = self.bind.clone();
+        let timeout = self.connect_timeout;
+        let delay = self.first_write_delay;
+        let watch = self.watch.clone();
+        let connects = self.connects.clone();
+        Box::pin(async move {
+            // IPv6 hosts are bracketed in URIs.
+            let host = dst.host().unwrap_or_default();
+            let host = host.trim_start_matches('[').trim_end_matches(']');
+            let port = dst.port_u16().unwrap_or(80);
+            let stream = bind
+                .connect_timeout((host, port), timeout, connects.as_deref())
+                .await?;
+            stream.set_nodelay(true)?;
+            let io = match watch {
+                Some(watch) => watch.watch(stream)?,
+                None => WatchedIo::unwatched(stream),
+            };
+            if let Some(delay) = delay {
+                tracing::debug!(?delay, "Delaying first write");
+                time::sleep(delay).await;
+            }
+            Ok(Conn(io))
+        })
+    }
+}
+
+// === impl Conn ===
+
+impl Connection for Conn {
+    fn connected(&self) -> Connected {
+        self.0.get_ref().connected()
+    }
+}
+
+impl AsyncRead for Conn {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Conn {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_shutdown(cx)
     }
 }
 
@@ -50,6 +195,7 @@ impl Ort for Http {
         Spec {
             latency,
             response_size,
+            ..
         }: Spec,
     ) -> Result<Reply, Error> {
         let mut uri = http::Uri::builder();
@@ -73,18 +219,48 @@ impl Ort for Http {
             )
         };
 
+        let mut req = http::Request::builder().uri(uri.build().unwrap());
+        if let Some(encoding) = self.encoding {
+            req = req.header(http::header::ACCEPT_ENCODING, encoding.as_str());
+        }
         let rsp = self
             .client
-            .request(
-                http::Request::builder()
-                    .uri(uri.build().unwrap())
-                    .body(hyper::Body::default())
-                    .unwrap(),
-            )
+            .request(req.body(hyper::Body::default()).unwrap())
             .await?;
+        let instance = rsp
+            .headers()
+            .get(INSTANCE_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string);
+        if !rsp.status().is_success() {
+            let failure = Failure {
+                status: Some(rsp.status().as_u16()),
+            };
+            return Err(match instance {
+                Some(instance) => InstanceError::new(instance, failure.into()).into(),
+                None => failure.into(),
+            });
+        }
+        let hops = match rsp.headers().get(HOPS_HEADER) {
+            Some(v) => v.to_str()?.parse::<Hops>()?.0,
+            None => vec![],
+        };
+        let encoding = rsp
+            .headers()
+            .get(http::header::CONTENT_ENCODING)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<Encoding>().ok());
+        let mut data = hyper::body::to_bytes(rsp.into_body()).await?;
+        if let Some(encoding) = encoding {
+            let encoded = data.len();
+            data = encoding.decode(&data)?;
+            tracing::trace!(%encoding, encoded, size = data.len(), "Decoded reply");
+        }
 
-        let data = hyper::body::to_bytes(rsp.into_body()).await?;
-
-        Ok(Reply { data })
+        Ok(Reply {
+            data,
+            instance,
+            hops,
+        })
     }
 }
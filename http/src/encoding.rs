@@ -0,0 +1,137 @@
+use bytes::Bytes;
+use std::{
+    io::{self, Read, Write},
+    str::FromStr,
+};
+
+/// A content-coding that may be applied to response bodies.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Encoding {
+    Gzip,
+    Zstd,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct InvalidEncoding(());
+
+// === impl Encoding ===
+
+impl Encoding {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Gzip => "gzip",
+            Self::Zstd => "zstd",
+        }
+    }
+
+    /// Selects the first of the given encodings that is acceptable according to an
+    /// `accept-encoding` header value.
+    pub(crate) fn negotiate(accept: &str, encodings: &[Encoding]) -> Option<Self> {
+        let acceptable = accept
+            .split(',')
+            .filter_map(|coding| {
+                let mut params = coding.split(';').map(str::trim);
+                let name = params.next()?;
+                // Codings with a quality of zero are explicitly unacceptable.
+                let rejected = params.any(|p| {
+                    p.strip_prefix("q=")
+                        .and_then(|q| q.parse::<f32>().ok())
+                        .map(|q| q == 0.0)
+                        .unwrap_or(false)
+                });
+                (!rejected).then(|| name)
+            })
+            .collect::<Vec<_>>();
+
+        encodings.iter().copied().find(|e| {
+            acceptable
+                .iter()
+                .any(|a| a.eq_ignore_ascii_case(e.as_str()))
+        })
+    }
+
+    pub(crate) fn encode(&self, data: &[u8]) -> io::Result<Bytes> {
+        let buf = match self {
+            Self::Gzip => {
+                let mut enc =
+                    flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                enc.write_all(data)?;
+                enc.finish()?
+            }
+            Self::Zstd => zstd::encode_all(data, 0)?,
+        };
+        Ok(buf.into())
+    }
+
+    pub(crate) fn decode(&self, data: &[u8]) -> io::Result<Bytes> {
+        let buf = match self {
+            Self::Gzip => {
+                let mut buf = Vec::new();
+                flate2::read::GzDecoder::new(data).read_to_end(&mut buf)?;
+                buf
+            }
+            Self::Zstd => zstd::decode_all(data)?,
+        };
+        Ok(buf.into())
+    }
+}
+
+impl FromStr for Encoding {
+    type Err = InvalidEncoding;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "gzip" => Ok(Self::Gzip),
+            "zstd" => Ok(Self::Zstd),
+            _ => Err(InvalidEncoding(())),
+        }
+    }
+}
+
+impl std::fmt::Display for Encoding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl std::fmt::Display for InvalidEncoding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid encoding; expected one of: gzip, zstd")
+    }
+}
+
+impl std::error::Error for InvalidEncoding {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negotiate() {
+        let all = [Encoding::Zstd, Encoding::Gzip];
+        assert_eq!(
+            Encoding::negotiate("gzip, deflate", &all),
+            Some(Encoding::Gzip)
+        );
+        assert_eq!(
+            Encoding::negotiate("gzip, zstd", &all),
+            Some(Encoding::Zstd)
+        );
+        assert_eq!(
+            Encoding::negotiate("zstd;q=0, GZIP;q=0.5", &all),
+            Some(Encoding::Gzip)
+        );
+        assert_eq!(Encoding::negotiate("identity", &all), None);
+        assert_eq!(Encoding::negotiate("zstd", &[Encoding::Gzip]), None);
+    }
+
+    #[test]
+    fn roundtrip() {
+        let data = vec![0u8; 1024];
+        for enc in [Encoding::Gzip, Encoding::Zstd] {
+            let encoded = enc.encode(&data).expect("must encode");
+            assert!(encoded.len() < data.len());
+            assert_eq!(enc.decode(&encoded).expect("must decode"), data);
+        }
+    }
+}
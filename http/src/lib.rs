@@ -1,4 +1,7 @@
 #![deny(warnings, rust_2018_idioms)]
 
 pub mod client;
+mod encoding;
 pub mod server;
+
+pub use self::encoding::{Encoding, InvalidEncoding};
@@ -1,21 +1,70 @@
+use crate::Encoding;
 use drain::Watch as Drain;
 use futures::prelude::*;
-use ort_core::{Error, Ort, Reply, Spec};
-use std::{convert::Infallible, net::SocketAddr};
+use ort_core::{
+    fault::{FaultIo, Injector},
+    limit::Overloaded,
+    ConnMetrics, CountedIo, Error, Failure, Hops, Ort, Pacing, Reply, Spec, HOPS_HEADER,
+    INSTANCE_HEADER,
+};
+use std::{convert::Infallible, net::SocketAddr, sync::Arc};
 use tokio::time;
 
 #[derive(Clone, Debug)]
 pub struct Server<O> {
     inner: O,
+    instance: Option<http::HeaderValue>,
+    encodings: Arc<[Encoding]>,
+    pacing: Option<Pacing>,
+    conns: Arc<ConnMetrics>,
 }
 
 impl<O: Ort> Server<O> {
-    pub fn new(inner: O) -> Self {
-        Self { inner }
+    /// Creates a server that may compress replies with any of the given encodings, in order of
+    /// preference, when the client accepts them. Every response carries `instance` in the
+    /// `ort-instance` header.
+    ///
+    /// When pacing is configured, reply bodies are trickled as a chunked body. Connections are
+    /// recorded in `conns`.
+    ///
+    /// # Panics
+    ///
+    /// If the instance ID is not a valid header value.
+    pub fn new(
+        inner: O,
+        instance: Option<String>,
+        encodings: Vec<Encoding>,
+        pacing: Option<Pacing>,
+        conns: Arc<ConnMetrics>,
+    ) -> Self {
+        let instance = instance.map(|i| {
+            http::HeaderValue::try_from(i).expect("instance ID must be a valid header value")
+        });
+        Self {
+            inner,
+            instance,
+            encodings: encodings.into(),
+            pacing,
+            conns,
+        }
+    }
+
+    async fn respond(
+        self,
+        injector: Injector,
+        req: http::Request<hyper::Body>,
+    ) -> Result<http::Response<hyper::Body>, Error> {
+        let instance = self.instance.clone();
+        let mut rsp = self.handle(injector, req).await?;
+        if let Some(instance) = instance {
+            rsp.headers_mut().insert(INSTANCE_HEADER, instance);
+        }
+        Ok(rsp)
     }
 
     async fn handle(
         mut self,
+        injector: Injector,
         req: http::Request<hyper::Body>,
     ) -> Result<http::Response<hyper::Body>, Error> {
         if req.method() == http::Method::GET {
@@ -40,12 +89,58 @@ impl<O: Ort> Server<O> {
                 }
             }
 
-            let Reply { data } = self.inner.ort(spec).await?;
-            return http::Response::builder()
+            let encoding = req
+                .headers()
+                .get_all(http::header::ACCEPT_ENCODING)
+                .iter()
+                .filter_map(|v| v.to_str().ok())
+                .find_map(|v| Encoding::negotiate(v, &self.encodings));
+
+            let Reply { data, hops, .. } = match self.inner.ort(spec).await {
+                Ok(reply) => reply,
+                Err(error) => match error_status(&error) {
+                    Some(status) => {
+                        return http::Response::builder()
+                            .status(status)
+                            .body(hyper::Body::default())
+                            .map_err(Into::into);
+                    }
+                    None => injector.recover(error, spec.response_size)?,
+                },
+            };
+            let mut rsp = http::Response::builder()
                 .status(http::StatusCode::OK)
-                .header(http::header::CONTENT_TYPE, "application/octet-stream")
-                .body(data.into())
-                .map_err(Into::into);
+                .header(http::header::CONTENT_TYPE, "application/octet-stream");
+            if !hops.is_empty() {
+                rsp = rsp.header(HOPS_HEADER, Hops(hops).to_string());
+            }
+            let data = match encoding {
+                Some(encoding) => {
+                    rsp = rsp.header(http::header::CONTENT_ENCODING, encoding.as_str());
+                    let encoded = encoding.encode(&data)?;
+                    tracing::trace!(%encoding, size = data.len(), encoded = encoded.len());
+                    encoded
+                }
+                None => data,
+            };
+            let body = match self.pacing {
+                None => data.into(),
+                Some(pacing) => {
+                    let (mut tx, body) = hyper::Body::channel();
+                    tokio::spawn(async move {
+                        let chunks = pacing.chunks(data);
+                        tokio::pin!(chunks);
+                        while let Some(chunk) = chunks.next().await {
+                            if tx.send_data(chunk).await.is_err() {
+                                tracing::debug!("Client dropped the response body");
+                                return;
+                            }
+                        }
+                    });
+                    body
+                }
+            };
+            return rsp.body(body).map_err(Into::into);
         }
 
         http::Response::builder()
@@ -55,18 +150,40 @@ impl<O: Ort> Server<O> {
     }
 
     pub async fn serve(self, addr: SocketAddr, drain: Drain) -> Result<(), Error> {
-        let svc = hyper::service::make_service_fn(move |_| {
+        let lis = tokio::net::TcpListener::bind(addr).await?;
+        self.serve_listener(lis, drain).await
+    }
+
+    /// Serves connections accepted by an already-bound listener.
+    pub async fn serve_listener(
+        self,
+        lis: tokio::net::TcpListener,
+        drain: Drain,
+    ) -> Result<(), Error> {
+        let addr = lis.local_addr()?;
+        let conns = self.conns.clone();
+        let svc = hyper::service::make_service_fn(move |io: &CountedIo<FaultIo>| {
             let handler = self.clone();
+            let injector = io.get_ref().injector().clone();
             async move {
                 Ok::<_, Infallible>(hyper::service::service_fn(
-                    move |req: http::Request<hyper::Body>| handler.clone().handle(req),
+                    move |req: http::Request<hyper::Body>| {
+                        handler.clone().respond(injector.clone(), req)
+                    },
                 ))
             }
         });
 
+        // Connections are wrapped so that faults may be injected into them.
+        let accept = hyper::server::accept::poll_fn(move |cx| {
+            lis.poll_accept(cx).map(|res| {
+                Some(res.map(|(sock, _)| CountedIo::new(FaultIo::new(sock), conns.clone())))
+            })
+        });
+
         let (close, closed) = tokio::sync::oneshot::channel();
         tokio::pin! {
-            let srv = hyper::Server::bind(&addr)
+            let srv = hyper::Server::builder(accept)
                 .serve(svc)
                 .with_graceful_shutdown(closed.map(|_| ()));
         }
@@ -83,3 +200,18 @@ impl<O: Ort> Server<O> {
         Ok(())
     }
 }
+
+/// Determines the status of a failed or shed request. Other errors have no status.
+fn error_status(error: &Error) -> Option<http::StatusCode> {
+    if let Some(failure) = error.downcast_ref::<Failure>() {
+        let status = failure
+            .status
+            .and_then(|s| http::StatusCode::from_u16(s).ok())
+            .unwrap_or(http::StatusCode::INTERNAL_SERVER_ERROR);
+        return Some(status);
+    }
+    if error.is::<Overloaded>() {
+        return Some(http::StatusCode::SERVICE_UNAVAILABLE);
+    }
+    None
+}
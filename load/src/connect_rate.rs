@@ -0,0 +1,416 @@
+use crate::{admin::Admin, metrics::Report};
+use anyhow::{anyhow, bail, Result};
+use clap::Parser;
+use futures::{prelude::*, stream::FuturesUnordered};
+use ort_core::{parse_duration, Bind, RecordConnect};
+use ort_tcp::muxer;
+use std::{
+    collections::{BTreeMap, HashSet},
+    fmt,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    sync::Arc,
+};
+use tokio::{
+    io::{self, AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    time::{self, Duration, Instant},
+};
+use tracing::{debug, debug_span, info, Instrument};
+
+/// Measures connection establishment alone—TCP handshakes, optionally followed by an HTTP/2
+/// SETTINGS exchange—at a target rate, e.g. to size proxies for short-lived connections. Each
+/// connection is closed as soon as it is established.
+///
+/// TLS handshakes are not supported.
+#[derive(Parser)]
+#[clap(
+    name = "connect-rate",
+    about = "Connection establishment benchmark (TCP and HTTP/2 handshakes; TLS is not supported)"
+)]
+pub struct ConnectRateCmd {
+    /// Serves connection metrics on this address while connecting.
+    #[clap(long, parse(try_from_str), default_value = "0.0.0.0:8000")]
+    admin_addr: SocketAddr,
+
+    /// The number of connections to attempt each second.
+    #[clap(long, default_value = "100")]
+    rate: u32,
+
+    #[clap(long, default_value = "10s", parse(try_from_str = parse_duration))]
+    duration: Duration,
+
+    #[clap(long, default_value = "1s", parse(try_from_str = parse_duration))]
+    connect_timeout: Duration,
+
+    /// Exchanges HTTP/2 SETTINGS with the server after connecting, e.g. with a gRPC port. The
+    /// exchange is bounded by the connect timeout.
+    #[clap(long)]
+    http2: bool,
+
+    /// Binds connections to these local IP addresses, rotating across them.
+    #[clap(long, use_delimiter = true)]
+    bind: Vec<IpAddr>,
+
+    /// The server to connect to, e.g. `tcp://server:8070`. Connections are plaintext.
+    target: Target,
+}
+
+#[derive(Clone, Debug)]
+struct Target(String);
+
+/// The outcome of a single connection attempt.
+struct Attempt {
+    local_port: Option<u16>,
+    connect: Result<Duration, Failure>,
+    handshake: Option<Result<Duration, Failure>>,
+}
+
+/// Classifies failures by errno so that, e.g., refusals are distinguished from resets.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Failure {
+    Timeout,
+    /// No ephemeral ports were available for the connection (`EADDRNOTAVAIL`).
+    PortsExhausted,
+    Errno(i32, io::ErrorKind),
+    Other(io::ErrorKind),
+}
+
+#[derive(Default)]
+struct Stats {
+    attempts: usize,
+    connects: Vec<Duration>,
+    handshakes: Vec<Duration>,
+    failures: BTreeMap<(&'static str, Failure), usize>,
+    local_ports: HashSet<u16>,
+    peak_in_flight: usize,
+}
+
+/// The HTTP/2 connection preface followed by an empty SETTINGS frame.
+const H2_PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n\0\0\0\x04\0\0\0\0\0";
+
+const H2_SETTINGS_ACK: &[u8] = b"\0\0\0\x04\x01\0\0\0\0";
+
+const H2_FRAME_SETTINGS: u8 = 0x4;
+
+/// The default maximum HTTP/2 frame size, which servers may not exceed before their SETTINGS
+/// are acknowledged.
+const H2_MAX_FRAME_LEN: usize = 16_384;
+
+/// The maximum rate, at which attempts are a nanosecond apart. Faster rates cannot be expressed
+/// as an interval.
+const MAX_RATE: u32 = 1_000_000_000;
+
+// === impl ConnectRateCmd ===
+
+impl ConnectRateCmd {
+    fn validate(&self) -> Result<()> {
+        if self.rate == 0 || self.rate > MAX_RATE {
+            bail!("--rate must be between 1 and {}", MAX_RATE);
+        }
+        Ok(())
+    }
+
+    pub async fn run(self) -> Result<()> {
+        self.validate()?;
+
+        // Resolve the target once so that DNS lookups are not measured.
+        let Target(target) = self.target;
+        let addr = tokio::net::lookup_host(&target)
+            .await?
+            .next()
+            .ok_or_else(|| anyhow!("no addresses found for {}", target))?;
+        info!(%target, %addr, rate = self.rate, duration = ?self.duration, "Connecting");
+
+        let report = Report::new(Arc::new(muxer::Metrics::default()));
+        let connects = report.connects();
+        let admin_addr = self.admin_addr;
+        tokio::spawn(
+            async move {
+                Admin::new(report)
+                    .serve(admin_addr)
+                    .await
+                    .expect("Admin server must not fail")
+            }
+            .instrument(debug_span!("admin")),
+        );
+
+        let bind = Bind::new(self.bind);
+        let mut stats = Stats::default();
+        let mut attempts = FuturesUnordered::new();
+        let deadline = Instant::now() + self.duration;
+        let mut interval = time::interval(Duration::from_secs_f64(1.0 / self.rate as f64));
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {
+                    if Instant::now() >= deadline {
+                        break;
+                    }
+                    let task = attempt(
+                        bind.clone(),
+                        addr,
+                        self.connect_timeout,
+                        self.http2,
+                        connects.clone(),
+                    );
+                    attempts.push(tokio::spawn(task));
+                    stats.peak_in_flight = stats.peak_in_flight.max(attempts.len());
+                }
+                Some(res) = attempts.next(), if !attempts.is_empty() => stats.record(res?),
+            }
+        }
+        debug!(
+            in_flight = attempts.len(),
+            "Waiting for connections to complete"
+        );
+        while let Some(res) = attempts.next().await {
+            stats.record(res?);
+        }
+
+        stats.report(self.http2);
+        Ok(())
+    }
+}
+
+/// Connects to `addr`, optionally exchanges HTTP/2 SETTINGS, and closes the connection. The
+/// connection is recorded in `connects`.
+async fn attempt(
+    bind: Bind,
+    addr: SocketAddr,
+    timeout: Duration,
+    http2: bool,
+    connects: Arc<dyn RecordConnect>,
+) -> Attempt {
+    let t0 = Instant::now();
+    let mut stream = match bind.connect_timeout(addr, timeout, Some(&*connects)).await {
+        Ok(stream) => stream,
+        Err(error) => {
+            return Attempt {
+                local_port: None,
+                connect: Err(Failure::from(error)),
+                handshake: None,
+            }
+        }
+    };
+    let connect = Ok(t0.elapsed());
+    let local_port = stream.local_addr().ok().map(|a| a.port());
+
+    let handshake = if http2 {
+        let t0 = Instant::now();
+        let res = match time::timeout(timeout, exchange_settings(&mut stream)).await {
+            Ok(Ok(())) => Ok(t0.elapsed()),
+            Ok(Err(error)) => Err(Failure::from(error)),
+            Err(_) => Err(Failure::Timeout),
+        };
+        Some(res)
+    } else {
+        None
+    };
+
+    Attempt {
+        local_port,
+        connect,
+        handshake,
+    }
+}
+
+/// Sends the HTTP/2 preface and SETTINGS, and waits to acknowledge the server's SETTINGS.
+async fn exchange_settings(stream: &mut TcpStream) -> io::Result<()> {
+    stream.write_all(H2_PREFACE).await?;
+    loop {
+        let mut head = [0u8; 9];
+        stream.read_exact(&mut head).await?;
+        let len = u32::from_be_bytes([0, head[0], head[1], head[2]]) as usize;
+        if len > H2_MAX_FRAME_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "HTTP/2 frame too large",
+            ));
+        }
+        let mut payload = vec![0u8; len];
+        stream.read_exact(&mut payload).await?;
+
+        // Skip the server's acknowledgement of our SETTINGS and any other frames.
+        let (kind, ack) = (head[3], head[4] & 0x1 == 0x1);
+        if kind == H2_FRAME_SETTINGS && !ack {
+            return stream.write_all(H2_SETTINGS_ACK).await;
+        }
+    }
+}
+
+// === impl Stats ===
+
+impl Stats {
+    fn record(&mut self, attempt: Attempt) {
+        self.attempts += 1;
+        if let Some(port) = attempt.local_port {
+            self.local_ports.insert(port);
+        }
+        match attempt.connect {
+            Ok(latency) => self.connects.push(latency),
+            Err(failure) => *self.failures.entry(("connect", failure)).or_default() += 1,
+        }
+        match attempt.handshake {
+            Some(Ok(latency)) => self.handshakes.push(latency),
+            Some(Err(failure)) => *self.failures.entry(("http2", failure)).or_default() += 1,
+            None => {}
+        }
+    }
+
+    fn report(mut self, http2: bool) {
+        info!(
+            attempts = self.attempts,
+            connected = self.connects.len(),
+            peak_in_flight = self.peak_in_flight,
+            "Connection attempts complete"
+        );
+        let connects = Percentiles::new(&mut self.connects);
+        info!(%connects, "Connect latency");
+        if http2 {
+            let handshakes = Percentiles::new(&mut self.handshakes);
+            info!(%handshakes, "HTTP/2 SETTINGS exchange latency");
+        }
+        for ((phase, failure), count) in self.failures.iter() {
+            info!(phase, %failure, count, "Failures");
+        }
+        match ephemeral_port_range() {
+            Some((lo, hi)) => info!(
+                used = self.local_ports.len(),
+                available = hi.saturating_sub(lo) + 1,
+                "Ephemeral ports"
+            ),
+            None => info!(used = self.local_ports.len(), "Ephemeral ports"),
+        }
+    }
+}
+
+/// Reads the range of ports that the kernel assigns to outbound connections.
+fn ephemeral_port_range() -> Option<(u32, u32)> {
+    let range = std::fs::read_to_string("/proc/sys/net/ipv4/ip_local_port_range").ok()?;
+    let mut ports = range.split_whitespace().map(|p| p.parse::<u32>().ok());
+    Some((ports.next()??, ports.next()??))
+}
+
+struct Percentiles<'a>(&'a [Duration]);
+
+impl<'a> Percentiles<'a> {
+    fn new(latencies: &'a mut [Duration]) -> Self {
+        latencies.sort_unstable();
+        Self(latencies)
+    }
+
+    fn get(&self, q: f64) -> Duration {
+        let idx = ((self.0.len() - 1) as f64 * q).round() as usize;
+        self.0[idx]
+    }
+}
+
+impl fmt::Display for Percentiles<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.0.is_empty() {
+            return write!(f, "none");
+        }
+        write!(
+            f,
+            "p50={:?} p90={:?} p99={:?} max={:?}",
+            self.get(0.5),
+            self.get(0.9),
+            self.get(0.99),
+            self.get(1.0)
+        )
+    }
+}
+
+// === impl Failure ===
+
+impl From<io::Error> for Failure {
+    fn from(error: io::Error) -> Self {
+        if error.kind() == io::ErrorKind::AddrNotAvailable {
+            return Self::PortsExhausted;
+        }
+        match error.raw_os_error() {
+            Some(errno) => Self::Errno(errno, error.kind()),
+            // Timeouts enforced by the client, rather than the OS, have no errno.
+            None if error.kind() == io::ErrorKind::TimedOut => Self::Timeout,
+            None => Self::Other(error.kind()),
+        }
+    }
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Timeout => write!(f, "timeout"),
+            Self::PortsExhausted => write!(f, "ephemeral ports exhausted"),
+            Self::Errno(errno, kind) => write!(f, "errno {} ({:?})", errno, kind),
+            Self::Other(kind) => write!(f, "{:?}", kind),
+        }
+    }
+}
+
+// === impl Target ===
+
+impl FromStr for Target {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let uri = http::Uri::from_str(s)?;
+        match uri.scheme_str() {
+            Some("tcp") => {}
+            Some(s) => bail!("invalid scheme: {}", s),
+            None => bail!("missing scheme"),
+        }
+        let a = uri
+            .authority()
+            .ok_or_else(|| anyhow!("missing authority"))?;
+        let port = a.port_u16().ok_or_else(|| anyhow!("missing port"))?;
+        Ok(Target(format!("{}:{}", a.host(), port)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validates_rate() {
+        let cmd = |rate: &str| {
+            ConnectRateCmd::try_parse_from(["connect-rate", "--rate", rate, "tcp://server:8070"])
+                .expect("must parse")
+        };
+        assert!(cmd("1").validate().is_ok());
+        assert!(cmd("1000000000").validate().is_ok());
+        assert!(cmd("0").validate().is_err());
+        assert!(cmd("1000000001").validate().is_err());
+    }
+
+    #[test]
+    fn classifies_failures() {
+        let error = io::Error::new(io::ErrorKind::AddrNotAvailable, "no ports");
+        assert_eq!(Failure::from(error), Failure::PortsExhausted);
+
+        let error = io::Error::from_raw_os_error(111);
+        let kind = error.kind();
+        assert_eq!(Failure::from(error), Failure::Errno(111, kind));
+
+        let error = io::Error::new(io::ErrorKind::TimedOut, "connect timed out");
+        assert_eq!(Failure::from(error), Failure::Timeout);
+
+        let error = io::Error::new(io::ErrorKind::UnexpectedEof, "closed");
+        assert_eq!(
+            Failure::from(error),
+            Failure::Other(io::ErrorKind::UnexpectedEof)
+        );
+    }
+
+    #[test]
+    fn parse_target() {
+        let Target(target) = "tcp://server:8070".parse().expect("must parse");
+        assert_eq!(target, "server:8070");
+        let Target(target) = "tcp://[::1]:8070".parse().expect("must parse");
+        assert_eq!(target, "[::1]:8070");
+        assert!("server:8070".parse::<Target>().is_err());
+        assert!("http://server:8070".parse::<Target>().is_err());
+        assert!("tcp://server".parse::<Target>().is_err());
+    }
+}
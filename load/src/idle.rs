@@ -0,0 +1,171 @@
+use crate::{timeout::MakeRequestTimeout, Target};
+use anyhow::{bail, Result};
+use clap::Parser;
+use futures::future;
+use ort_core::{parse_duration, Closed, ConnWatch, MakeOrt, Ort, Spec};
+use ort_grpc::client::{
+    ErrorCodes as GrpcErrorCodes, MakeGrpc, Mode as GrpcMode, Settings as GrpcSettings,
+};
+use ort_http::client::MakeHttp;
+use ort_tcp::client::{MakeTcp, Settings as TcpSettings};
+use tokio::{
+    signal::{
+        ctrl_c,
+        unix::{signal, SignalKind},
+    },
+    time::{self, Duration},
+};
+use tracing::{debug, info, info_span, Instrument};
+
+/// Holds connections open—issuing sparse requests, or none at all—and reports when and how each
+/// connection closes, e.g. to verify a proxy's idle timeouts and keepalive behavior.
+#[derive(Parser)]
+#[clap(name = "idle", about = "Idle connection test")]
+pub struct IdleCmd {
+    /// The number of connections to open to each target.
+    #[clap(long, default_value = "1")]
+    connections: usize,
+
+    /// The time each connection waits between requests. When unset, each connection issues a
+    /// single request (so that it is established) and is then held fully idle.
+    #[clap(long, parse(try_from_str = parse_duration))]
+    request_interval: Option<Duration>,
+
+    #[clap(long, parse(try_from_str = parse_duration), default_value = "10s")]
+    request_timeout: Duration,
+
+    #[clap(long, parse(try_from_str = parse_duration), default_value = "1s")]
+    connect_timeout: Duration,
+
+    /// Enables TCP keepalive probes at this interval on all connections.
+    #[clap(long, parse(try_from_str = parse_duration))]
+    tcp_keepalive: Option<Duration>,
+
+    /// Sends HTTP/2 PINGs at this interval on gRPC connections, even while they are idle.
+    #[clap(long, parse(try_from_str = parse_duration))]
+    http2_keepalive_interval: Option<Duration>,
+
+    /// Closes gRPC connections when a PING is not acknowledged within this timeout.
+    #[clap(long, parse(try_from_str = parse_duration))]
+    http2_keepalive_timeout: Option<Duration>,
+
+    /// The servers to connect to, e.g. `http://server:8080 grpc://server:8070 tcp://server:8090`.
+    #[clap(required = true)]
+    targets: Vec<Target>,
+}
+
+// === impl IdleCmd ===
+
+impl IdleCmd {
+    pub async fn run(self) -> Result<()> {
+        if self.connections == 0 {
+            bail!("--connections must be positive");
+        }
+
+        let (watch, mut closes) = ConnWatch::new(self.tcp_keepalive);
+        let grpc_settings = GrpcSettings {
+            connect_timeout: Some(self.connect_timeout),
+            http2_keep_alive_interval: self.http2_keepalive_interval,
+            keep_alive_timeout: self.http2_keepalive_timeout,
+            keep_alive_while_idle: true,
+            watch: Some(watch.clone()),
+            ..GrpcSettings::default()
+        };
+        let make = MakeRequestTimeout::new(
+            (
+                MakeHttp::new(
+                    None,
+                    self.connect_timeout,
+                    None,
+                    None,
+                    Some(watch.clone()),
+                    None,
+                    None,
+                ),
+                MakeGrpc::new(
+                    grpc_settings,
+                    GrpcMode::Unary,
+                    None,
+                    vec![],
+                    GrpcErrorCodes::default(),
+                ),
+                MakeTcp::new(
+                    TcpSettings {
+                        watch: Some(watch),
+                        ..TcpSettings::default()
+                    },
+                    Default::default(),
+                ),
+            ),
+            self.request_timeout,
+        );
+
+        for target in self.targets.into_iter() {
+            for conn in 0..self.connections {
+                let task = hold(
+                    make.clone(),
+                    target.clone(),
+                    self.connect_timeout,
+                    self.request_interval,
+                );
+                tokio::spawn(task.instrument(info_span!("conn", %target, conn)));
+            }
+        }
+        // Once every connection's client is dropped, no further closes can be reported.
+        drop(make);
+
+        let mut term = signal(SignalKind::terminate())?;
+        loop {
+            tokio::select! {
+                _ = ctrl_c() => return Ok(()),
+                _ = term.recv() => return Ok(()),
+                closed = closes.recv() => match closed {
+                    Some(Closed { local, peer, lifetime, reason }) => {
+                        info!(%local, %peer, ?lifetime, ?reason, "Connection closed");
+                    }
+                    None => {
+                        info!("All connections failed");
+                        return Ok(());
+                    }
+                },
+            }
+        }
+    }
+}
+
+/// Connects to the target and issues a request every `interval`, or holds the connection idle
+/// after its first request.
+async fn hold<M: MakeOrt<Target>>(
+    mut make: M,
+    target: Target,
+    connect_timeout: Duration,
+    interval: Option<Duration>,
+) {
+    let mut client = match time::timeout(connect_timeout, make.make_ort(target)).await {
+        Ok(Ok(client)) => client,
+        Ok(Err(error)) => {
+            info!(%error, "Failed to connect");
+            return;
+        }
+        Err(_) => {
+            info!("Connect timed out");
+            return;
+        }
+    };
+
+    loop {
+        let t0 = time::Instant::now();
+        match client.ort(Spec::default()).await {
+            Ok(_) => debug!(latency = ?t0.elapsed(), "Request complete"),
+            Err(error) => info!(%error, "Request failed"),
+        }
+        match interval {
+            Some(interval) => time::sleep(interval).await,
+            None => {
+                debug!("Holding connection idle");
+                // Keep the client, and therefore its connection, until the process exits.
+                future::pending::<()>().await;
+            }
+        }
+    }
+}
@@ -2,22 +2,42 @@
 
 mod admin;
 mod concurrency_ramp;
+mod connect_rate;
+mod idle;
 mod metrics;
 mod rate_limit;
 mod runner;
+mod throughput;
 mod timeout;
 
 use self::{
-    admin::Admin, concurrency_ramp::ConcurrencyRamp, metrics::MakeMetrics, rate_limit::RateLimit,
-    runner::Runner, timeout::MakeRequestTimeout,
+    admin::Admin,
+    concurrency_ramp::ConcurrencyRamp,
+    metrics::{MakeMetrics, Report},
+    rate_limit::RateLimit,
+    runner::{Churn, Runner, Specs},
+    timeout::MakeRequestTimeout,
 };
+pub use self::{connect_rate::ConnectRateCmd, idle::IdleCmd, throughput::ThroughputCmd};
 use anyhow::{anyhow, bail, Result};
 use clap::Parser;
-use ort_core::{latency, parse_duration, Distribution, Error, MakeOrt, Ort, Reply, Spec};
-use ort_grpc::client::MakeGrpc;
-use ort_http::client::MakeHttp;
-use ort_tcp::client::MakeTcp;
-use std::{fmt::Debug, net::SocketAddr, str::FromStr, sync::Arc};
+use ort_core::{latency, parse_duration, Bind, Distribution, Error, MakeOrt, Ort, Reply, Spec};
+use ort_grpc::client::{
+    Compression as GrpcCompression, ErrorCodes as GrpcErrorCodes, MakeGrpc,
+    Metadata as GrpcMetadata, Mode as GrpcMode, Settings as GrpcSettings,
+};
+use ort_http::{client::MakeHttp, Encoding as HttpEncoding};
+use ort_tcp::{
+    client::{MakeTcp, Mode as TcpMode, Settings as TcpSettings, Target as TcpTarget},
+    muxer::{Metrics as TcpMetrics, Settings as TcpMuxerSettings},
+    preface::Version as TcpVersion,
+};
+use std::{
+    fmt::Debug,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    sync::Arc,
+};
 use tokio::{
     signal::{
         ctrl_c,
@@ -75,6 +95,29 @@ pub struct Cmd {
     #[clap(long, parse(try_from_str = parse_duration), default_value = "1s")]
     connect_timeout: Duration,
 
+    /// Replaces each client's connection after it has issued this many requests.
+    #[clap(long)]
+    connection_max_requests: Option<usize>,
+
+    /// Replaces each client's connection once it is this old.
+    #[clap(long, parse(try_from_str = parse_duration))]
+    connection_max_age: Option<Duration>,
+
+    /// Opens a new connection for every request. Equivalent to `--connection-max-requests=1`.
+    #[clap(long, conflicts_with = "connection-max-requests")]
+    connection_per_request: bool,
+
+    /// Binds connections to these local IP addresses, rotating across them, so that more
+    /// ephemeral ports are available than a single address provides. Balanced gRPC channels
+    /// cannot be bound.
+    #[clap(long, use_delimiter = true, conflicts_with = "grpc-balance")]
+    bind: Vec<IpAddr>,
+
+    /// Delays the first write on each new HTTP or TCP connection, e.g. to exercise a proxy's
+    /// protocol detection timeout.
+    #[clap(long, parse(try_from_str = parse_duration))]
+    first_write_delay: Option<Duration>,
+
     #[clap(long)]
     total_requests: Option<usize>,
 
@@ -84,6 +127,75 @@ pub struct Cmd {
     #[clap(long, default_value = "0")]
     response_size: Distribution,
 
+    #[clap(long)]
+    http_compression: Option<HttpEncoding>,
+
+    #[clap(long, default_value = "unary")]
+    grpc_mode: GrpcMode,
+
+    #[clap(long)]
+    grpc_metadata: Vec<GrpcMetadata>,
+
+    #[clap(long, default_value = "")]
+    grpc_errors: GrpcErrorCodes,
+
+    #[clap(long)]
+    grpc_stream_window_size: Option<u32>,
+
+    #[clap(long, default_value = "2147483647")]
+    grpc_connection_window_size: u32,
+
+    #[clap(long)]
+    grpc_adaptive_window: bool,
+
+    #[clap(long, parse(try_from_str = parse_duration))]
+    grpc_keepalive_interval: Option<Duration>,
+
+    #[clap(long, parse(try_from_str = parse_duration))]
+    grpc_keepalive_timeout: Option<Duration>,
+
+    #[clap(long)]
+    grpc_keepalive_while_idle: bool,
+
+    #[clap(long)]
+    grpc_concurrency_limit: Option<usize>,
+
+    #[clap(long)]
+    grpc_compression: Option<GrpcCompression>,
+
+    #[clap(long)]
+    grpc_balance: bool,
+
+    /// Expects TCP servers to write a banner line before the client speaks.
+    #[clap(long)]
+    tcp_banner: bool,
+
+    /// The TCP protocol version to speak, 2 by default. Version 1 is supported for compatibility
+    /// with older servers; it cannot be used with `tcp+serial://` targets, which always speak
+    /// version 2.
+    #[clap(long)]
+    tcp_version: Option<TcpVersion>,
+
+    /// The number of requests that may be in flight on each TCP connection. Further requests
+    /// wait to be dispatched.
+    #[clap(long, default_value = "10000")]
+    tcp_max_in_flight: usize,
+
+    /// The number of requests on each TCP connection that may wait to be dispatched. Once it is
+    /// reached, requests wait to be sent.
+    #[clap(long, default_value = "100000")]
+    tcp_buffer_capacity: usize,
+
+    #[clap(long, default_value = "1")]
+    stream_messages: Distribution,
+
+    #[clap(long, default_value = "0")]
+    stream_interval: latency::Distribution,
+
+    /// The server to load, e.g. `grpc://server:8070`. TCP targets may use `tcp+serial://` to issue
+    /// one request at a time on each connection instead of multiplexing requests. An
+    /// `echo://server:8092` target sends `--response-size` bytes to the server's echo port for
+    /// each request and verifies that they are echoed back.
     target: Target,
 }
 
@@ -96,7 +208,7 @@ struct Ramp {
     reset: bool,
 }
 
-type Target = Flavor<hyper::Uri, hyper::Uri, String>;
+type Target = Flavor<hyper::Uri, hyper::Uri, TcpTarget>;
 
 #[derive(Clone, Debug)]
 pub enum Flavor<H, G, T> {
@@ -113,6 +225,11 @@ impl Cmd {
             admin_addr,
             clients,
             connect_timeout,
+            connection_max_requests,
+            connection_max_age,
+            connection_per_request,
+            bind,
+            first_write_delay,
             concurrency_limit_init,
             concurrency_limit,
             concurrency_limit_ramp_step,
@@ -127,10 +244,54 @@ impl Cmd {
             request_limit_window,
             response_latency,
             response_size,
+            http_compression,
+            grpc_mode,
+            grpc_metadata,
+            grpc_errors,
+            grpc_stream_window_size,
+            grpc_connection_window_size,
+            grpc_adaptive_window,
+            grpc_keepalive_interval,
+            grpc_keepalive_timeout,
+            grpc_keepalive_while_idle,
+            grpc_concurrency_limit,
+            grpc_compression,
+            grpc_balance,
+            tcp_banner,
+            tcp_version,
+            tcp_max_in_flight,
+            tcp_buffer_capacity,
+            stream_messages,
+            stream_interval,
             total_requests,
             target,
         } = self;
 
+        if tcp_max_in_flight == 0 || tcp_buffer_capacity == 0 {
+            bail!("--tcp-max-in-flight and --tcp-buffer-capacity must be positive");
+        }
+        if connection_max_requests == Some(0) {
+            bail!("--connection-max-requests must be positive");
+        }
+        let serial = matches!(
+            target,
+            Target::Tcp(TcpTarget {
+                mode: TcpMode::Serial,
+                ..
+            })
+        );
+        if serial && tcp_version == Some(TcpVersion::V1) {
+            bail!("--tcp-version 1 cannot be used with tcp+serial targets");
+        }
+        let churn = Churn {
+            max_requests: if connection_per_request {
+                Some(1)
+            } else {
+                connection_max_requests
+            },
+            max_age: connection_max_age,
+        };
+
         let concurrency = if let Some(c) = concurrency_limit {
             let ramp = Ramp::try_new(
                 concurrency_limit_init.unwrap_or(c),
@@ -160,18 +321,72 @@ impl Cmd {
             clients.unwrap_or(threads),
             total_requests,
             (concurrency, rate_limit),
-            Arc::new(response_latency),
-            Arc::new(response_size),
+            Specs {
+                response_latencies: response_latency,
+                response_sizes: response_size,
+                stream_messages,
+                stream_intervals: stream_interval,
+            },
+            churn,
         );
 
+        let bind = if bind.is_empty() {
+            None
+        } else {
+            Some(Bind::new(bind))
+        };
+        let grpc_settings = GrpcSettings {
+            connect_timeout: Some(connect_timeout),
+            initial_stream_window_size: grpc_stream_window_size,
+            initial_connection_window_size: Some(grpc_connection_window_size),
+            http2_adaptive_window: grpc_adaptive_window,
+            http2_keep_alive_interval: grpc_keepalive_interval,
+            keep_alive_timeout: grpc_keepalive_timeout,
+            keep_alive_while_idle: grpc_keepalive_while_idle,
+            concurrency_limit: grpc_concurrency_limit,
+            compression: grpc_compression,
+            balance: grpc_balance,
+            watch: None,
+            bind: bind.clone(),
+        };
+
         let (connect, report) = {
+            let tcp_conns = Arc::new(TcpMetrics::default());
+            let report = Report::new(tcp_conns.clone());
             let client = (
-                MakeHttp::new(concurrency_limit, connect_timeout),
-                MakeGrpc::default(),
-                MakeTcp::new(100_000),
+                MakeHttp::new(
+                    concurrency_limit,
+                    connect_timeout,
+                    first_write_delay,
+                    http_compression,
+                    None,
+                    bind.clone(),
+                    Some(report.connects()),
+                ),
+                MakeGrpc::new(
+                    grpc_settings,
+                    grpc_mode,
+                    Some(request_timeout),
+                    grpc_metadata,
+                    grpc_errors,
+                ),
+                MakeTcp::new(
+                    TcpSettings {
+                        muxer: TcpMuxerSettings {
+                            buffer_capacity: tcp_buffer_capacity,
+                            max_in_flight: tcp_max_in_flight,
+                        },
+                        version: tcp_version.unwrap_or_default(),
+                        banner: tcp_banner,
+                        first_write_delay,
+                        watch: None,
+                        bind,
+                    },
+                    tcp_conns,
+                ),
             );
             let client = MakeRequestTimeout::new(client, request_timeout);
-            MakeMetrics::new(client)
+            (MakeMetrics::new(client, &report, grpc_balance), report)
         };
 
         tokio::spawn(
@@ -205,7 +420,7 @@ where
     H::Ort: Send + Sync + 'static,
     G: MakeOrt<hyper::Uri> + Send + Sync + 'static,
     G::Ort: Send + Sync + 'static,
-    T: MakeOrt<String> + Send + Sync + 'static,
+    T: MakeOrt<TcpTarget> + Send + Sync + 'static,
     T::Ort: Send + Sync + 'static,
 {
     type Ort = Flavor<H::Ort, G::Ort, T::Ort>;
@@ -275,12 +490,20 @@ impl FromStr for Target {
                 let uri = Self::uri_default_port(uri, 8080)?;
                 Ok(Target::Http(uri))
             }
-            Some("tcp") => {
-                let uri = Self::uri_default_port(uri, 8090)?;
+            Some(scheme @ ("tcp" | "tcp+serial" | "echo")) => {
+                let (mode, port) = match scheme {
+                    "tcp+serial" => (TcpMode::Serial, 8090),
+                    "echo" => (TcpMode::Echo, 8092),
+                    _ => (TcpMode::Multiplexed, 8090),
+                };
+                let uri = Self::uri_default_port(uri, port)?;
                 let a = uri
                     .authority()
                     .ok_or_else(|| anyhow!("missing authority"))?;
-                Ok(Target::Tcp(a.to_string()))
+                Ok(Target::Tcp(TcpTarget {
+                    addr: a.to_string(),
+                    mode,
+                }))
             }
             Some(s) => bail!("invalid scheme: {}", s),
             None => bail!("missing scheme"),
@@ -1,6 +1,17 @@
-use linkerd_metrics::{metrics, Counter, FmtMetrics, MillisAsSeconds, Summary};
-use ort_core::{Error, MakeOrt, Ort, Reply, Spec};
-use std::{fmt, sync::Arc};
+use crate::{Flavor, Target};
+use linkerd_metrics::{metrics, Counter, FmtLabels, FmtMetrics, Gauge, MillisAsSeconds, Summary};
+use ort_core::{
+    is_ports_exhausted, Error, InstanceError, MakeOrt, Ort, RecordConnect, Reply, Spec,
+};
+use ort_tcp::muxer;
+use parking_lot::RwLock;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt, io,
+    net::SocketAddr,
+    sync::Arc,
+    time::Duration,
+};
 use tokio::time;
 use tracing::trace;
 
@@ -8,6 +19,7 @@ use tracing::trace;
 pub struct MakeMetrics<M> {
     inner: M,
     shared: Arc<Shared>,
+    grpc_balance: bool,
 }
 
 #[derive(Clone)]
@@ -17,51 +29,267 @@ pub struct Metrics<C> {
 }
 
 struct Shared {
+    connect_attempts: Counter,
+    connects: Counter,
+    connect_failures: Counter,
+    // Connections that failed with an OS error, indexed by errno.
+    connect_errnos: RwLock<BTreeMap<i32, Arc<Counter>>>,
+    // Connections that failed because no ephemeral ports were available, whether they were
+    // established eagerly or (for HTTP) by a request.
+    ports_exhausted: Counter,
+    connect_latencies: Summary<MillisAsSeconds>,
     latencies: Summary<MillisAsSeconds>,
     failures: Counter,
+    // Failures from gRPC requests, indexed by status code.
+    grpc_failures: [Counter; 17],
+    // Responses from each server instance, keyed by the instance ID stamped into responses.
+    endpoints: RwLock<HashMap<String, Arc<Endpoint>>>,
+    // Responses from instances seen after `MAX_ENDPOINTS` instances are tracked.
+    overflow: Arc<Endpoint>,
+}
+
+struct Endpoint {
+    responses: Counter,
+    failures: Counter,
+    latencies: Summary<MillisAsSeconds>,
 }
 
 #[derive(Clone)]
-pub struct Report(Arc<Shared>);
+pub struct Report {
+    shared: Arc<Shared>,
+    tcp_conns: Arc<muxer::Metrics>,
+}
+
+struct GrpcCode(tonic::Code);
+
+struct Instance<'a>(&'a str);
+
+/// Labels the responses of instances that are not tracked individually.
+struct Overflow;
+
+struct Local(SocketAddr);
+
+struct Errno(i32);
+
+/// The most server instances whose responses are tracked individually, so that servers that
+/// churn through instance IDs do not grow the report without bound.
+const MAX_ENDPOINTS: usize = 1_000;
 
 metrics! {
+    connect_attempt_count: Counter { "A count of client connections attempted" },
+    connect_count: Counter { "A count of client connections established" },
+    connect_failure_count: Counter { "A count of client connections that failed to be established" },
+    connect_failure_errno_count: Counter { "A count of client connections that failed to be established by OS error number" },
+    connect_ports_exhausted_count: Counter { "A count of client connections that failed because no ephemeral ports were available" },
+    connect_latency_seconds: Summary<MillisAsSeconds> { "Time taken to establish client connections" },
     response_latency_seconds: Summary<MillisAsSeconds> { "Response latencies" },
-    response_failure_count: Counter { "A count of failed responses" }
+    response_failure_count: Counter { "A count of failed responses" },
+    grpc_response_failure_count: Counter { "A count of failed gRPC responses by status code" },
+    endpoint_response_count: Counter { "A count of successful responses by server instance" },
+    endpoint_response_failure_count: Counter { "A count of failed responses by server instance" },
+    endpoint_response_latency_seconds: Summary<MillisAsSeconds> { "Response latencies by server instance" },
+    tcp_connection_queued: Gauge { "The number of requests waiting to be dispatched on each TCP connection" },
+    tcp_connection_in_flight: Gauge { "The number of requests in flight on each TCP connection" }
+}
+
+// === impl Report ===
+
+impl Report {
+    /// Creates an empty report. It also describes the requests on each TCP connection recorded in
+    /// `tcp_conns`.
+    pub fn new(tcp_conns: Arc<muxer::Metrics>) -> Self {
+        let shared = Arc::new(Shared {
+            connect_attempts: Counter::default(),
+            connects: Counter::default(),
+            connect_failures: Counter::default(),
+            connect_errnos: Default::default(),
+            ports_exhausted: Counter::default(),
+            connect_latencies: summary(),
+            failures: Counter::default(),
+            grpc_failures: Default::default(),
+            latencies: summary(),
+            endpoints: Default::default(),
+            overflow: Arc::new(Endpoint::default()),
+        });
+        Self { shared, tcp_conns }
+    }
+
+    /// Records connections established by clients that connect lazily.
+    pub fn connects(&self) -> Arc<dyn RecordConnect> {
+        self.shared.clone()
+    }
 }
 
 impl FmtMetrics for Report {
     fn fmt_metrics(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        connect_attempt_count.fmt_help(f)?;
+        connect_attempt_count.fmt_metric(f, &self.shared.connect_attempts)?;
+        connect_count.fmt_help(f)?;
+        connect_count.fmt_metric(f, &self.shared.connects)?;
+        connect_failure_count.fmt_help(f)?;
+        connect_failure_count.fmt_metric(f, &self.shared.connect_failures)?;
+        connect_failure_errno_count.fmt_help(f)?;
+        for (errno, failures) in self.shared.connect_errnos.read().iter() {
+            connect_failure_errno_count.fmt_metric_labeled(f, &Errno(*errno), &**failures)?;
+        }
+        connect_ports_exhausted_count.fmt_help(f)?;
+        connect_ports_exhausted_count.fmt_metric(f, &self.shared.ports_exhausted)?;
+        connect_latency_seconds.fmt_help(f)?;
+        connect_latency_seconds.fmt_metric(f, &self.shared.connect_latencies)?;
         response_latency_seconds.fmt_help(f)?;
-        response_latency_seconds.fmt_metric(f, &self.0.latencies)?;
+        response_latency_seconds.fmt_metric(f, &self.shared.latencies)?;
         response_failure_count.fmt_help(f)?;
-        response_failure_count.fmt_metric(f, &self.0.failures)?;
+        response_failure_count.fmt_metric(f, &self.shared.failures)?;
+        grpc_response_failure_count.fmt_help(f)?;
+        // Skip `OK`, which is never a failure.
+        for (code, failures) in self.shared.grpc_failures.iter().enumerate().skip(1) {
+            let code = GrpcCode(tonic::Code::from_i32(code as i32));
+            grpc_response_failure_count.fmt_metric_labeled(f, &code, failures)?;
+        }
+
+        let endpoints = self.shared.endpoints.read();
+        let mut instances = endpoints.keys().collect::<Vec<_>>();
+        instances.sort();
+        let overflow = endpoints.len() >= MAX_ENDPOINTS;
+        endpoint_response_count.fmt_help(f)?;
+        for i in instances.iter() {
+            endpoint_response_count.fmt_metric_labeled(
+                f,
+                &Instance(i),
+                &endpoints[*i].responses,
+            )?;
+        }
+        if overflow {
+            endpoint_response_count.fmt_metric_labeled(
+                f,
+                &Overflow,
+                &self.shared.overflow.responses,
+            )?;
+        }
+        endpoint_response_failure_count.fmt_help(f)?;
+        for i in instances.iter() {
+            endpoint_response_failure_count.fmt_metric_labeled(
+                f,
+                &Instance(i),
+                &endpoints[*i].failures,
+            )?;
+        }
+        if overflow {
+            endpoint_response_failure_count.fmt_metric_labeled(
+                f,
+                &Overflow,
+                &self.shared.overflow.failures,
+            )?;
+        }
+        endpoint_response_latency_seconds.fmt_help(f)?;
+        for i in instances.iter() {
+            endpoint_response_latency_seconds.fmt_metric_labeled(
+                f,
+                &Instance(i),
+                &endpoints[*i].latencies,
+            )?;
+        }
+        if overflow {
+            endpoint_response_latency_seconds.fmt_metric_labeled(
+                f,
+                &Overflow,
+                &self.shared.overflow.latencies,
+            )?;
+        }
+
+        let tcp_conns = self.tcp_conns.conns();
+        tcp_connection_queued.fmt_help(f)?;
+        for c in tcp_conns.iter() {
+            let queued = Gauge::from(c.queued() as u64);
+            tcp_connection_queued.fmt_metric_labeled(f, &Local(c.addr()), &queued)?;
+        }
+        tcp_connection_in_flight.fmt_help(f)?;
+        for c in tcp_conns.iter() {
+            let in_flight = Gauge::from(c.in_flight() as u64);
+            tcp_connection_in_flight.fmt_metric_labeled(f, &Local(c.addr()), &in_flight)?;
+        }
         Ok(())
     }
 }
 
+impl FmtLabels for GrpcCode {
+    fn fmt_labels(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "code=\"{:?}\"", self.0)
+    }
+}
+
+impl FmtLabels for Instance<'_> {
+    /// Instance IDs are chosen by servers, so they are escaped as Prometheus label values.
+    fn fmt_labels(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let instance = self
+            .0
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace('\n', "\\n");
+        write!(f, "instance=\"{}\"", instance)
+    }
+}
+
+impl FmtLabels for Overflow {
+    fn fmt_labels(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "instance_overflow=\"true\"")
+    }
+}
+
+impl FmtLabels for Errno {
+    fn fmt_labels(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "errno=\"{}\"", self.0)
+    }
+}
+
+impl FmtLabels for Local {
+    fn fmt_labels(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "local=\"{}\"", self.0)
+    }
+}
+
 impl<M> MakeMetrics<M> {
-    pub fn new(inner: M) -> (Self, Report) {
-        let shared = Arc::new(Shared {
-            failures: Counter::default(),
-            latencies: Summary::new_resizable(10, time::Duration::from_secs(300), 5)
-                .expect("Summary must be valid"),
-        });
-        let report = Report(shared.clone());
-        (Self { inner, shared }, report)
+    /// Wraps `inner` to record request metrics in `report`. `grpc_balance` indicates that gRPC
+    /// clients balance over lazily connected channels, whose connections cannot be measured.
+    pub fn new(inner: M, report: &Report, grpc_balance: bool) -> Self {
+        Self {
+            inner,
+            shared: report.shared.clone(),
+            grpc_balance,
+        }
     }
 }
 
 #[async_trait::async_trait]
-impl<M, T> MakeOrt<T> for MakeMetrics<M>
+impl<M> MakeOrt<Target> for MakeMetrics<M>
 where
-    T: Send + 'static,
-    M: MakeOrt<T> + Send + 'static,
+    M: MakeOrt<Target> + Send + 'static,
     M::Ort: Send + 'static,
 {
     type Ort = Metrics<M::Ort>;
 
-    async fn make_ort(&mut self, t: T) -> Result<Self::Ort, Error> {
-        let inner = self.inner.make_ort(t).await?;
+    /// Records the time taken to connect. HTTP clients connect lazily, so their connections are
+    /// recorded by the client (see `Report::connects`) instead. Balanced gRPC channels also
+    /// connect lazily, so their connections are not recorded.
+    async fn make_ort(&mut self, target: Target) -> Result<Self::Ort, Error> {
+        let lazy = match target {
+            Flavor::Http(_) => true,
+            Flavor::Grpc(_) => self.grpc_balance,
+            Flavor::Tcp(_) => false,
+        };
+        if !lazy {
+            self.shared.record_attempt();
+        }
+        let t0 = time::Instant::now();
+        let res = self.inner.make_ort(target).await;
+        if !lazy {
+            let result = res
+                .as_ref()
+                .map(|_| ())
+                .map_err(|e| &**e as &(dyn std::error::Error + 'static));
+            self.shared.record_connect(t0.elapsed(), result);
+        }
+        let inner = res?;
         let shared = self.shared.clone();
         Ok(Metrics { inner, shared })
     }
@@ -80,9 +308,186 @@ impl<C: Ort + Send + 'static> Ort for Metrics<C> {
             .record(millis as u64)
             .expect("latency must fit in histogram");
 
-        if res.is_err() {
-            self.shared.failures.incr();
+        match res.as_ref() {
+            Ok(Reply {
+                instance: Some(instance),
+                ..
+            }) => {
+                let endpoint = self.shared.endpoint(instance);
+                endpoint.responses.incr();
+                endpoint
+                    .latencies
+                    .record(millis as u64)
+                    .expect("latency must fit in histogram");
+            }
+            Ok(_) => {}
+            Err(error) => {
+                self.shared.failures.incr();
+                let error = match error.downcast_ref::<InstanceError>() {
+                    Some(e) => {
+                        self.shared.endpoint(e.instance()).failures.incr();
+                        e.inner()
+                    }
+                    None => error,
+                };
+                if let Some(status) = error.downcast_ref::<tonic::Status>() {
+                    self.shared.grpc_failures[status.code() as usize].incr();
+                }
+            }
         }
         res
     }
 }
+
+// === impl Shared ===
+
+impl RecordConnect for Shared {
+    fn record_attempt(&self) {
+        self.connect_attempts.incr();
+    }
+
+    fn record_connect(
+        &self,
+        latency: Duration,
+        result: Result<(), &(dyn std::error::Error + 'static)>,
+    ) {
+        self.connect_latencies
+            .record(latency.as_millis() as u64)
+            .expect("latency must fit in histogram");
+        match result {
+            Ok(()) => self.connects.incr(),
+            Err(error) => {
+                self.connect_failures.incr();
+                if is_ports_exhausted(error) {
+                    self.ports_exhausted.incr();
+                }
+                if let Some(errno) = errno(error) {
+                    self.connect_errno(errno).incr();
+                }
+            }
+        }
+    }
+}
+
+impl Shared {
+    fn connect_errno(&self, errno: i32) -> Arc<Counter> {
+        if let Some(counter) = self.connect_errnos.read().get(&errno) {
+            return counter.clone();
+        }
+        self.connect_errnos
+            .write()
+            .entry(errno)
+            .or_default()
+            .clone()
+    }
+
+    /// Finds the endpoint for `instance`, or the overflow endpoint if `MAX_ENDPOINTS` other
+    /// instances are already tracked.
+    fn endpoint(&self, instance: &str) -> Arc<Endpoint> {
+        if let Some(endpoint) = self.endpoints.read().get(instance) {
+            return endpoint.clone();
+        }
+        let mut endpoints = self.endpoints.write();
+        if !endpoints.contains_key(instance) && endpoints.len() >= MAX_ENDPOINTS {
+            return self.overflow.clone();
+        }
+        endpoints
+            .entry(instance.to_string())
+            .or_insert_with(|| Arc::new(Endpoint::default()))
+            .clone()
+    }
+}
+
+// === impl Endpoint ===
+
+impl Default for Endpoint {
+    fn default() -> Self {
+        Self {
+            responses: Counter::default(),
+            failures: Counter::default(),
+            latencies: summary(),
+        }
+    }
+}
+
+/// Finds the OS error number that caused `error`, if any.
+fn errno(error: &(dyn std::error::Error + 'static)) -> Option<i32> {
+    let mut next = Some(error);
+    while let Some(e) = next {
+        if let Some(errno) = e
+            .downcast_ref::<io::Error>()
+            .and_then(io::Error::raw_os_error)
+        {
+            return Some(errno);
+        }
+        next = e.source();
+    }
+    None
+}
+
+fn summary() -> Summary<MillisAsSeconds> {
+    Summary::new_resizable(10, time::Duration::from_secs(300), 5).expect("Summary must be valid")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Labels<L>(L);
+
+    impl<L: FmtLabels> fmt::Display for Labels<L> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            self.0.fmt_labels(f)
+        }
+    }
+
+    #[test]
+    fn escapes_instances() {
+        assert_eq!(Labels(Instance("a-1")).to_string(), "instance=\"a-1\"");
+        assert_eq!(
+            Labels(Instance("a\\b\"c\nd")).to_string(),
+            "instance=\"a\\\\b\\\"c\\nd\""
+        );
+    }
+
+    #[test]
+    fn caps_endpoints() {
+        let report = Report::new(Arc::new(muxer::Metrics::default()));
+        for i in 0..MAX_ENDPOINTS {
+            report.shared.endpoint(&i.to_string()).responses.incr();
+        }
+        assert!(!report
+            .as_display()
+            .to_string()
+            .contains("instance_overflow"));
+
+        report.shared.endpoint("new").responses.incr();
+        report.shared.endpoint("0").responses.incr();
+        assert_eq!(report.shared.endpoints.read().len(), MAX_ENDPOINTS);
+        let metrics = report.as_display().to_string();
+        assert!(metrics.contains("endpoint_response_count{instance_overflow=\"true\"} 1\n"));
+        assert!(metrics.contains("endpoint_response_count{instance=\"0\"} 2\n"));
+    }
+
+    #[test]
+    fn records_connect_errnos() {
+        let report = Report::new(Arc::new(muxer::Metrics::default()));
+        let connects = report.connects();
+        for _ in 0..2 {
+            connects.record_attempt();
+            let error = io::Error::from_raw_os_error(111);
+            connects.record_connect(Duration::from_millis(1), Err(&error));
+        }
+        connects.record_attempt();
+        connects.record_connect(Duration::from_millis(1), Ok(()));
+
+        let metrics = report.as_display().to_string();
+        assert!(metrics.contains("connect_attempt_count 3\n"), "{}", metrics);
+        assert!(metrics.contains("connect_failure_count 2\n"), "{}", metrics);
+        assert!(
+            metrics.contains("connect_failure_errno_count{errno=\"111\"} 2\n"),
+            "{}",
+            metrics
+        );
+    }
+}
@@ -1,11 +1,12 @@
 use crate::{latency, Distribution, Error, Target};
 use futures::{prelude::*, stream::FuturesUnordered};
-use ort_core::{limit::Acquire, MakeOrt, Ort, Spec};
-use rand::{distributions::Distribution as _, thread_rng};
+use ort_core::{is_ports_exhausted, limit::Acquire, MakeOrt, Ort, Spec, StreamSpec};
+use rand::{distributions::Distribution as _, thread_rng, Rng};
 use std::sync::{
     atomic::{AtomicUsize, Ordering},
     Arc,
 };
+use tokio::time;
 use tracing::{debug, debug_span, info, trace, Instrument};
 
 #[derive(Clone)]
@@ -13,8 +14,27 @@ pub struct Runner<L> {
     clients: usize,
     limit: L,
     counter: Arc<Counter>,
-    response_latencies: Arc<latency::Distribution>,
-    response_sizes: Arc<Distribution>,
+    specs: Arc<Specs>,
+    churn: Churn,
+}
+
+/// Determines when each client closes its connection and opens a new one. By default, a client's
+/// connection is used for all of its requests.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Churn {
+    /// The number of requests issued on a connection before it is replaced.
+    pub max_requests: Option<usize>,
+    /// The time after which a connection is replaced.
+    pub max_age: Option<time::Duration>,
+}
+
+/// Samples request specs from distributions.
+#[derive(Clone, Debug)]
+pub struct Specs {
+    pub response_latencies: latency::Distribution,
+    pub response_sizes: Distribution,
+    pub stream_messages: Distribution,
+    pub stream_intervals: latency::Distribution,
 }
 
 #[derive(Debug)]
@@ -23,6 +43,9 @@ struct Counter {
     count: AtomicUsize,
 }
 
+const MIN_RECONNECT_BACKOFF: time::Duration = time::Duration::from_millis(100);
+const MAX_RECONNECT_BACKOFF: time::Duration = time::Duration::from_secs(5);
+
 // === impl Runner ===
 
 impl<L: Acquire> Runner<L> {
@@ -30,15 +53,15 @@ impl<L: Acquire> Runner<L> {
         clients: usize,
         total_requests: Option<usize>,
         limit: L,
-        response_latencies: Arc<latency::Distribution>,
-        response_sizes: Arc<Distribution>,
+        specs: Specs,
+        churn: Churn,
     ) -> Self {
         Self {
             clients,
             counter: Arc::new(Counter::from(total_requests)),
             limit,
-            response_latencies,
-            response_sizes,
+            specs: Arc::new(specs),
+            churn,
         }
     }
 
@@ -50,8 +73,8 @@ impl<L: Acquire> Runner<L> {
             clients,
             limit,
             counter,
-            response_latencies,
-            response_sizes,
+            specs,
+            churn,
         } = self;
 
         let mut tasks = (0..clients)
@@ -59,24 +82,27 @@ impl<L: Acquire> Runner<L> {
                 debug!(c, %target, "Spawning client task");
                 let limit = limit.clone();
                 let counter = counter.clone();
-                let response_latencies = response_latencies.clone();
-                let response_sizes = response_sizes.clone();
+                let specs = specs.clone();
                 let mut connect = connect.clone();
                 let target = target.clone();
                 tokio::spawn(
                     async move {
-                        let client = connect.make_ort(target).await?;
+                        let mut client = connect.make_ort(target.clone()).await?;
+                        let mut opened = time::Instant::now();
+                        let mut issued = 0;
 
                         while let Some(n) = counter.next() {
+                            if churn.expired(issued, opened) {
+                                debug!(issued, age = ?opened.elapsed(), "Replacing connection");
+                                // Requests in flight hold the old client until they complete.
+                                client = reconnect(&mut connect, &target).await;
+                                opened = time::Instant::now();
+                                issued = 0;
+                            }
                             let permit = limit.acquire().await;
+                            issued += 1;
 
-                            let spec = {
-                                let mut rng = thread_rng();
-                                Spec {
-                                    latency: response_latencies.sample(&mut rng),
-                                    response_size: response_sizes.sample(&mut rng) as usize,
-                                }
-                            };
+                            let spec = specs.sample(&mut thread_rng());
 
                             let mut client = client.clone();
                             tokio::spawn(
@@ -84,6 +110,9 @@ impl<L: Acquire> Runner<L> {
                                     trace!(?spec, "Sending request");
                                     match client.ort(spec).await {
                                         Ok(_) => trace!("Request complete"),
+                                        Err(error) if is_ports_exhausted(&*error) => {
+                                            info!(%error, "Request failed: ephemeral ports exhausted")
+                                        }
                                         Err(error) => info!(%error, "Request failed"),
                                     }
                                     drop(permit);
@@ -108,6 +137,47 @@ impl<L: Acquire> Runner<L> {
     }
 }
 
+/// Connects to the target, retrying with exponential backoff until a connection is established so
+/// that the request waiting on the connection is not lost.
+async fn reconnect<C: MakeOrt<Target>>(connect: &mut C, target: &Target) -> C::Ort {
+    let mut backoff = MIN_RECONNECT_BACKOFF;
+    loop {
+        match connect.make_ort(target.clone()).await {
+            Ok(client) => return client,
+            Err(error) if is_ports_exhausted(&*error) => {
+                info!(%error, ?backoff, "Failed to connect: ephemeral ports exhausted")
+            }
+            Err(error) => info!(%error, ?backoff, "Failed to connect"),
+        }
+        time::sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_RECONNECT_BACKOFF);
+    }
+}
+
+// === impl Specs ===
+
+impl rand::distributions::Distribution<Spec> for Specs {
+    fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> Spec {
+        Spec {
+            latency: self.response_latencies.sample(rng),
+            response_size: self.response_sizes.sample(rng) as usize,
+            stream: StreamSpec {
+                messages: self.stream_messages.sample(rng) as usize,
+                interval: self.stream_intervals.sample(rng),
+            },
+        }
+    }
+}
+
+// === impl Churn ===
+
+impl Churn {
+    fn expired(&self, issued: usize, opened: time::Instant) -> bool {
+        self.max_requests.map_or(false, |max| issued >= max)
+            || self.max_age.map_or(false, |max| opened.elapsed() >= max)
+    }
+}
+
 // === impl Counter ===
 
 impl Default for Counter {
@@ -139,3 +209,101 @@ impl Counter {
             .ok()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ort_core::Reply;
+
+    /// Counts connections and requests, failing the connection attempts listed in `failures`.
+    #[derive(Clone, Default)]
+    struct MakeCount {
+        attempts: Arc<AtomicUsize>,
+        connects: Arc<AtomicUsize>,
+        requests: Arc<AtomicUsize>,
+        failures: Arc<[usize]>,
+    }
+
+    #[derive(Clone)]
+    struct Count(Arc<AtomicUsize>);
+
+    #[async_trait::async_trait]
+    impl MakeOrt<Target> for MakeCount {
+        type Ort = Count;
+
+        async fn make_ort(&mut self, _: Target) -> Result<Count, Error> {
+            let attempt = self.attempts.fetch_add(1, Ordering::SeqCst);
+            if self.failures.contains(&attempt) {
+                return Err("connection refused".into());
+            }
+            self.connects.fetch_add(1, Ordering::SeqCst);
+            Ok(Count(self.requests.clone()))
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl Ort for Count {
+        async fn ort(&mut self, _: Spec) -> Result<Reply, Error> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Ok(Reply::default())
+        }
+    }
+
+    fn specs() -> Specs {
+        Specs {
+            response_latencies: Default::default(),
+            response_sizes: Default::default(),
+            stream_messages: Default::default(),
+            stream_intervals: Default::default(),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn churn_expired() {
+        let opened = time::Instant::now();
+        assert!(!Churn::default().expired(1000, opened));
+
+        let churn = Churn {
+            max_requests: Some(2),
+            max_age: None,
+        };
+        assert!(!churn.expired(1, opened));
+        assert!(churn.expired(2, opened));
+
+        let churn = Churn {
+            max_requests: None,
+            max_age: Some(time::Duration::from_secs(10)),
+        };
+        assert!(!churn.expired(100, opened));
+        time::advance(time::Duration::from_secs(10)).await;
+        assert!(churn.expired(0, opened));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn churn_reconnects_without_losing_requests() {
+        let make = MakeCount {
+            // The first reconnect fails twice before it succeeds.
+            failures: vec![1, 2].into(),
+            ..MakeCount::default()
+        };
+        let churn = Churn {
+            max_requests: Some(2),
+            max_age: None,
+        };
+        let runner = Runner::new(
+            1,
+            Some(6),
+            None::<Arc<tokio::sync::Semaphore>>,
+            specs(),
+            churn,
+        );
+        let target = Target::Http("http://localhost:8080".parse().unwrap());
+        runner.run(make.clone(), target).await.expect("must run");
+        // Let the final requests complete.
+        time::sleep(time::Duration::from_secs(1)).await;
+
+        assert_eq!(make.attempts.load(Ordering::SeqCst), 5);
+        assert_eq!(make.connects.load(Ordering::SeqCst), 3);
+        assert_eq!(make.requests.load(Ordering::SeqCst), 6);
+    }
+}
@@ -0,0 +1,103 @@
+use anyhow::{anyhow, bail, Result};
+use clap::Parser;
+use futures::future;
+use ort_core::parse_duration;
+use ort_tcp::throughput::{Direction, Report, Settings};
+use std::str::FromStr;
+use tokio::time::Duration;
+use tracing::{debug, info};
+
+/// Streams raw bytes to and from a server's throughput port, without any framing, to measure the
+/// throughput of an opaque TCP proxy.
+#[derive(Parser)]
+#[clap(name = "throughput", about = "Raw TCP throughput test")]
+pub struct ThroughputCmd {
+    /// The directions in which bytes flow: `upload`, `download`, or `both`.
+    #[clap(long, default_value = "download")]
+    direction: Direction,
+
+    #[clap(long, default_value = "10s", parse(try_from_str = parse_duration))]
+    duration: Duration,
+
+    /// Limits the number of bytes written by each side of each connection every second.
+    /// Unlimited by default.
+    #[clap(long)]
+    bytes_per_sec: Option<u64>,
+
+    /// The size of each write.
+    #[clap(long, default_value = "16384")]
+    chunk_size: usize,
+
+    /// The number of connections to stream on concurrently.
+    #[clap(long, default_value = "1")]
+    connections: usize,
+
+    #[clap(long, parse(try_from_str = parse_duration), default_value = "1s")]
+    connect_timeout: Duration,
+
+    /// The server's throughput port, e.g. `tcp://server:8091`.
+    target: Target,
+}
+
+#[derive(Clone, Debug)]
+struct Target(String);
+
+// === impl ThroughputCmd ===
+
+impl ThroughputCmd {
+    pub async fn run(self) -> Result<()> {
+        if self.connections == 0 || self.chunk_size == 0 {
+            bail!("--connections and --chunk-size must be positive");
+        }
+        if self.bytes_per_sec == Some(0) {
+            bail!("--bytes-per-sec must be positive");
+        }
+
+        let settings = Settings {
+            direction: self.direction,
+            bytes_per_sec: self.bytes_per_sec,
+            duration: self.duration,
+            chunk_size: self.chunk_size,
+        };
+        let Target(addr) = self.target;
+        let connect_timeout = self.connect_timeout;
+        // Each connection is driven by its own task so that connections may use all cores.
+        let conns = (0..self.connections).map(|i| {
+            let addr = addr.clone();
+            tokio::spawn(async move {
+                let report = settings.connect(&addr, connect_timeout).await?;
+                debug!(conn = i, %report, "Connection complete");
+                Ok::<_, anyhow::Error>(report)
+            })
+        });
+        let mut report = Report::default();
+        for res in future::join_all(conns).await {
+            report = report.merge(res??);
+        }
+        info!(%addr, %report, "Throughput test complete");
+        Ok(())
+    }
+}
+
+// === impl Target ===
+
+impl FromStr for Target {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let uri = http::Uri::from_str(s)?;
+        match uri.scheme_str() {
+            Some("tcp") => {}
+            Some(s) => bail!("invalid scheme: {}", s),
+            None => bail!("missing scheme"),
+        }
+        let a = uri
+            .authority()
+            .ok_or_else(|| anyhow!("missing authority"))?;
+        Ok(Target(format!(
+            "{}:{}",
+            a.host(),
+            a.port_u16().unwrap_or(8091)
+        )))
+    }
+}
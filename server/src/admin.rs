@@ -0,0 +1,205 @@
+use crate::{
+    behavior::{Behavior, Overrides},
+    metrics::Report,
+};
+use linkerd_metrics::Serve;
+use parking_lot::RwLock;
+use std::{
+    io,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+use tracing::{debug, info};
+
+/// Serves health checks and metrics, and allows each listener's behavior to be changed at
+/// runtime.
+#[derive(Clone)]
+pub(crate) struct Admin {
+    metrics: Serve<Report>,
+    ready: Arc<AtomicBool>,
+    behaviors: Arc<[(&'static str, Arc<RwLock<Behavior>>)]>,
+}
+
+impl Admin {
+    pub fn new(report: Report, behaviors: Vec<(&'static str, Arc<RwLock<Behavior>>)>) -> Self {
+        Self {
+            metrics: Serve::new(report),
+            ready: Arc::new(AtomicBool::new(true)),
+            behaviors: behaviors.into(),
+        }
+    }
+
+    pub async fn serve(self, addr: SocketAddr) -> Result<(), hyper::Error> {
+        hyper::Server::bind(&addr)
+            .serve(hyper::service::make_service_fn(move |_| {
+                let admin = self.clone();
+                async move {
+                    Ok::<_, io::Error>(hyper::service::service_fn(
+                        move |req: hyper::Request<hyper::Body>| {
+                            let admin = admin.clone();
+                            async move { admin.handle(req).await }
+                        },
+                    ))
+                }
+            }))
+            .await
+    }
+
+    async fn handle(
+        &self,
+        req: hyper::Request<hyper::Body>,
+    ) -> io::Result<hyper::Response<hyper::Body>> {
+        debug!(?req);
+        let method = req.method().clone();
+        let path = req.uri().path().to_string();
+        match (&method, path.as_str()) {
+            (&hyper::Method::GET, "/live") => return Ok(rsp(hyper::StatusCode::OK, "ok\n")),
+
+            (&hyper::Method::GET, "/ready") => {
+                if self.ready.load(Ordering::Acquire) {
+                    return Ok(rsp(hyper::StatusCode::OK, "ok\n"));
+                }
+                return Ok(rsp(hyper::StatusCode::SERVICE_UNAVAILABLE, "not ready\n"));
+            }
+
+            (&hyper::Method::PUT, "/ready") => {
+                let body = read_body(req).await?;
+                let ready = match body.trim() {
+                    "true" => true,
+                    "false" => false,
+                    _ => {
+                        return Ok(rsp(
+                            hyper::StatusCode::BAD_REQUEST,
+                            "expected true or false\n",
+                        ))
+                    }
+                };
+                info!(ready, "Updating readiness");
+                self.ready.store(ready, Ordering::Release);
+                return Ok(rsp(hyper::StatusCode::NO_CONTENT, ""));
+            }
+
+            (&hyper::Method::GET, "/metrics") => return self.metrics.serve(req),
+
+            (&hyper::Method::GET, "/behavior") => {
+                let mut out = String::new();
+                for (protocol, behavior) in self.behaviors.iter() {
+                    out.push_str(&format!("{}: {:?}\n", protocol, *behavior.read()));
+                }
+                return Ok(rsp(hyper::StatusCode::OK, out));
+            }
+
+            // Updates listeners' behaviors with overrides formatted like the `--*-behavior`
+            // flags, e.g. `latency=1s;failure-rate=0.1`. The `protocol` query parameter limits
+            // the update to a single listener. Stream intervals and pacing are fixed when the
+            // server starts, so overriding them is rejected.
+            (&hyper::Method::PUT, "/behavior") => {
+                let protocol = req.uri().query().and_then(|q| {
+                    q.split('&')
+                        .find_map(|kv| kv.strip_prefix("protocol="))
+                        .map(str::to_string)
+                });
+                let body = read_body(req).await?;
+                let overrides = match body.trim().parse::<Overrides>() {
+                    Ok(o) => o,
+                    Err(e) => return Ok(rsp(hyper::StatusCode::BAD_REQUEST, format!("{}\n", e))),
+                };
+                let fixed = overrides.fixed();
+                if !fixed.is_empty() {
+                    return Ok(rsp(
+                        hyper::StatusCode::BAD_REQUEST,
+                        format!("cannot be changed at runtime: {}\n", fixed.join(", ")),
+                    ));
+                }
+
+                let targets = self
+                    .behaviors
+                    .iter()
+                    .filter(|(p, _)| protocol.as_deref().map(|q| q == *p).unwrap_or(true))
+                    .collect::<Vec<_>>();
+                if targets.is_empty() {
+                    return Ok(rsp(hyper::StatusCode::NOT_FOUND, "unknown protocol\n"));
+                }
+
+                let updated = targets
+                    .iter()
+                    .map(|(p, b)| (*p, b.read().with_overrides(&overrides)))
+                    .collect::<Vec<_>>();
+                for (protocol, update) in &updated {
+                    if let Err(e) = update.validate(protocol) {
+                        return Ok(rsp(hyper::StatusCode::BAD_REQUEST, format!("{}\n", e)));
+                    }
+                }
+                for ((_, behavior), (protocol, update)) in targets.into_iter().zip(updated) {
+                    info!(protocol, ?update, "Updating behavior");
+                    *behavior.write() = update;
+                }
+                return Ok(rsp(hyper::StatusCode::NO_CONTENT, ""));
+            }
+
+            _ => {}
+        }
+
+        Ok(rsp(hyper::StatusCode::NOT_FOUND, ""))
+    }
+}
+
+fn rsp(status: hyper::StatusCode, body: impl Into<hyper::Body>) -> hyper::Response<hyper::Body> {
+    hyper::Response::builder()
+        .status(status)
+        .body(body.into())
+        .unwrap()
+}
+
+async fn read_body(req: hyper::Request<hyper::Body>) -> io::Result<String> {
+    let body = hyper::body::to_bytes(req.into_body())
+        .await
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    String::from_utf8(body.to_vec()).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ort_tcp::muxer;
+
+    async fn put(admin: &Admin, uri: &str, body: &'static str) -> hyper::StatusCode {
+        let req = hyper::Request::put(uri).body(body.into()).unwrap();
+        admin.handle(req).await.expect("must respond").status()
+    }
+
+    #[tokio::test]
+    async fn validates_behavior_updates() {
+        let http = Arc::new(RwLock::new(Behavior::default()));
+        let admin = Admin::new(
+            Report::new(vec![], Arc::new(muxer::Metrics::default())),
+            vec![("http", http.clone())],
+        );
+
+        for body in [
+            "chunk-size=10",
+            "chunk-interval=1s",
+            "bytes-per-sec=100",
+            "stream-interval=1s",
+            "failure-status=1000",
+            "reset-rate=0.6;garbage-rate=0.6",
+        ] {
+            assert_eq!(
+                put(&admin, "/behavior", body).await,
+                hyper::StatusCode::BAD_REQUEST,
+                "{}",
+                body
+            );
+        }
+        assert_eq!(http.read().failure_status, None);
+
+        assert_eq!(
+            put(&admin, "/behavior?protocol=http", "failure-status=503").await,
+            hyper::StatusCode::NO_CONTENT
+        );
+        assert_eq!(http.read().failure_status, Some(503));
+    }
+}
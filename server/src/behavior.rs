@@ -0,0 +1,316 @@
+use ort_core::{latency, parse_duration, Distribution, Fault, Pacing};
+use rand::Rng;
+use std::{str::FromStr, time::Duration};
+
+/// Describes how a listener replies to requests, independently of what clients request.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Behavior {
+    /// The minimum latency of each reply.
+    pub latencies: latency::Distribution,
+
+    /// The minimum size of each reply.
+    pub sizes: Distribution,
+
+    /// The fraction of requests that fail.
+    pub failure_rate: f64,
+
+    /// The protocol-specific status with which requests fail.
+    pub failure_status: Option<u16>,
+
+    /// The minimum interval between streamed replies.
+    pub stream_intervals: latency::Distribution,
+
+    /// The rate at which each fault is injected.
+    pub faults: FaultRates,
+
+    /// The maximum size of each chunk of a paced reply.
+    pub chunk_size: usize,
+
+    /// When set, replies are paced with this interval between chunks.
+    pub chunk_interval: Option<Duration>,
+}
+
+/// The rates at which faults are injected. Rates must sum to at most 1.
+#[derive(Copy, Clone, Debug, Default)]
+pub(crate) struct FaultRates {
+    pub reset: f64,
+    pub hang: f64,
+    pub half_close: f64,
+    pub truncate: f64,
+    pub garbage: f64,
+}
+
+/// Per-listener overrides for a `Behavior`, formatted as `;`-separated `key=value` pairs, e.g.
+/// `latency=50=10ms,100=1s;failure-rate=0.01;failure-status=503`.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Overrides {
+    latencies: Option<latency::Distribution>,
+    sizes: Option<Distribution>,
+    failure_rate: Option<f64>,
+    failure_status: Option<u16>,
+    stream_intervals: Option<latency::Distribution>,
+    faults: Vec<(Fault, f64)>,
+    chunk_size: Option<usize>,
+    chunk_interval: Option<Duration>,
+    bytes_per_sec: Option<u64>,
+}
+
+#[derive(Debug)]
+pub(crate) struct InvalidOverrides(String);
+
+// === impl Behavior ===
+
+impl Behavior {
+    pub fn with_overrides(&self, overrides: &Overrides) -> Self {
+        let Overrides {
+            latencies,
+            sizes,
+            failure_rate,
+            failure_status,
+            stream_intervals,
+            faults,
+            chunk_size,
+            chunk_interval,
+            bytes_per_sec,
+        } = overrides.clone();
+        let mut rates = self.faults;
+        for (fault, rate) in faults {
+            *rates.get_mut(fault) = rate;
+        }
+        let chunk_size = chunk_size.unwrap_or(self.chunk_size);
+        let chunk_interval = match bytes_per_sec {
+            Some(rate) => Some(Pacing::from_rate(chunk_size, rate).interval),
+            None => chunk_interval.or(self.chunk_interval),
+        };
+        Self {
+            latencies: latencies.unwrap_or_else(|| self.latencies.clone()),
+            sizes: sizes.unwrap_or_else(|| self.sizes.clone()),
+            failure_rate: failure_rate.unwrap_or(self.failure_rate),
+            failure_status: failure_status.or(self.failure_status),
+            stream_intervals: stream_intervals.unwrap_or_else(|| self.stream_intervals.clone()),
+            faults: rates,
+            chunk_size,
+            chunk_interval,
+        }
+    }
+
+    /// Fails if the behavior cannot be honored by the listener serving `protocol`, since each
+    /// protocol expresses failures with its own statuses.
+    pub fn validate(&self, protocol: &str) -> Result<(), String> {
+        if !self.faults.is_valid() {
+            return Err(format!(
+                "{} fault rates must each be between 0 and 1 and sum to at most 1",
+                protocol
+            ));
+        }
+        let valid_status: fn(u16) -> bool = match protocol {
+            "grpc" => |s: u16| (1..=16).contains(&s),
+            "http" => |s: u16| hyper::StatusCode::from_u16(s).is_ok(),
+            _ => |_: u16| true,
+        };
+        if let Some(status) = self.failure_status.filter(|s| !valid_status(*s)) {
+            return Err(format!("invalid {} failure status: {}", protocol, status));
+        }
+        Ok(())
+    }
+
+    pub fn pacing(&self) -> Option<Pacing> {
+        self.chunk_interval.map(|interval| Pacing {
+            chunk_size: self.chunk_size,
+            interval,
+        })
+    }
+}
+
+// === impl FaultRates ===
+
+impl FaultRates {
+    fn get_mut(&mut self, fault: Fault) -> &mut f64 {
+        match fault {
+            Fault::Reset => &mut self.reset,
+            Fault::Hang => &mut self.hang,
+            Fault::HalfClose => &mut self.half_close,
+            Fault::Truncate => &mut self.truncate,
+            Fault::Garbage => &mut self.garbage,
+        }
+    }
+
+    pub fn is_valid(&self) -> bool {
+        let rates = [
+            self.reset,
+            self.hang,
+            self.half_close,
+            self.truncate,
+            self.garbage,
+        ];
+        rates.iter().all(|r| (0.0..=1.0).contains(r)) && rates.iter().sum::<f64>() <= 1.0
+    }
+
+    /// Determines which fault, if any, should be injected for a request.
+    pub fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> Option<Fault> {
+        let mut p = rng.gen::<f64>();
+        for (rate, fault) in [
+            (self.reset, Fault::Reset),
+            (self.hang, Fault::Hang),
+            (self.half_close, Fault::HalfClose),
+            (self.truncate, Fault::Truncate),
+            (self.garbage, Fault::Garbage),
+        ] {
+            if p < rate {
+                return Some(fault);
+            }
+            p -= rate;
+        }
+        None
+    }
+}
+
+// === impl Overrides ===
+
+impl Overrides {
+    /// Lists the overridden settings that are fixed when a listener starts and so cannot be
+    /// updated at runtime.
+    pub fn fixed(&self) -> Vec<&'static str> {
+        let mut fixed = Vec::new();
+        if self.stream_intervals.is_some() {
+            fixed.push("stream-interval");
+        }
+        if self.chunk_size.is_some() {
+            fixed.push("chunk-size");
+        }
+        if self.chunk_interval.is_some() {
+            fixed.push("chunk-interval");
+        }
+        if self.bytes_per_sec.is_some() {
+            fixed.push("bytes-per-sec");
+        }
+        fixed
+    }
+}
+
+impl FromStr for Overrides {
+    type Err = InvalidOverrides;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut overrides = Self::default();
+        for kv in s.split(';').filter(|kv| !kv.is_empty()) {
+            let invalid = || InvalidOverrides(kv.to_string());
+            let mut kv = kv.splitn(2, '=');
+            let (k, v) = match (kv.next(), kv.next()) {
+                (Some(k), Some(v)) => (k.trim(), v.trim()),
+                _ => return Err(invalid()),
+            };
+            match k {
+                "latency" => overrides.latencies = Some(v.parse().map_err(|_| invalid())?),
+                "size" => overrides.sizes = Some(v.parse().map_err(|_| invalid())?),
+                "failure-rate" => {
+                    let rate = v.parse::<f64>().map_err(|_| invalid())?;
+                    if !(0.0..=1.0).contains(&rate) {
+                        return Err(invalid());
+                    }
+                    overrides.failure_rate = Some(rate);
+                }
+                "failure-status" => {
+                    overrides.failure_status = Some(v.parse().map_err(|_| invalid())?)
+                }
+                "stream-interval" => {
+                    overrides.stream_intervals = Some(v.parse().map_err(|_| invalid())?)
+                }
+                "chunk-size" => {
+                    let sz = v.parse::<usize>().map_err(|_| invalid())?;
+                    if sz == 0 {
+                        return Err(invalid());
+                    }
+                    overrides.chunk_size = Some(sz);
+                }
+                "chunk-interval" => {
+                    overrides.chunk_interval = Some(parse_duration(v).map_err(|_| invalid())?)
+                }
+                "bytes-per-sec" => {
+                    overrides.bytes_per_sec = Some(v.parse().map_err(|_| invalid())?)
+                }
+                _ => {
+                    let fault = match k {
+                        "reset-rate" => Fault::Reset,
+                        "hang-rate" => Fault::Hang,
+                        "half-close-rate" => Fault::HalfClose,
+                        "truncate-rate" => Fault::Truncate,
+                        "garbage-rate" => Fault::Garbage,
+                        _ => return Err(invalid()),
+                    };
+                    let rate = v.parse::<f64>().map_err(|_| invalid())?;
+                    if !(0.0..=1.0).contains(&rate) {
+                        return Err(invalid());
+                    }
+                    overrides.faults.push((fault, rate));
+                }
+            }
+        }
+        Ok(overrides)
+    }
+}
+
+impl std::fmt::Display for InvalidOverrides {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "invalid behavior override '{}'; expected one of: latency, size, failure-rate, failure-status, stream-interval, reset-rate, hang-rate, half-close-rate, truncate-rate, garbage-rate, chunk-size, chunk-interval, bytes-per-sec",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for InvalidOverrides {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{distributions::Distribution as _, thread_rng};
+
+    #[test]
+    fn parse_overrides() {
+        let base = Behavior {
+            failure_rate: 0.5,
+            failure_status: Some(500),
+            ..Behavior::default()
+        };
+
+        let overrides = "latency=1s;failure-status=503;hang-rate=1"
+            .parse::<Overrides>()
+            .expect("must parse");
+        let behavior = base.with_overrides(&overrides);
+        assert_eq!(behavior.failure_rate, 0.5);
+        assert_eq!(behavior.failure_status, Some(503));
+        assert!(behavior.faults.is_valid());
+        assert_eq!(behavior.faults.sample(&mut thread_rng()), Some(Fault::Hang));
+        assert_eq!(
+            behavior.latencies.sample(&mut thread_rng()),
+            Duration::from_secs(1)
+        );
+        assert_eq!(
+            behavior.stream_intervals.sample(&mut thread_rng()),
+            Duration::from_secs(0)
+        );
+
+        assert!("".parse::<Overrides>().is_ok());
+        assert!("size=50=10,100=100".parse::<Overrides>().is_ok());
+        assert!("failure-rate=2".parse::<Overrides>().is_err());
+        assert!("latency".parse::<Overrides>().is_err());
+        assert!("bogus=1".parse::<Overrides>().is_err());
+        assert!("chunk-size=0".parse::<Overrides>().is_err());
+
+        let overrides = "chunk-size=100;bytes-per-sec=400".parse().unwrap();
+        assert_eq!(
+            base.with_overrides(&overrides).pacing(),
+            Some(Pacing {
+                chunk_size: 100,
+                interval: Duration::from_millis(250),
+            })
+        );
+        assert_eq!(base.pacing(), None);
+        assert!("reset-rate=1.5".parse::<Overrides>().is_err());
+
+        let overrides = "reset-rate=0.6;garbage-rate=0.6".parse().unwrap();
+        assert!(!base.with_overrides(&overrides).faults.is_valid());
+    }
+}
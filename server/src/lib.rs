@@ -1,19 +1,36 @@
 #![deny(warnings, rust_2018_idioms)]
 
+mod admin;
+mod behavior;
+mod metrics;
+mod relay;
 mod replier;
+mod schedule;
 
-use self::replier::Replier;
+use self::{
+    admin::Admin,
+    behavior::{Behavior, FaultRates, Overrides},
+    metrics::{Listener, Report},
+    relay::{Relay, Upstream, Upstreams},
+    replier::Replier,
+    schedule::{Degradation, Schedule},
+};
 use clap::Parser;
-use ort_core::latency;
+use ort_core::{
+    latency,
+    limit::{Bounded, Shed},
+    parse_duration, Distribution, Pacing,
+};
 use ort_grpc::server as grpc;
-use ort_http::server as http;
-use ort_tcp::server as tcp;
-use std::net::SocketAddr;
+use ort_http::{server as http, Encoding};
+use ort_tcp::{echo, muxer as tcp_muxer, server as tcp, throughput};
+use parking_lot::RwLock;
+use std::{net::SocketAddr, sync::Arc, time::Duration};
 use tokio::signal::{
     ctrl_c,
     unix::{signal, SignalKind},
 };
-use tracing::{debug, info_span, instrument, Instrument};
+use tracing::{debug_span, info_span, Instrument};
 
 #[derive(Parser)]
 #[clap(name = "server", about = "Load target")]
@@ -30,32 +47,281 @@ pub struct Cmd {
     #[clap(short, long, default_value = "0.0.0.0:8090")]
     tcp_addr: SocketAddr,
 
+    /// Serves raw byte streams for throughput tests (see `ort throughput`).
+    #[clap(long, default_value = "0.0.0.0:8091")]
+    throughput_addr: SocketAddr,
+
+    /// Serves an opaque echo protocol that writes back whatever it reads.
+    #[clap(long, default_value = "0.0.0.0:8092")]
+    echo_addr: SocketAddr,
+
+    /// Delays each echoed read.
+    #[clap(long, parse(try_from_str = parse_duration))]
+    echo_latency: Option<Duration>,
+
+    /// A line that the TCP listener writes to each client as soon as it connects, before the
+    /// client speaks. Clients must be configured to expect it.
+    #[clap(long)]
+    tcp_banner: Option<String>,
+
+    /// The number of requests that may be in flight on each TCP connection. Further requests are
+    /// not read until others complete.
+    #[clap(long, default_value = "10000")]
+    tcp_max_in_flight: usize,
+
+    /// The number of requests on each TCP connection that may wait to be served.
+    #[clap(long, default_value = "100000")]
+    tcp_buffer_capacity: usize,
+
+    /// Identifies this server in every reply so that clients can tell which replica answered.
+    #[clap(long, env = "POD_NAME")]
+    instance_id: Option<String>,
+
     #[clap(long, default_value = "0")]
     response_latency: latency::Distribution,
+
+    #[clap(long, default_value = "0")]
+    response_size: Distribution,
+
+    #[clap(long, default_value = "0")]
+    response_failure_rate: f64,
+
+    /// The status of failed responses. It must be valid for every listener's protocol, so it is
+    /// usually overridden per listener, e.g. with `--grpc-behavior=failure-status=14`.
+    #[clap(long)]
+    response_failure_status: Option<u16>,
+
+    #[clap(long, default_value = "0")]
+    response_stream_interval: latency::Distribution,
+
+    /// The maximum size of each chunk of a paced reply.
+    #[clap(long, default_value = "1024")]
+    response_chunk_size: usize,
+
+    /// Paces replies with this interval between chunks. The first chunk is written as soon as
+    /// the reply is ready.
+    #[clap(long, parse(try_from_str = parse_duration))]
+    response_chunk_interval: Option<Duration>,
+
+    /// Paces replies so that at most this many bytes are written each second. Takes precedence
+    /// over `--response-chunk-interval`.
+    #[clap(long)]
+    response_bytes_per_sec: Option<u64>,
+
+    /// The rate at which connections are reset partway through writing a reply.
+    #[clap(long, default_value = "0")]
+    fault_reset_rate: f64,
+
+    /// The rate at which requests are accepted but never replied to. Other requests on the same
+    /// connection are unaffected.
+    #[clap(long, default_value = "0")]
+    fault_hang_rate: f64,
+
+    /// The rate at which the write side of a connection is closed instead of replying.
+    #[clap(long, default_value = "0")]
+    fault_half_close_rate: f64,
+
+    /// The rate at which replies are truncated before the connection is closed.
+    #[clap(long, default_value = "0")]
+    fault_truncate_rate: f64,
+
+    /// The rate at which random bytes are written instead of a reply.
+    #[clap(long, default_value = "0")]
+    fault_garbage_rate: f64,
+
+    /// Limits the number of requests each listener serves concurrently.
+    #[clap(long)]
+    concurrency_limit: Option<usize>,
+
+    /// The number of requests that may wait for the concurrency limit on each listener. Requests
+    /// beyond this are shed immediately: with a 503 on HTTP, RESOURCE_EXHAUSTED on gRPC, and an
+    /// error frame on TCP.
+    #[clap(long, default_value = "0")]
+    concurrency_queue: usize,
+
+    /// The fraction of each reply that is filled with random data. The remainder is zeroed.
+    #[clap(long, default_value = "1")]
+    response_entropy: f64,
+
+    /// Encodings that may be used to compress replies, in order of preference. gRPC replies
+    /// only support gzip.
+    #[clap(long, use_delimiter = true)]
+    compression: Vec<Encoding>,
+
+    /// Degrades all listeners over time, e.g. `every=10m;for=30s;latency=500ms`. May be specified
+    /// multiple times.
+    #[clap(long)]
+    degrade: Vec<Degradation>,
+
+    /// Relays each request to an upstream server before replying, e.g. `grpc://backend:8070`.
+    /// May be specified multiple times. When upstreams are chosen by weight, each target may be
+    /// prefixed by its weight, e.g. `3=http://backend:8080`.
+    ///
+    /// Upstreams are connected when the server starts. Replies describe the latency of each hop.
+    #[clap(long)]
+    relay: Vec<Upstream>,
+
+    /// Determines how requests are relayed to upstreams: `sequential`, `parallel`, or `weighted`.
+    #[clap(long, default_value = "sequential")]
+    relay_mode: relay::Mode,
+
+    #[clap(long, default_value = "1s", parse(try_from_str = parse_duration))]
+    relay_connect_timeout: Duration,
+
+    /// Overrides the response behavior of the gRPC listener, e.g.
+    /// `latency=50=10ms,100=1s;size=1000;failure-rate=0.01;failure-status=14`.
+    #[clap(long, default_value = "")]
+    grpc_behavior: Overrides,
+
+    /// Overrides the response behavior of the HTTP listener.
+    #[clap(long, default_value = "")]
+    http_behavior: Overrides,
+
+    /// Overrides the response behavior of the TCP listener.
+    #[clap(long, default_value = "")]
+    tcp_behavior: Overrides,
 }
 
 impl Cmd {
     pub async fn run(self) -> Result<(), Box<dyn std::error::Error + 'static>> {
-        let replier = Replier::new(self.response_latency);
+        if !(0.0..=1.0).contains(&self.response_failure_rate) {
+            return Err("--response-failure-rate must be between 0 and 1".into());
+        }
+        if self.tcp_max_in_flight == 0 || self.tcp_buffer_capacity == 0 {
+            return Err("--tcp-max-in-flight and --tcp-buffer-capacity must be positive".into());
+        }
+        if self.concurrency_limit == Some(0) {
+            return Err("--concurrency-limit must be positive".into());
+        }
+        if let Some(id) = self.instance_id.as_deref() {
+            let valid = |b: u8| b.is_ascii_graphic() && b != b',';
+            if id.is_empty() || id.len() > 255 || !id.bytes().all(valid) {
+                return Err(
+                    "--instance-id must be 1-255 printable ASCII characters other than ','".into(),
+                );
+            }
+        }
+        if let Some(banner) = self.tcp_banner.as_deref() {
+            if banner.contains(|c| c == '\r' || c == '\n') {
+                return Err("--tcp-banner must not contain line breaks".into());
+            }
+        }
+        let (grpc_behavior, http_behavior, tcp_behavior) = self.behaviors()?;
+        let gzip = self.compression.contains(&Encoding::Gzip);
 
+        let schedule = Arc::new(Schedule::new(self.degrade));
+        let upstreams = if self.relay.is_empty() {
+            Upstreams::none()
+        } else {
+            Upstreams::connect(self.relay, self.relay_mode, self.relay_connect_timeout)
+                .await
+                .map_err(|e| format!("failed to connect to upstreams: {}", e))?
+        };
+        let (limit, queue) = (self.concurrency_limit, self.concurrency_queue);
+        let bounded = move || {
+            limit
+                .map(|limit| Bounded::new(limit, queue))
+                .unwrap_or_default()
+        };
+
+        let tcp_conns = Arc::new(tcp_muxer::Metrics::default());
         let (close, closed) = drain::channel();
-        tokio::spawn(
-            grpc::Server::new(replier.clone())
+        let mut listeners = Vec::new();
+        let mut behaviors = Vec::new();
+        {
+            let behavior = grpc_behavior;
+            let intervals = behavior.stream_intervals.clone();
+            let pacing = behavior.pacing();
+            let behavior = Arc::new(RwLock::new(behavior));
+            let replier = Replier::new(behavior.clone(), schedule.clone(), self.response_entropy);
+            let (listener, replier, conns) = Listener::new(
+                "grpc",
+                Shed::new(bounded(), Relay::new(upstreams.clone(), replier)),
+            );
+            listeners.push(listener);
+            behaviors.push(("grpc", behavior));
+            tokio::spawn(
+                grpc::Server::new(
+                    replier,
+                    self.instance_id.clone(),
+                    gzip,
+                    intervals,
+                    pacing,
+                    conns,
+                )
                 .serve(self.grpc_addr, closed.clone())
                 .instrument(info_span!("grpc")),
-        );
-        tokio::spawn(
-            http::Server::new(replier.clone())
+            );
+        }
+        {
+            let behavior = http_behavior;
+            let pacing = behavior.pacing();
+            let behavior = Arc::new(RwLock::new(behavior));
+            let replier = Replier::new(behavior.clone(), schedule.clone(), self.response_entropy);
+            let (listener, replier, conns) = Listener::new(
+                "http",
+                Shed::new(bounded(), Relay::new(upstreams.clone(), replier)),
+            );
+            listeners.push(listener);
+            behaviors.push(("http", behavior));
+            tokio::spawn(
+                http::Server::new(
+                    replier,
+                    self.instance_id.clone(),
+                    self.compression,
+                    pacing,
+                    conns,
+                )
                 .serve(self.http_addr, closed.clone())
                 .instrument(info_span!("http")),
+            );
+        }
+        {
+            let behavior = tcp_behavior;
+            let pacing = behavior.pacing();
+            let behavior = Arc::new(RwLock::new(behavior));
+            let replier = Replier::new(behavior.clone(), schedule.clone(), self.response_entropy);
+            let (listener, replier, conns) = Listener::new(
+                "tcp",
+                Shed::new(bounded(), Relay::new(upstreams.clone(), replier)),
+            );
+            listeners.push(listener);
+            behaviors.push(("tcp", behavior));
+            let muxer = tcp_muxer::Settings {
+                buffer_capacity: self.tcp_buffer_capacity,
+                max_in_flight: self.tcp_max_in_flight,
+            };
+            tokio::spawn(
+                tcp::Server::new(
+                    replier,
+                    self.instance_id,
+                    self.tcp_banner,
+                    muxer,
+                    pacing,
+                    conns,
+                    tcp_conns.clone(),
+                )
+                .serve(self.tcp_addr, closed.clone())
+                .instrument(info_span!("tcp")),
+            );
+        }
+        tokio::spawn(
+            throughput::Server::default()
+                .serve(self.throughput_addr, closed.clone())
+                .instrument(info_span!("throughput")),
         );
         tokio::spawn(
-            tcp::Server::new(replier)
-                .serve(self.tcp_addr, closed)
-                .instrument(info_span!("tcp")),
+            echo::Server::new(self.echo_latency)
+                .serve(self.echo_addr, closed)
+                .instrument(info_span!("echo")),
         );
 
-        tokio::spawn(admin(self.admin_addr));
+        let admin = Admin::new(Report::new(listeners, tcp_conns), behaviors);
+        tokio::spawn(
+            admin
+                .serve(self.admin_addr)
+                .instrument(debug_span!("admin")),
+        );
 
         let mut term = signal(SignalKind::terminate())?;
         tokio::select! {
@@ -67,29 +333,76 @@ impl Cmd {
 
         Ok(())
     }
+
+    /// Builds the gRPC, HTTP, and TCP listeners' behaviors, failing if any of them is invalid so
+    /// that the server does not start with settings it cannot honor.
+    fn behaviors(&self) -> Result<(Behavior, Behavior, Behavior), String> {
+        let behavior = Behavior {
+            latencies: self.response_latency.clone(),
+            sizes: self.response_size.clone(),
+            failure_rate: self.response_failure_rate,
+            failure_status: self.response_failure_status,
+            stream_intervals: self.response_stream_interval.clone(),
+            faults: FaultRates {
+                reset: self.fault_reset_rate,
+                hang: self.fault_hang_rate,
+                half_close: self.fault_half_close_rate,
+                truncate: self.fault_truncate_rate,
+                garbage: self.fault_garbage_rate,
+            },
+            chunk_size: self.response_chunk_size,
+            chunk_interval: match self.response_bytes_per_sec {
+                Some(rate) => Some(Pacing::from_rate(self.response_chunk_size, rate).interval),
+                None => self.response_chunk_interval,
+            },
+        };
+        let listener = |name: &str, overrides: &Overrides| {
+            let behavior = behavior.with_overrides(overrides);
+            behavior.validate(name)?;
+            Ok::<_, String>(behavior)
+        };
+        Ok((
+            listener("grpc", &self.grpc_behavior)?,
+            listener("http", &self.http_behavior)?,
+            listener("tcp", &self.tcp_behavior)?,
+        ))
+    }
 }
 
-/// Runs an admin server that can be used for health checking.
-///
-/// It always returns a successful response with a body of `ok\n`.
-#[instrument]
-async fn admin(addr: SocketAddr) -> Result<(), hyper::Error> {
-    use std::convert::Infallible;
-
-    hyper::Server::bind(&addr)
-        .serve(hyper::service::make_service_fn(move |_| async {
-            Ok::<_, Infallible>(hyper::service::service_fn(
-                |req: hyper::Request<hyper::Body>| async move {
-                    debug!(?req);
-                    Ok::<_, Infallible>(match req.uri().path() {
-                        "/ready" | "/live" => hyper::Response::new(hyper::Body::from("ok\n")),
-                        _ => hyper::Response::builder()
-                            .status(hyper::StatusCode::NOT_FOUND)
-                            .body(hyper::Body::from("ok\n"))
-                            .unwrap(),
-                    })
-                },
-            ))
-        }))
-        .await
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_invalid_fault_rates() {
+        let behaviors = |args: &[&str]| {
+            let args = std::iter::once("server").chain(args.iter().copied());
+            Cmd::try_parse_from(args).expect("must parse").behaviors()
+        };
+        assert!(behaviors(&[]).is_ok());
+        assert!(behaviors(&["--fault-reset-rate=0.5", "--fault-hang-rate=0.5"]).is_ok());
+        assert!(behaviors(&["--fault-reset-rate=7"]).is_err());
+        assert!(behaviors(&["--fault-garbage-rate=-0.1"]).is_err());
+        assert!(behaviors(&["--fault-reset-rate=0.6", "--fault-truncate-rate=0.6"]).is_err());
+        assert!(behaviors(&["--tcp-behavior=reset-rate=0.6;garbage-rate=0.6"]).is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_failure_statuses() {
+        let behaviors = |args: &[&str]| {
+            let args = std::iter::once("server").chain(args.iter().copied());
+            Cmd::try_parse_from(args).expect("must parse").behaviors()
+        };
+        // A status that is valid for one protocol must be overridden for the others.
+        assert!(behaviors(&["--response-failure-status=503"]).is_err());
+        assert!(behaviors(&["--response-failure-status=14"]).is_err());
+        assert!(behaviors(&[
+            "--response-failure-status=503",
+            "--grpc-behavior=failure-status=14"
+        ])
+        .is_ok());
+        assert!(behaviors(&["--http-behavior=failure-status=1000"]).is_err());
+        assert!(behaviors(&["--grpc-behavior=failure-status=0"]).is_err());
+        assert!(behaviors(&["--tcp-behavior=failure-status=1000"]).is_ok());
+    }
 }
@@ -0,0 +1,352 @@
+use linkerd_metrics::{metrics, Counter, FmtLabels, FmtMetrics, Gauge, MillisAsSeconds, Summary};
+use ort_core::{limit::Overloaded, ConnMetrics, Error, Ort, Reply, Spec};
+use ort_tcp::muxer;
+use std::{fmt, net::SocketAddr, sync::Arc};
+use tokio::time;
+
+/// Records metrics about the requests served by a listener.
+#[derive(Clone)]
+pub(crate) struct Metrics<O> {
+    inner: O,
+    shared: Arc<Shared>,
+}
+
+#[derive(Clone)]
+pub(crate) struct Report {
+    listeners: Arc<[Listener]>,
+    tcp_conns: Arc<muxer::Metrics>,
+}
+
+/// A listener's metrics.
+pub(crate) struct Listener {
+    protocol: &'static str,
+    requests: Arc<Shared>,
+    conns: Arc<ConnMetrics>,
+}
+
+struct Shared {
+    requests: Counter,
+    failures: Counter,
+    shed: Counter,
+    canceled: Counter,
+    cancel_latencies: Summary<MillisAsSeconds>,
+    in_flight: Gauge,
+    latencies: Summary<MillisAsSeconds>,
+    response_bytes: Counter,
+}
+
+/// Decrements the in-flight gauge when a request completes or is canceled. Requests that are
+/// dropped before they complete are recorded as canceled.
+struct InFlight<'a> {
+    shared: &'a Shared,
+    t0: time::Instant,
+    complete: bool,
+}
+
+struct Protocol(&'static str);
+
+struct Peer(SocketAddr);
+
+metrics! {
+    server_request_count: Counter { "A count of requests received" },
+    server_request_in_flight: Gauge { "The number of requests currently being served" },
+    server_response_latency_seconds: Summary<MillisAsSeconds> { "Response latencies" },
+    server_response_failure_count: Counter { "A count of failed responses" },
+    server_request_shed_count: Counter { "A count of requests rejected by the concurrency limit" },
+    server_request_canceled_count: Counter { "A count of requests canceled before a response was ready" },
+    server_request_cancel_latency_seconds: Summary<MillisAsSeconds> { "Time from receiving a request until its cancelation was observed" },
+    server_response_bytes_count: Counter { "A count of response payload bytes" },
+    server_connection_count: Counter { "A count of accepted connections" },
+    server_connection_open: Gauge { "The number of open connections" },
+    server_read_bytes_count: Counter { "A count of bytes read from connections" },
+    server_write_bytes_count: Counter { "A count of bytes written to connections" },
+    server_tcp_connection_queued: Gauge { "The number of requests waiting to be served on each TCP connection" },
+    server_tcp_connection_in_flight: Gauge { "The number of requests in flight on each TCP connection" }
+}
+
+// === impl Listener ===
+
+impl Listener {
+    /// Wraps `inner` to record request metrics for the listener.
+    pub fn new<O>(protocol: &'static str, inner: O) -> (Self, Metrics<O>, Arc<ConnMetrics>) {
+        let requests = Arc::new(Shared {
+            requests: Counter::default(),
+            failures: Counter::default(),
+            shed: Counter::default(),
+            canceled: Counter::default(),
+            cancel_latencies: Summary::new_resizable(10, time::Duration::from_secs(300), 5)
+                .expect("Summary must be valid"),
+            in_flight: Gauge::default(),
+            latencies: Summary::new_resizable(10, time::Duration::from_secs(300), 5)
+                .expect("Summary must be valid"),
+            response_bytes: Counter::default(),
+        });
+        let conns = Arc::new(ConnMetrics::default());
+        let metrics = Metrics {
+            inner,
+            shared: requests.clone(),
+        };
+        let listener = Self {
+            protocol,
+            requests,
+            conns: conns.clone(),
+        };
+        (listener, metrics, conns)
+    }
+}
+
+// === impl Report ===
+
+impl Report {
+    pub fn new(listeners: Vec<Listener>, tcp_conns: Arc<muxer::Metrics>) -> Self {
+        Self {
+            listeners: listeners.into(),
+            tcp_conns,
+        }
+    }
+}
+
+impl FmtMetrics for Report {
+    fn fmt_metrics(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        server_request_count.fmt_help(f)?;
+        for l in self.listeners.iter() {
+            server_request_count.fmt_metric_labeled(
+                f,
+                &Protocol(l.protocol),
+                &l.requests.requests,
+            )?;
+        }
+
+        server_request_in_flight.fmt_help(f)?;
+        for l in self.listeners.iter() {
+            server_request_in_flight.fmt_metric_labeled(
+                f,
+                &Protocol(l.protocol),
+                &l.requests.in_flight,
+            )?;
+        }
+
+        server_response_latency_seconds.fmt_help(f)?;
+        for l in self.listeners.iter() {
+            server_response_latency_seconds.fmt_metric_labeled(
+                f,
+                &Protocol(l.protocol),
+                &l.requests.latencies,
+            )?;
+        }
+
+        server_response_failure_count.fmt_help(f)?;
+        for l in self.listeners.iter() {
+            server_response_failure_count.fmt_metric_labeled(
+                f,
+                &Protocol(l.protocol),
+                &l.requests.failures,
+            )?;
+        }
+
+        server_request_shed_count.fmt_help(f)?;
+        for l in self.listeners.iter() {
+            server_request_shed_count.fmt_metric_labeled(
+                f,
+                &Protocol(l.protocol),
+                &l.requests.shed,
+            )?;
+        }
+
+        server_request_canceled_count.fmt_help(f)?;
+        for l in self.listeners.iter() {
+            server_request_canceled_count.fmt_metric_labeled(
+                f,
+                &Protocol(l.protocol),
+                &l.requests.canceled,
+            )?;
+        }
+
+        server_request_cancel_latency_seconds.fmt_help(f)?;
+        for l in self.listeners.iter() {
+            server_request_cancel_latency_seconds.fmt_metric_labeled(
+                f,
+                &Protocol(l.protocol),
+                &l.requests.cancel_latencies,
+            )?;
+        }
+
+        server_response_bytes_count.fmt_help(f)?;
+        for l in self.listeners.iter() {
+            server_response_bytes_count.fmt_metric_labeled(
+                f,
+                &Protocol(l.protocol),
+                &l.requests.response_bytes,
+            )?;
+        }
+
+        server_connection_count.fmt_help(f)?;
+        for l in self.listeners.iter() {
+            let accepted = Counter::from(l.conns.accepted());
+            server_connection_count.fmt_metric_labeled(f, &Protocol(l.protocol), &accepted)?;
+        }
+
+        server_connection_open.fmt_help(f)?;
+        for l in self.listeners.iter() {
+            let open = Gauge::from(l.conns.open());
+            server_connection_open.fmt_metric_labeled(f, &Protocol(l.protocol), &open)?;
+        }
+
+        server_read_bytes_count.fmt_help(f)?;
+        for l in self.listeners.iter() {
+            let read = Counter::from(l.conns.read_bytes());
+            server_read_bytes_count.fmt_metric_labeled(f, &Protocol(l.protocol), &read)?;
+        }
+
+        server_write_bytes_count.fmt_help(f)?;
+        for l in self.listeners.iter() {
+            let written = Counter::from(l.conns.write_bytes());
+            server_write_bytes_count.fmt_metric_labeled(f, &Protocol(l.protocol), &written)?;
+        }
+
+        let tcp_conns = self.tcp_conns.conns();
+        server_tcp_connection_queued.fmt_help(f)?;
+        for c in tcp_conns.iter() {
+            let queued = Gauge::from(c.queued() as u64);
+            server_tcp_connection_queued.fmt_metric_labeled(f, &Peer(c.addr()), &queued)?;
+        }
+
+        server_tcp_connection_in_flight.fmt_help(f)?;
+        for c in tcp_conns.iter() {
+            let in_flight = Gauge::from(c.in_flight() as u64);
+            server_tcp_connection_in_flight.fmt_metric_labeled(f, &Peer(c.addr()), &in_flight)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl FmtLabels for Protocol {
+    fn fmt_labels(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "protocol=\"{}\"", self.0)
+    }
+}
+
+impl FmtLabels for Peer {
+    fn fmt_labels(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "peer=\"{}\"", self.0)
+    }
+}
+
+// === impl Metrics ===
+
+#[async_trait::async_trait]
+impl<O: Ort> Ort for Metrics<O> {
+    async fn ort(&mut self, spec: Spec) -> Result<Reply, Error> {
+        self.shared.requests.incr();
+        let t0 = time::Instant::now();
+        let mut in_flight = InFlight::new(&self.shared, t0);
+
+        let res = self.inner.ort(spec).await;
+        in_flight.complete = true;
+        let millis = t0.elapsed().as_millis();
+        self.shared
+            .latencies
+            .record(millis as u64)
+            .expect("latency must fit in histogram");
+
+        match res.as_ref() {
+            Ok(Reply { data, .. }) => self.shared.response_bytes.add(data.len() as u64),
+            Err(error) => {
+                self.shared.failures.incr();
+                if error.is::<Overloaded>() {
+                    self.shared.shed.incr();
+                }
+            }
+        }
+        res
+    }
+}
+
+// === impl InFlight ===
+
+impl<'a> InFlight<'a> {
+    fn new(shared: &'a Shared, t0: time::Instant) -> Self {
+        shared.in_flight.incr();
+        Self {
+            shared,
+            t0,
+            complete: false,
+        }
+    }
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.shared.in_flight.decr();
+        if !self.complete {
+            self.shared.canceled.incr();
+            self.shared
+                .cancel_latencies
+                .record(self.t0.elapsed().as_millis() as u64)
+                .expect("latency must fit in histogram");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ort_core::MakeOrt;
+    use ort_tcp::{
+        client::{MakeTcp, Mode, Target},
+        server::Server,
+    };
+    use tokio::sync::{mpsc, oneshot};
+
+    /// Never replies. Each request sends a receiver that completes when the request is dropped.
+    #[derive(Clone)]
+    struct Pending(mpsc::UnboundedSender<oneshot::Receiver<()>>);
+
+    #[async_trait::async_trait]
+    impl Ort for Pending {
+        async fn ort(&mut self, _: Spec) -> Result<Reply, Error> {
+            let (_tx, rx) = oneshot::channel();
+            let _ = self.0.send(rx);
+            futures::future::pending().await
+        }
+    }
+
+    #[tokio::test]
+    async fn records_tcp_cancelations() {
+        let (started_tx, mut started) = mpsc::unbounded_channel();
+        let (listener, metrics, conns) = Listener::new("tcp", Pending(started_tx));
+        let lis = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("must bind");
+        let addr = lis.local_addr().expect("must have an address");
+        let (_close, closed) = drain::channel();
+        let server = Server::new(
+            metrics,
+            None,
+            None,
+            muxer::Settings::default(),
+            None,
+            conns,
+            Default::default(),
+        );
+        tokio::spawn(server.serve_listener(lis, closed));
+
+        let target = Target {
+            addr: addr.to_string(),
+            mode: Mode::Multiplexed,
+        };
+        let mut client = MakeTcp::new(Default::default(), Default::default())
+            .make_ort(target)
+            .await
+            .expect("must connect");
+        let req = tokio::spawn(async move { client.ort(Spec::default()).await });
+        let served = started.recv().await.expect("request must be served");
+
+        // Dropping the caller cancels the request, which drops the server's future.
+        req.abort();
+        assert!(served.await.is_err(), "request must be dropped");
+        assert_eq!(listener.requests.canceled.value(), 1);
+        assert_eq!(listener.requests.requests.value(), 1);
+    }
+}
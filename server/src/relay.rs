@@ -0,0 +1,433 @@
+use futures::future;
+use ort_core::{Error, Failure, Hop, MakeOrt, Ort, Reply, Spec};
+use ort_grpc::client::{Grpc, MakeGrpc, Settings as GrpcSettings};
+use ort_http::client::{Http, MakeHttp};
+use ort_tcp::client::{MakeTcp, Mode as TcpMode, Target as TcpTarget, Tcp};
+use rand::{thread_rng, Rng};
+use std::{str::FromStr, sync::Arc, time::Duration};
+use tokio::{sync::Mutex, time};
+use tracing::{debug, trace};
+
+/// Relays each request to upstream servers before replying.
+#[derive(Clone)]
+pub(crate) struct Relay<O> {
+    inner: O,
+    upstreams: Upstreams,
+}
+
+/// Clients for the upstream servers to which requests are relayed.
+#[derive(Clone)]
+pub(crate) struct Upstreams {
+    mode: Mode,
+    clients: Arc<[(u32, Client)]>,
+}
+
+/// An upstream target, e.g. `grpc://backend:8070`, optionally prefixed by a weight that is used
+/// when upstreams are chosen by weight, e.g. `3=http://backend:8080`.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Upstream {
+    weight: u32,
+    target: Target,
+}
+
+#[derive(Debug)]
+pub(crate) struct InvalidUpstream(String);
+
+/// Determines how a request is relayed to upstream servers.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Mode {
+    /// Each upstream is called in order.
+    Sequential,
+    /// All upstreams are called concurrently.
+    Parallel,
+    /// A single upstream is chosen by weight.
+    Weighted,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub(crate) struct InvalidMode(());
+
+#[derive(Clone, Debug, PartialEq)]
+enum Target {
+    Http(hyper::Uri),
+    Grpc(hyper::Uri),
+    Tcp(TcpTarget),
+}
+
+#[derive(Clone)]
+enum Client {
+    Http(Http),
+    Grpc(Grpc),
+    Tcp(TcpUpstream),
+}
+
+/// A connection to a TCP upstream. Unlike HTTP and gRPC clients, TCP clients do not reconnect, so
+/// a lost connection is replaced when the next request is relayed.
+#[derive(Clone)]
+struct TcpUpstream {
+    make: MakeTcp,
+    target: TcpTarget,
+    connect_timeout: Duration,
+    conn: Arc<Mutex<Tcp>>,
+}
+
+// === impl Relay ===
+
+impl<O> Relay<O> {
+    pub fn new(upstreams: Upstreams, inner: O) -> Self {
+        Self { inner, upstreams }
+    }
+}
+
+#[async_trait::async_trait]
+impl<O: Ort> Ort for Relay<O> {
+    async fn ort(&mut self, spec: Spec) -> Result<Reply, Error> {
+        let hops = self.upstreams.relay(spec).await?;
+        let mut reply = self.inner.ort(spec).await?;
+        reply.hops = hops;
+        Ok(reply)
+    }
+}
+
+// === impl Upstreams ===
+
+impl Upstreams {
+    /// Connects to each upstream.
+    pub async fn connect(
+        upstreams: Vec<Upstream>,
+        mode: Mode,
+        connect_timeout: Duration,
+    ) -> Result<Self, Error> {
+        let mut clients = Vec::with_capacity(upstreams.len());
+        for Upstream { weight, target } in upstreams.into_iter() {
+            debug!(?target, weight, "Connecting to upstream");
+            let client = match target {
+                Target::Http(uri) => {
+                    let mut make =
+                        MakeHttp::new(None, connect_timeout, None, None, None, None, None);
+                    Client::Http(make.make_ort(uri).await?)
+                }
+                Target::Grpc(uri) => {
+                    let settings = GrpcSettings {
+                        connect_timeout: Some(connect_timeout),
+                        ..GrpcSettings::default()
+                    };
+                    let mut make = MakeGrpc::new(
+                        settings,
+                        ort_grpc::client::Mode::Unary,
+                        None,
+                        vec![],
+                        Default::default(),
+                    );
+                    Client::Grpc(make.make_ort(uri).await?)
+                }
+                Target::Tcp(target) => {
+                    let tcp = TcpUpstream::connect(target, connect_timeout).await?;
+                    Client::Tcp(tcp)
+                }
+            };
+            clients.push((weight, client));
+        }
+        Ok(Self {
+            mode,
+            clients: clients.into(),
+        })
+    }
+
+    /// Does not relay requests.
+    pub fn none() -> Self {
+        Self {
+            mode: Mode::default(),
+            clients: Vec::new().into(),
+        }
+    }
+
+    /// Relays the request to upstreams, returning the hops of each upstream call followed by the
+    /// hops relayed by that upstream.
+    ///
+    /// Upstreams apply their own latency; only the requested response size is relayed. When an
+    /// upstream call fails, the request fails.
+    async fn relay(&self, spec: Spec) -> Result<Vec<Hop>, Error> {
+        if self.clients.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let spec = Spec {
+            response_size: spec.response_size,
+            ..Spec::default()
+        };
+        let replies = match self.mode {
+            Mode::Sequential => {
+                let mut replies = Vec::with_capacity(self.clients.len());
+                for (_, client) in self.clients.iter() {
+                    replies.push(call(client.clone(), spec).await?);
+                }
+                replies
+            }
+            Mode::Parallel => {
+                let calls = self.clients.iter().map(|(_, c)| call(c.clone(), spec));
+                future::try_join_all(calls).await?
+            }
+            Mode::Weighted => {
+                let client = self.choose();
+                vec![call(client.clone(), spec).await?]
+            }
+        };
+
+        let mut hops = Vec::new();
+        for (latency, reply) in replies.into_iter() {
+            hops.push(Hop {
+                instance: reply.instance,
+                latency,
+            });
+            hops.extend(reply.hops);
+        }
+        Ok(hops)
+    }
+
+    fn choose(&self) -> &Client {
+        let total = self.clients.iter().map(|(w, _)| *w as u64).sum::<u64>();
+        let mut n = thread_rng().gen_range(0..total.max(1));
+        for (weight, client) in self.clients.iter() {
+            if n < *weight as u64 {
+                return client;
+            }
+            n -= *weight as u64;
+        }
+        &self.clients[self.clients.len() - 1].1
+    }
+}
+
+/// Calls an upstream, returning the observed latency with its reply. Upstream errors fail the
+/// request.
+async fn call(mut client: Client, spec: Spec) -> Result<(Duration, Reply), Error> {
+    let t0 = time::Instant::now();
+    let res = match client {
+        Client::Http(ref mut c) => c.ort(spec).await,
+        Client::Grpc(ref mut c) => c.ort(spec).await,
+        Client::Tcp(ref mut c) => c.ort(spec).await,
+    };
+    let latency = t0.elapsed();
+    match res {
+        Ok(reply) => {
+            trace!(?latency, instance = ?reply.instance, "Upstream replied");
+            Ok((latency, reply))
+        }
+        Err(error) => {
+            debug!(%error, ?latency, "Upstream failed");
+            Err(Failure::default().into())
+        }
+    }
+}
+
+// === impl TcpUpstream ===
+
+impl TcpUpstream {
+    async fn connect(target: TcpTarget, connect_timeout: Duration) -> Result<Self, Error> {
+        let mut make = MakeTcp::new(Default::default(), Default::default());
+        let connect = make.make_ort(target.clone());
+        let tcp = time::timeout(connect_timeout, connect).await??;
+        Ok(Self {
+            make,
+            target,
+            connect_timeout,
+            conn: Arc::new(Mutex::new(tcp)),
+        })
+    }
+
+    /// Returns the upstream's connection, reconnecting if it has been lost.
+    async fn conn(&mut self) -> Result<Tcp, Error> {
+        let mut conn = self.conn.lock().await;
+        if conn.is_closed() {
+            debug!(target = %self.target, "Reconnecting to upstream");
+            let connect = self.make.make_ort(self.target.clone());
+            *conn = time::timeout(self.connect_timeout, connect).await??;
+        }
+        Ok(conn.clone())
+    }
+}
+
+#[async_trait::async_trait]
+impl Ort for TcpUpstream {
+    async fn ort(&mut self, spec: Spec) -> Result<Reply, Error> {
+        self.conn().await?.ort(spec).await
+    }
+}
+
+// === impl Upstream ===
+
+impl FromStr for Upstream {
+    type Err = InvalidUpstream;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || InvalidUpstream(s.to_string());
+        let (weight, target) = match s.split_once('=') {
+            Some((weight, target)) => (weight.parse::<u32>().map_err(|_| invalid())?, target),
+            None => (1, s),
+        };
+
+        let uri = target.parse::<hyper::Uri>().map_err(|_| invalid())?;
+        let host = uri.host().ok_or_else(invalid)?;
+        let target = match uri.scheme_str() {
+            Some("http") => {
+                let port = uri.port_u16().unwrap_or(8080);
+                let uri = format!("http://{}:{}", host, port)
+                    .parse()
+                    .map_err(|_| invalid())?;
+                Target::Http(uri)
+            }
+            Some("grpc") => {
+                let port = uri.port_u16().unwrap_or(8070);
+                let uri = format!("http://{}:{}", host, port)
+                    .parse()
+                    .map_err(|_| invalid())?;
+                Target::Grpc(uri)
+            }
+            Some(scheme @ ("tcp" | "tcp+serial" | "echo")) => {
+                let (mode, port) = match scheme {
+                    "tcp+serial" => (TcpMode::Serial, 8090),
+                    "echo" => (TcpMode::Echo, 8092),
+                    _ => (TcpMode::Multiplexed, 8090),
+                };
+                Target::Tcp(TcpTarget {
+                    addr: format!("{}:{}", host, uri.port_u16().unwrap_or(port)),
+                    mode,
+                })
+            }
+            _ => return Err(invalid()),
+        };
+        Ok(Self { weight, target })
+    }
+}
+
+impl std::fmt::Display for InvalidUpstream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "invalid upstream '{}'; expected [<weight>=]<http|grpc|tcp|tcp+serial|echo>://<host>[:<port>]",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for InvalidUpstream {}
+
+// === impl Mode ===
+
+impl Default for Mode {
+    fn default() -> Self {
+        Self::Sequential
+    }
+}
+
+impl FromStr for Mode {
+    type Err = InvalidMode;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sequential" => Ok(Self::Sequential),
+            "parallel" => Ok(Self::Parallel),
+            "weighted" => Ok(Self::Weighted),
+            _ => Err(InvalidMode(())),
+        }
+    }
+}
+
+impl std::fmt::Display for InvalidMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "invalid mode; expected sequential, parallel, or weighted"
+        )
+    }
+}
+
+impl std::error::Error for InvalidMode {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_upstream() {
+        assert_eq!(
+            "grpc://backend".parse::<Upstream>().expect("must parse"),
+            Upstream {
+                weight: 1,
+                target: Target::Grpc("http://backend:8070".parse().unwrap()),
+            }
+        );
+        assert_eq!(
+            "3=tcp://backend:9000"
+                .parse::<Upstream>()
+                .expect("must parse"),
+            Upstream {
+                weight: 3,
+                target: Target::Tcp(TcpTarget {
+                    addr: "backend:9000".to_string(),
+                    mode: TcpMode::Multiplexed,
+                }),
+            }
+        );
+        assert_eq!(
+            "tcp+serial://backend"
+                .parse::<Upstream>()
+                .expect("must parse"),
+            Upstream {
+                weight: 1,
+                target: Target::Tcp(TcpTarget {
+                    addr: "backend:8090".to_string(),
+                    mode: TcpMode::Serial,
+                }),
+            }
+        );
+        assert!("backend:8080".parse::<Upstream>().is_err());
+        assert!("x=http://backend".parse::<Upstream>().is_err());
+        assert!("udp://backend".parse::<Upstream>().is_err());
+    }
+
+    #[derive(Clone)]
+    struct Fails;
+
+    #[async_trait::async_trait]
+    impl Ort for Fails {
+        async fn ort(&mut self, _: Spec) -> Result<Reply, Error> {
+            Err(Failure { status: Some(503) }.into())
+        }
+    }
+
+    #[derive(Clone)]
+    struct Replies;
+
+    #[async_trait::async_trait]
+    impl Ort for Replies {
+        async fn ort(&mut self, _: Spec) -> Result<Reply, Error> {
+            Ok(Reply::default())
+        }
+    }
+
+    #[tokio::test]
+    async fn relays_upstream_failures() {
+        let lis = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("must bind");
+        let addr = lis.local_addr().expect("must have an address");
+        let (_close, closed) = drain::channel();
+        let server = ort_http::server::Server::new(Fails, None, vec![], None, Default::default());
+        tokio::spawn(server.serve_listener(lis, closed));
+
+        let upstream = format!("http://{}", addr).parse().expect("must parse");
+        let upstreams =
+            Upstreams::connect(vec![upstream], Mode::Sequential, Duration::from_secs(1))
+                .await
+                .expect("must connect");
+        let mut relay = Relay::new(upstreams, Replies);
+        for _ in 0..3 {
+            let error = relay
+                .ort(Spec::default())
+                .await
+                .expect_err("upstream failure must fail the request");
+            assert!(error.is::<Failure>());
+        }
+    }
+}
@@ -1,30 +1,158 @@
+use crate::{behavior::Behavior, schedule::Schedule};
 use bytes::BytesMut;
-use ort_core::{latency, Error, Ort, Reply, Spec};
-use rand::{distributions::Distribution, thread_rng, RngCore};
+use ort_core::{Error, Failure, Fault, Ort, Reply, Spec};
+use parking_lot::RwLock;
+use rand::{distributions::Distribution, thread_rng, Rng, RngCore};
+use std::sync::Arc;
 use tokio::time;
 use tracing::trace;
 
 #[derive(Clone)]
 pub(crate) struct Replier {
-    latencies: latency::Distribution,
+    behavior: Arc<RwLock<Behavior>>,
+    schedule: Arc<Schedule>,
+    entropy: f64,
 }
 
 impl Replier {
-    pub fn new(latencies: latency::Distribution) -> Self {
-        Self { latencies }
+    /// Creates a replier whose replies are filled with random bytes in proportion to `entropy`
+    /// (between 0 and 1); the remainder of each reply is zeroed so that it compresses well.
+    ///
+    /// The schedule's degradations are applied on top of the behavior, which may be updated
+    /// while the server runs.
+    pub fn new(behavior: Arc<RwLock<Behavior>>, schedule: Arc<Schedule>, entropy: f64) -> Self {
+        Self {
+            behavior,
+            schedule,
+            entropy: entropy.clamp(0.0, 1.0),
+        }
     }
 }
 
 #[async_trait::async_trait]
 impl Ort for Replier {
     async fn ort(&mut self, spec: Spec) -> Result<Reply, Error> {
-        let latency = spec.latency.max(self.latencies.sample(&mut thread_rng()));
-        trace!(?latency, spec.response_size, "Serving request");
+        let (latency, response_size, fail, status, fault) = {
+            let degraded = self.schedule.degraded();
+            let behavior = self.behavior.read();
+            let mut rng = thread_rng();
+            let latency = spec.latency.max(behavior.latencies.sample(&mut rng));
+            let size = behavior.sizes.sample(&mut rng) as usize;
+            let failure_rate = behavior.failure_rate.max(degraded.failure_rate);
+            let fail = rng.gen::<f64>() < failure_rate;
+            let fault = behavior.faults.sample(&mut rng);
+            (
+                latency + degraded.latency,
+                spec.response_size.max(size),
+                fail,
+                behavior.failure_status,
+                fault,
+            )
+        };
+        trace!(?latency, response_size, fail, ?fault, "Serving request");
         let sleep = time::sleep(latency);
-        let mut buf = BytesMut::with_capacity(spec.response_size);
-        thread_rng().fill_bytes(buf.as_mut());
+
+        if fault == Some(Fault::Hang) {
+            sleep.await;
+            trace!("Hanging request");
+            // Only this request hangs; the connection continues to serve other requests.
+            return futures::future::pending().await;
+        }
+
+        if let Some(fault) = fault {
+            sleep.await;
+            trace!(%fault, "Injecting fault");
+            return Err(fault.into());
+        }
+
+        if fail {
+            sleep.await;
+            trace!("Failing request");
+            return Err(Failure { status }.into());
+        }
+
+        let mut buf = BytesMut::zeroed(response_size);
+        let random = (response_size as f64 * self.entropy) as usize;
+        thread_rng().fill_bytes(&mut buf[..random]);
         sleep.await;
         trace!("Returning reply");
-        Ok(Reply { data: buf.freeze() })
+        Ok(Reply {
+            data: buf.freeze(),
+            instance: None,
+            hops: vec![],
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::behavior::FaultRates;
+    use ort_core::MakeOrt;
+    use ort_http::{client::MakeHttp, server as http};
+
+    #[tokio::test]
+    async fn hangs_never_reply() {
+        let behavior = Behavior {
+            faults: FaultRates {
+                hang: 1.0,
+                ..FaultRates::default()
+            },
+            ..Behavior::default()
+        };
+        let mut replier = Replier::new(
+            Arc::new(RwLock::new(behavior)),
+            Arc::new(Schedule::new(vec![])),
+            1.0,
+        );
+        let res = time::timeout(
+            time::Duration::from_millis(100),
+            replier.ort(Spec::default()),
+        )
+        .await;
+        assert!(res.is_err(), "request must hang");
+    }
+
+    #[tokio::test]
+    async fn http_failures_are_client_errors() {
+        let behavior = Behavior {
+            failure_rate: 1.0,
+            failure_status: Some(503),
+            ..Behavior::default()
+        };
+        let replier = Replier::new(
+            Arc::new(RwLock::new(behavior)),
+            Arc::new(Schedule::new(vec![])),
+            1.0,
+        );
+        let lis = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("must bind");
+        let addr = lis.local_addr().expect("must have an address");
+        let (_close, closed) = drain::channel();
+        let server = http::Server::new(replier, None, vec![], None, Default::default());
+        tokio::spawn(server.serve_listener(lis, closed));
+
+        let target = format!("http://{}", addr).parse().unwrap();
+        let mut client = MakeHttp::new(
+            None,
+            time::Duration::from_secs(1),
+            None,
+            None,
+            None,
+            None,
+            None,
+        )
+        .make_ort(target)
+        .await
+        .expect("must build client");
+        for _ in 0..3 {
+            let error = client
+                .ort(Spec::default())
+                .await
+                .expect_err("request must fail");
+            let failure = error.downcast_ref::<Failure>().expect("must be a failure");
+            assert_eq!(failure.status, Some(503));
+        }
     }
 }
@@ -0,0 +1,229 @@
+use ort_core::parse_duration;
+use std::{str::FromStr, time::Duration};
+use tokio::time::Instant;
+
+/// Degrades a server's behavior over time.
+#[derive(Clone, Debug)]
+pub(crate) struct Schedule {
+    start: Instant,
+    degradations: Vec<Degradation>,
+}
+
+/// A degradation that is active during a window of time, formatted as `;`-separated `key=value`
+/// pairs. For example:
+///
+/// - `every=10m;for=30s;latency=500ms` adds 500ms of latency for 30s every 10 minutes;
+/// - `for=1h;failure-rate=0..0.2` ramps the failure rate from 0 to 20% over an hour;
+/// - `start=5m;every=1h;for=1m;failure-rate=1` fails all requests for a minute every hour,
+///   starting 5 minutes after the server starts.
+///
+/// Ranges (`from..to`) are interpolated linearly over the window.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct Degradation {
+    start: Duration,
+    every: Option<Duration>,
+    window: Option<Duration>,
+    latency: Range<Duration>,
+    failure_rate: Range<f64>,
+}
+
+#[derive(Debug)]
+pub(crate) struct InvalidDegradation(String);
+
+/// The degradations in effect at a point in time.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub(crate) struct Degraded {
+    /// Latency added to every reply.
+    pub latency: Duration,
+    /// The minimum failure rate.
+    pub failure_rate: f64,
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+struct Range<T> {
+    from: T,
+    to: T,
+}
+
+// === impl Schedule ===
+
+impl Schedule {
+    pub fn new(degradations: Vec<Degradation>) -> Self {
+        Self {
+            start: Instant::now(),
+            degradations,
+        }
+    }
+
+    pub fn degraded(&self) -> Degraded {
+        self.degraded_at(self.start.elapsed())
+    }
+
+    fn degraded_at(&self, elapsed: Duration) -> Degraded {
+        self.degradations
+            .iter()
+            .filter_map(|d| d.progress(elapsed).map(|p| (d, p)))
+            .fold(Degraded::default(), |acc, (d, p)| Degraded {
+                latency: acc.latency + d.latency.at(p),
+                failure_rate: acc.failure_rate.max(d.failure_rate.at(p)),
+            })
+    }
+}
+
+// === impl Degradation ===
+
+impl Degradation {
+    /// Returns how far into its window the degradation is (between 0 and 1) or `None` if it is
+    /// not active.
+    fn progress(&self, elapsed: Duration) -> Option<f64> {
+        let mut t = elapsed.checked_sub(self.start)?;
+        if let Some(every) = self.every {
+            t = Duration::from_nanos((t.as_nanos() % every.as_nanos()) as u64);
+        }
+        match self.window.or(self.every) {
+            None => Some(0.0),
+            Some(window) if t < window => Some(t.as_secs_f64() / window.as_secs_f64()),
+            Some(_) => None,
+        }
+    }
+}
+
+impl FromStr for Degradation {
+    type Err = InvalidDegradation;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || InvalidDegradation(s.to_string());
+        let mut degradation = Self::default();
+        for kv in s.split(';').filter(|kv| !kv.is_empty()) {
+            let mut kv = kv.splitn(2, '=');
+            let (k, v) = match (kv.next(), kv.next()) {
+                (Some(k), Some(v)) => (k.trim(), v.trim()),
+                _ => return Err(invalid()),
+            };
+            match k {
+                "start" => degradation.start = parse_duration(v).map_err(|_| invalid())?,
+                "every" => {
+                    let every = parse_duration(v).map_err(|_| invalid())?;
+                    if every == Duration::from_secs(0) {
+                        return Err(invalid());
+                    }
+                    degradation.every = Some(every);
+                }
+                "for" => degradation.window = Some(parse_duration(v).map_err(|_| invalid())?),
+                "latency" => {
+                    degradation.latency =
+                        Range::parse(v, |v| parse_duration(v).ok()).ok_or_else(invalid)?
+                }
+                "failure-rate" => {
+                    let rate = Range::parse(v, |v| {
+                        v.parse::<f64>().ok().filter(|r| (0.0..=1.0).contains(r))
+                    })
+                    .ok_or_else(invalid)?;
+                    degradation.failure_rate = rate;
+                }
+                _ => return Err(invalid()),
+            }
+        }
+        Ok(degradation)
+    }
+}
+
+impl std::fmt::Display for InvalidDegradation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "invalid degradation '{}'; expected ;-separated start, every, for, latency, or failure-rate values",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for InvalidDegradation {}
+
+// === impl Range ===
+
+impl<T: Copy> Range<T> {
+    /// Parses either a single value or a `from..to` range.
+    fn parse(s: &str, parse: impl Fn(&str) -> Option<T>) -> Option<Self> {
+        let mut parts = s.splitn(2, "..");
+        match (parts.next(), parts.next()) {
+            (Some(from), Some(to)) => Some(Self {
+                from: parse(from)?,
+                to: parse(to)?,
+            }),
+            (Some(v), None) => {
+                let v = parse(v)?;
+                Some(Self { from: v, to: v })
+            }
+            _ => None,
+        }
+    }
+}
+
+impl Range<Duration> {
+    fn at(&self, p: f64) -> Duration {
+        if self.to >= self.from {
+            self.from + (self.to - self.from).mul_f64(p)
+        } else {
+            self.from - (self.from - self.to).mul_f64(p)
+        }
+    }
+}
+
+impl Range<f64> {
+    fn at(&self, p: f64) -> f64 {
+        self.from + (self.to - self.from) * p
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn periodic() {
+        let d = "every=10m;for=30s;latency=500ms"
+            .parse::<Degradation>()
+            .expect("must parse");
+        let schedule = Schedule::new(vec![d]);
+
+        let degraded = schedule.degraded_at(Duration::from_secs(10));
+        assert_eq!(degraded.latency, Duration::from_millis(500));
+        assert_eq!(degraded.failure_rate, 0.0);
+
+        let degraded = schedule.degraded_at(Duration::from_secs(60));
+        assert_eq!(degraded, Degraded::default());
+
+        let degraded = schedule.degraded_at(Duration::from_secs(10 * 60 + 29));
+        assert_eq!(degraded.latency, Duration::from_millis(500));
+    }
+
+    #[test]
+    fn ramp() {
+        let d = "start=1m;for=1h;failure-rate=0..0.2"
+            .parse::<Degradation>()
+            .expect("must parse");
+        let schedule = Schedule::new(vec![d]);
+
+        assert_eq!(
+            schedule.degraded_at(Duration::from_secs(30)).failure_rate,
+            0.0
+        );
+        let rate = schedule
+            .degraded_at(Duration::from_secs(31 * 60))
+            .failure_rate;
+        assert!((rate - 0.1).abs() < 1e-9, "{}", rate);
+        assert_eq!(
+            schedule.degraded_at(Duration::from_secs(62 * 60)),
+            Degraded::default()
+        );
+    }
+
+    #[test]
+    fn parse_invalid() {
+        assert!("every=0s".parse::<Degradation>().is_err());
+        assert!("failure-rate=0..2".parse::<Degradation>().is_err());
+        assert!("latency=fast".parse::<Degradation>().is_err());
+        assert!("bogus=1".parse::<Degradation>().is_err());
+    }
+}
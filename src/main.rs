@@ -22,8 +22,11 @@ struct Ort {
 #[derive(Parser)]
 #[allow(clippy::large_enum_variant)]
 enum Cmd {
+    ConnectRate(load::ConnectRateCmd),
+    Idle(load::IdleCmd),
     Load(load::Cmd),
     Server(server::Cmd),
+    Throughput(load::ThroughputCmd),
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error + 'static>> {
@@ -38,8 +41,11 @@ fn main() -> Result<(), Box<dyn std::error::Error + 'static>> {
         .build()?;
 
     match cmd {
+        Cmd::ConnectRate(c) => rt.block_on(c.run())?,
+        Cmd::Idle(i) => rt.block_on(i.run())?,
         Cmd::Load(l) => rt.block_on(l.run(threads))?,
         Cmd::Server(s) => rt.block_on(s.run())?,
+        Cmd::Throughput(t) => rt.block_on(t.run())?,
     }
 
     Ok(())
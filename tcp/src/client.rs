@@ -1,65 +1,312 @@
 //! TODO TCP clients shoudl automatically reconnect, but they don't
 
-use crate::{muxer, preface, ReplyCodec, SpecCodec};
-use ort_core::{Error, MakeOrt, Ort, Reply, Spec};
+use crate::{
+    echo,
+    muxer::{self, Frame, Message},
+    preface::{self, Capabilities, Version},
+    v2, ReplyCodec, Response, SpecCodec,
+};
+use futures::prelude::*;
+use ort_core::{Bind, ConnWatch, Error, MakeOrt, Ort, Reply, Spec, WatchedIo};
+use std::{fmt, sync::Arc};
 use tokio::{
-    io,
+    io::{self, AsyncRead, AsyncReadExt},
     net::TcpStream,
     sync::{mpsc, oneshot},
+    time,
 };
 use tokio_util::codec::{FramedRead, FramedWrite};
-use tracing::{debug, debug_span};
+use tracing::{debug, debug_span, trace, Instrument};
 
 #[derive(Clone)]
 pub struct MakeTcp {
-    buffer_capacity: usize,
+    settings: Settings,
+    metrics: Arc<muxer::Metrics>,
+}
+
+/// Configures the connections used to issue requests.
+#[derive(Clone, Debug)]
+pub struct Settings {
+    /// Limits the requests on each connection.
+    pub muxer: muxer::Settings,
+    /// The protocol version to speak. Version 1 is supported for compatibility with older servers.
+    pub version: Version,
+    /// When set, a banner line (terminated by `\r\n`) is read from the server before the client
+    /// writes anything.
+    pub banner: bool,
+    /// Delays the client's first write on each connection.
+    pub first_write_delay: Option<time::Duration>,
+    /// Reports how each connection closes.
+    pub watch: Option<ConnWatch>,
+    /// Binds each connection to one of several local addresses.
+    pub bind: Option<Bind>,
+}
+
+/// A server to connect to.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Target {
+    pub addr: String,
+    pub mode: Mode,
+}
+
+/// Determines how requests are issued on a connection.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Mode {
+    /// Requests are multiplexed.
+    Multiplexed,
+    /// Requests are issued one at a time. Serial connections always speak protocol version 2.
+    Serial,
+    /// Each request's payload is sent to an echo server, one request at a time, and the echoed
+    /// bytes are verified.
+    Echo,
 }
 
 #[derive(Clone)]
 pub struct Tcp {
-    tx: mpsc::Sender<(Spec, oneshot::Sender<Reply>)>,
+    tx: Dispatch,
+    stats: Arc<muxer::ConnStats>,
 }
 
+#[derive(Clone)]
+enum Dispatch {
+    Framed(mpsc::Sender<(Spec, oneshot::Sender<Response>)>),
+    Echo(mpsc::Sender<(Spec, oneshot::Sender<io::Result<Reply>>)>),
+}
+
+/// The maximum length of a server's banner.
+const MAX_BANNER_LEN: usize = 1024;
+
+// === impl MakeTcp ===
+
 impl MakeTcp {
-    pub fn new(buffer_capacity: usize) -> Self {
-        Self { buffer_capacity }
+    /// Creates a client factory. Each connection's requests are recorded in `metrics`.
+    pub fn new(settings: Settings, metrics: Arc<muxer::Metrics>) -> Self {
+        Self { settings, metrics }
     }
 }
 
 #[async_trait::async_trait]
-impl MakeOrt<String> for MakeTcp {
+impl MakeOrt<Target> for MakeTcp {
     type Ort = Tcp;
 
-    async fn make_ort(&mut self, target: String) -> Result<Tcp, Error> {
+    async fn make_ort(&mut self, target: Target) -> Result<Tcp, Error> {
         debug!(%target, "Initializing a new connection");
-        let stream = TcpStream::connect(target).await?;
+        let stream = match self.settings.bind.as_ref() {
+            Some(bind) => bind.connect(target.addr).await?,
+            None => TcpStream::connect(target.addr).await?,
+        };
         stream.set_nodelay(true)?;
-
         let local = stream.local_addr()?;
         let peer = stream.peer_addr()?;
-        let (rio, wio) = stream.into_split();
-        let write = FramedWrite::new(
-            wio,
-            preface::Codec::from(muxer::FramedEncode::from(SpecCodec::default())),
-        );
-        let read = FramedRead::new(rio, muxer::FramedDecode::from(ReplyCodec::default()));
-        let tx = debug_span!("conn", %local, %peer)
-            .in_scope(|| muxer::spawn_client(write, read, self.buffer_capacity));
-
-        Ok(Tcp { tx })
+        let mut conn = match self.settings.watch.as_ref() {
+            Some(watch) => watch.watch(stream)?,
+            None => WatchedIo::unwatched(stream),
+        };
+
+        if self.settings.banner {
+            let banner = read_banner(&mut conn).await?;
+            debug!(?banner, "Read banner");
+        }
+        if let Some(delay) = self.settings.first_write_delay {
+            debug!(?delay, "Delaying first write");
+            time::sleep(delay).await;
+        }
+
+        let span = debug_span!("conn", %local, %peer);
+        let mux = self.settings.muxer;
+        let stats = self.metrics.register(local);
+        if target.mode == Mode::Echo {
+            let tx = span.in_scope(|| echo::spawn_client(conn, mux, stats.clone()));
+            return Ok(Tcp {
+                tx: Dispatch::Echo(tx),
+                stats,
+            });
+        }
+
+        let (mut rio, mut wio) = io::split(conn);
+        let tx = if target.mode == Mode::Serial {
+            let negotiated = preface::connect(&mut rio, &mut wio, Capabilities::SERIAL).await?;
+            debug!(?negotiated, "Negotiated protocol");
+            let write = FramedWrite::new(wio, v2::Codec::default());
+            let read = FramedRead::new(rio, v2::Codec::default());
+            span.in_scope(|| spawn_serial(write, read, mux, stats.clone()))
+        } else {
+            match self.settings.version {
+                Version::V1 => {
+                    let write = FramedWrite::new(
+                        wio,
+                        preface::Codec::from(muxer::FramedEncode::from(SpecCodec::default())),
+                    );
+                    let read =
+                        FramedRead::new(rio, muxer::FramedDecode::from(ReplyCodec::default()));
+                    span.in_scope(|| muxer::spawn_client(write, read, mux, false, stats.clone()))
+                }
+                Version::V2 => {
+                    let negotiated =
+                        preface::connect(&mut rio, &mut wio, Capabilities::CANCEL).await?;
+                    debug!(?negotiated, "Negotiated protocol");
+                    let cancel = negotiated.capabilities.contains(Capabilities::CANCEL);
+                    let write = FramedWrite::new(wio, v2::Codec::default());
+                    let read = FramedRead::new(rio, v2::Codec::default());
+                    span.in_scope(|| muxer::spawn_client(write, read, mux, cancel, stats.clone()))
+                }
+            }
+        };
+
+        Ok(Tcp {
+            tx: Dispatch::Framed(tx),
+            stats,
+        })
+    }
+}
+
+/// Spawns a task that issues requests one at a time, writing each request only once the previous
+/// request's response has been read.
+fn spawn_serial<W, R>(
+    mut write: W,
+    mut read: R,
+    settings: muxer::Settings,
+    stats: Arc<muxer::ConnStats>,
+) -> mpsc::Sender<(Spec, oneshot::Sender<Response>)>
+where
+    W: Sink<Frame<Message<Spec>>, Error = io::Error> + Send + Unpin + 'static,
+    R: Stream<Item = io::Result<Frame<Message<Response>>>> + Send + Unpin + 'static,
+{
+    let (tx, mut rx) = mpsc::channel(settings.buffer_capacity);
+
+    tokio::spawn(
+        async move {
+            let mut id = 0u64;
+            while let Some((spec, rsp_tx)) = rx.recv().await {
+                stats.dequeued();
+                stats.set_in_flight(1);
+                id = id.wrapping_add(1);
+                trace!(id, "Dispatching request");
+                write
+                    .send(Frame {
+                        id,
+                        value: Message::Data(spec),
+                    })
+                    .await?;
+
+                // Callers that stop waiting for a response cannot cancel it; the response must be
+                // read before the next request is written.
+                let rsp = match read.try_next().await? {
+                    Some(Frame {
+                        id: rsp_id,
+                        value: Message::Data(rsp),
+                    }) if rsp_id == id => rsp,
+                    Some(_) => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "Unexpected response",
+                        ))
+                    }
+                    None => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::ConnectionReset,
+                            "Server closed",
+                        ))
+                    }
+                };
+                stats.set_in_flight(0);
+                let _ = rsp_tx.send(rsp);
+            }
+            debug!("Client dropped its send handle");
+            Ok(())
+        }
+        .map(|res: io::Result<()>| {
+            if let Err(error) = res {
+                debug!(%error, "Connection failed");
+            }
+        })
+        .in_current_span(),
+    );
+
+    tx
+}
+
+// === impl Target ===
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.mode {
+            Mode::Multiplexed => write!(f, "tcp://{}", self.addr),
+            Mode::Serial => write!(f, "tcp+serial://{}", self.addr),
+            Mode::Echo => write!(f, "echo://{}", self.addr),
+        }
+    }
+}
+
+// === impl Settings ===
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            muxer: muxer::Settings::default(),
+            version: Version::default(),
+            banner: false,
+            first_write_delay: None,
+            watch: None,
+            bind: None,
+        }
+    }
+}
+
+// === impl Tcp ===
+
+impl Tcp {
+    /// Indicates whether the connection has been lost, so that it can no longer issue requests.
+    pub fn is_closed(&self) -> bool {
+        match self.tx {
+            Dispatch::Framed(ref tx) => tx.is_closed(),
+            Dispatch::Echo(ref tx) => tx.is_closed(),
+        }
     }
 }
 
 #[async_trait::async_trait]
 impl Ort for Tcp {
     async fn ort(&mut self, spec: Spec) -> Result<Reply, Error> {
-        let (tx, rx) = oneshot::channel();
-        self.tx
-            .send((spec, tx))
-            .await
-            .map_err(|_| io::Error::new(io::ErrorKind::NotConnected, "Muxer lost"))?;
-        rx.await.map_err(|_| {
-            io::Error::new(io::ErrorKind::NotConnected, "Muxer dropped response").into()
-        })
+        match self.tx {
+            Dispatch::Framed(ref tx) => dispatch(tx, &self.stats, spec).await?.into_result(),
+            Dispatch::Echo(ref tx) => Ok(dispatch(tx, &self.stats, spec).await??),
+        }
+    }
+}
+
+/// Sends a request to a connection's task and waits for its response.
+async fn dispatch<T>(
+    tx: &mpsc::Sender<(Spec, oneshot::Sender<T>)>,
+    stats: &muxer::ConnStats,
+    spec: Spec,
+) -> io::Result<T> {
+    let (rsp_tx, rsp_rx) = oneshot::channel();
+    // Wait for the muxer's buffer to have capacity so that the request is counted as queued only
+    // once it is buffered.
+    let permit = tx
+        .reserve()
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::NotConnected, "Muxer lost"))?;
+    stats.enqueued();
+    permit.send((spec, rsp_tx));
+    rsp_rx
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::NotConnected, "Muxer dropped response"))
+}
+
+/// Reads a banner line from the server.
+async fn read_banner<T: AsyncRead + Unpin>(io: &mut T) -> io::Result<String> {
+    let mut line = Vec::new();
+    while !line.ends_with(b"\r\n") {
+        if line.len() == MAX_BANNER_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Banner too long",
+            ));
+        }
+        line.push(io.read_u8().await?);
     }
+    line.truncate(line.len() - 2);
+    String::from_utf8(line).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
 }
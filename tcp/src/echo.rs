@@ -0,0 +1,222 @@
+//! An opaque echo protocol.
+//!
+//! Echo connections carry no preface or framing: the server writes back whatever it reads, so it
+//! can sit behind any TCP proxy. Clients send a payload for each request and verify that exactly
+//! the same bytes are echoed back.
+
+use crate::{muxer, next_or_pending};
+use bytes::Bytes;
+use drain::Watch as Drain;
+use futures::{prelude::*, stream::FuturesUnordered};
+use ort_core::{Error, Reply, Spec};
+use std::{net::SocketAddr, sync::Arc};
+use tokio::{
+    io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    sync::{mpsc, oneshot},
+    time,
+};
+use tracing::{debug, debug_span, error, info, trace, Instrument};
+
+/// Serves echo connections.
+#[derive(Clone, Debug)]
+pub struct Server {
+    latency: Option<time::Duration>,
+}
+
+const READ_BUFFER_LEN: usize = 16 * 1024;
+
+// === impl Server ===
+
+impl Server {
+    /// Creates a server that waits for `latency` before echoing each read.
+    pub fn new(latency: Option<time::Duration>) -> Self {
+        Self { latency }
+    }
+
+    pub async fn serve(self, addr: SocketAddr, drain: Drain) -> Result<(), Error> {
+        let mut serving = FuturesUnordered::new();
+        let lis = tokio::net::TcpListener::bind(addr).await?;
+        info!("Listening on {}", addr);
+
+        tokio::pin! {
+            let closed = drain.clone().signaled();
+        }
+
+        loop {
+            tokio::select! {
+                shutdown = (&mut closed) => {
+                    // Echo connections have no way to signal shutdown, so they are closed.
+                    debug!("Closing connections before shutdown");
+                    for conn in serving.iter() {
+                        conn.abort();
+                    }
+                    while serving.next().await.is_some() {}
+                    drop(shutdown);
+                    return Ok(());
+                }
+
+                res = next_or_pending(&mut serving) => match res {
+                    Ok(Ok(())) => {}
+                    Ok(Err(error)) => debug!(%error, "Connection failed"),
+                    Err(error) => error!(%error, "Connection task failed"),
+                },
+
+                acc = lis.accept() => match acc {
+                    Ok((sock, peer)) => {
+                        debug!(%peer, "Client connected");
+                        let conn = echo(sock, self.latency).instrument(debug_span!("conn", %peer));
+                        serving.push(tokio::spawn(conn));
+                    }
+                    Err(error) => error!(%error, "Failed to accept connection"),
+                },
+            }
+        }
+    }
+}
+
+/// Writes back everything read from `io` until the client closes its write side.
+async fn echo<T>(mut io: T, latency: Option<time::Duration>) -> io::Result<()>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut buf = vec![0u8; READ_BUFFER_LEN];
+    loop {
+        let n = io.read(&mut buf).await?;
+        if n == 0 {
+            debug!("Client closed");
+            return io.shutdown().await;
+        }
+        trace!(n, "Echoing");
+        if let Some(latency) = latency {
+            time::sleep(latency).await;
+        }
+        io.write_all(&buf[..n]).await?;
+    }
+}
+
+/// Spawns a task that sends each request's payload—`response_size` bytes—to an echo server and
+/// verifies that the same bytes are read back. Requests are issued one at a time.
+pub(crate) fn spawn_client<T>(
+    io: T,
+    settings: muxer::Settings,
+    stats: Arc<muxer::ConnStats>,
+) -> mpsc::Sender<(Spec, oneshot::Sender<io::Result<Reply>>)>
+where
+    T: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (tx, mut rx) = mpsc::channel(settings.buffer_capacity);
+
+    tokio::spawn(
+        async move {
+            let (mut rio, mut wio) = io::split(io);
+            let mut payloads = Payloads::default();
+            while let Some((spec, rsp_tx)) = rx.recv().await {
+                stats.dequeued();
+                stats.set_in_flight(1);
+                let sent = payloads.next(spec.response_size.max(1));
+                let mut echoed = vec![0u8; sent.len()];
+                trace!(len = sent.len(), "Sending payload");
+                // Read while writing so that large payloads do not fill both sides' buffers.
+                tokio::try_join!(wio.write_all(&sent), rio.read_exact(&mut echoed))?;
+                stats.set_in_flight(0);
+
+                let rsp = match sent.iter().zip(echoed.iter()).position(|(s, e)| s != e) {
+                    None => Ok(Reply {
+                        data: Bytes::from(echoed),
+                        ..Reply::default()
+                    }),
+                    Some(offset) => {
+                        debug!(offset, "Echoed bytes differ");
+                        Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("echoed bytes differ at offset {}", offset),
+                        ))
+                    }
+                };
+                let _ = rsp_tx.send(rsp);
+            }
+            debug!("Client dropped its send handle");
+            wio.shutdown().await
+        }
+        .map(|res: io::Result<()>| {
+            if let Err(error) = res {
+                debug!(%error, "Connection failed");
+            }
+        })
+        .in_current_span(),
+    );
+
+    tx
+}
+
+/// Generates payloads that differ from one another so that misdirected or reordered bytes are
+/// detected.
+#[derive(Debug, Default)]
+struct Payloads(u64);
+
+impl Payloads {
+    fn next(&mut self, len: usize) -> Vec<u8> {
+        self.0 = self.0.wrapping_add(1);
+        // An xorshift sequence seeded by the request's position on the connection.
+        let mut state = self.0.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn verifies_echoes() {
+        let (client, server) = io::duplex(100);
+        tokio::spawn(echo(server, None));
+        let tx = spawn_client(
+            client,
+            muxer::Settings::default(),
+            muxer::Metrics::default().register(([127, 0, 0, 1], 0).into()),
+        );
+        for response_size in [1, 1000] {
+            let (rsp_tx, rsp_rx) = oneshot::channel();
+            let spec = Spec {
+                response_size,
+                ..Spec::default()
+            };
+            tx.send((spec, rsp_tx)).await.expect("must send");
+            let reply = rsp_rx.await.expect("must respond").expect("must echo");
+            assert_eq!(reply.data.len(), response_size);
+        }
+
+        // A server that corrupts the stream is detected.
+        let (client, mut server) = io::duplex(100);
+        tokio::spawn(async move {
+            let mut buf = [0u8; 10];
+            server.read_exact(&mut buf).await.expect("must read");
+            buf[3] ^= 0xff;
+            server.write_all(&buf).await.expect("must write");
+        });
+        let tx = spawn_client(
+            client,
+            muxer::Settings::default(),
+            muxer::Metrics::default().register(([127, 0, 0, 1], 0).into()),
+        );
+        let (rsp_tx, rsp_rx) = oneshot::channel();
+        let spec = Spec {
+            response_size: 10,
+            ..Spec::default()
+        };
+        tx.send((spec, rsp_tx)).await.expect("must send");
+        let error = rsp_rx
+            .await
+            .expect("must respond")
+            .expect_err("must detect corruption");
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -1,20 +1,39 @@
 #![deny(warnings, rust_2018_idioms)]
 
 pub mod client;
+pub mod echo;
 pub mod muxer;
 pub mod preface;
 pub mod server;
+pub mod throughput;
+mod v2;
 
 use bytes::{Buf, BufMut, BytesMut};
-use ort_core::{Reply, Spec};
+use ort_core::{limit::Overloaded, Failure, Reply, Spec};
 use tokio::{io, time};
 use tokio_util::codec::{Decoder, Encoder, LengthDelimitedCodec};
 
 #[derive(Default)]
 struct SpecCodec(());
 
+/// Encodes version 1 replies as length-delimited frames containing only the reply's data. Errors
+/// cannot be expressed, so they fail the connection.
 struct ReplyCodec(LengthDelimitedCodec);
 
+/// A response to a single request. Errors fail only their request, leaving the connection usable.
+#[derive(Clone, Debug, PartialEq)]
+enum Response {
+    Reply(Reply),
+    Failure(Failure),
+    Overloaded,
+    /// The server failed to serve the request, as described by the message.
+    Error(String),
+}
+
+/// An error that a server reported while serving a single request.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ServerError(String);
+
 // === impl SpecCodec ===
 
 impl Decoder for SpecCodec {
@@ -30,6 +49,7 @@ impl Decoder for SpecCodec {
         Ok(Some(Spec {
             latency: time::Duration::from_millis(ms as u64),
             response_size: sz as usize,
+            ..Spec::default()
         }))
     }
 }
@@ -58,26 +78,66 @@ impl Default for ReplyCodec {
 }
 
 impl Decoder for ReplyCodec {
-    type Item = Reply;
+    type Item = Response;
     type Error = io::Error;
 
-    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Reply>, io::Error> {
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Response>, io::Error> {
         match self.0.decode(src)? {
             None => Ok(None),
-            Some(buf) => Ok(Some(Reply { data: buf.freeze() })),
+            Some(buf) => Ok(Some(Response::Reply(Reply {
+                data: buf.freeze(),
+                ..Reply::default()
+            }))),
         }
     }
 }
 
-impl Encoder<Reply> for ReplyCodec {
+impl Encoder<Response> for ReplyCodec {
     type Error = io::Error;
 
-    fn encode(&mut self, Reply { data }: Reply, dst: &mut BytesMut) -> io::Result<()> {
-        self.0.encode(data, dst)?;
-        Ok(())
+    fn encode(&mut self, rsp: Response, dst: &mut BytesMut) -> io::Result<()> {
+        match rsp {
+            Response::Reply(Reply { data, .. }) => self.0.encode(data, dst),
+            Response::Failure(_) | Response::Overloaded | Response::Error(_) => {
+                Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "version 1 connections cannot express errors",
+                ))
+            }
+        }
+    }
+}
+
+// === impl Response ===
+
+impl Response {
+    fn into_result(self) -> Result<Reply, ort_core::Error> {
+        match self {
+            Response::Reply(reply) => Ok(reply),
+            Response::Failure(failure) => Err(failure.into()),
+            Response::Overloaded => Err(Overloaded::default().into()),
+            Response::Error(message) => Err(ServerError(message).into()),
+        }
     }
 }
 
+// === impl ServerError ===
+
+impl ServerError {
+    /// Describes the server's error.
+    pub fn message(&self) -> &str {
+        &self.0
+    }
+}
+
+impl std::fmt::Display for ServerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "server error: {}", self.0)
+    }
+}
+
+impl std::error::Error for ServerError {}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -88,10 +148,12 @@ mod tests {
         let spec0 = Spec {
             latency: time::Duration::from_millis(1),
             response_size: 3,
+            ..Spec::default()
         };
         let spec1 = Spec {
             latency: time::Duration::from_millis(2),
             response_size: 4,
+            ..Spec::default()
         };
 
         let mut buf = BytesMut::with_capacity(100);
@@ -117,18 +179,21 @@ mod tests {
 
     #[tokio::test]
     async fn roundtrip_reply() {
-        let reply0 = Reply {
+        let reply0 = Response::Reply(Reply {
             data: Bytes::from_static(b"abcdef"),
-        };
-        let reply1 = Reply {
+            ..Reply::default()
+        });
+        let reply1 = Response::Reply(Reply {
             data: Bytes::from_static(b"ghijkl"),
-        };
+            ..Reply::default()
+        });
 
         let mut buf = BytesMut::with_capacity(100);
 
         let mut enc = ReplyCodec::default();
         enc.encode(reply0.clone(), &mut buf).expect("must encode");
         enc.encode(reply1.clone(), &mut buf).expect("must encode");
+        assert!(enc.encode(Response::Overloaded, &mut buf).is_err());
 
         let mut dec = ReplyCodec::default();
         assert_eq!(
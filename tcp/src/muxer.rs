@@ -2,13 +2,20 @@ use crate::next_or_pending;
 use bytes::{Buf, BufMut, BytesMut};
 use drain::Watch as Drain;
 use futures::{prelude::*, stream::FuturesUnordered};
-use std::collections::HashMap;
+use std::{
+    collections::{HashMap, VecDeque},
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, Weak,
+    },
+};
 use tokio::{
     io,
     sync::{mpsc, oneshot},
 };
 use tokio_util::codec::{Decoder, Encoder};
-use tracing::{debug, debug_span, error, info, trace, Instrument};
+use tracing::{debug, debug_span, error, trace, Instrument};
 
 #[derive(Debug)]
 pub struct Frame<T> {
@@ -16,6 +23,53 @@ pub struct Frame<T> {
     pub value: T,
 }
 
+/// The content of a frame.
+///
+/// Clients send `Cancel` when a request's caller is no longer waiting for its response. Servers
+/// stop serving the request and send `Cancel` in place of its response so that the request ID may
+/// be released. When encoded by `FramedEncode`, cancellation is indicated by the high bit of the
+/// frame's ID; a canceled frame has no content.
+#[derive(Debug, PartialEq)]
+pub enum Message<T> {
+    Data(T),
+    Cancel,
+}
+
+const CANCEL: u64 = 1 << 63;
+
+/// Limits the requests on a multiplexed connection.
+#[derive(Copy, Clone, Debug)]
+pub struct Settings {
+    /// The number of requests that may wait to be dispatched. Once it is reached, callers wait.
+    pub buffer_capacity: usize,
+    /// The number of requests that may be in flight at once. Further requests wait to be
+    /// dispatched: clients stop writing requests and servers hold them back, reading at most
+    /// `buffer_capacity` of them so that cancelations are still read.
+    pub max_in_flight: usize,
+}
+
+/// Tracks the requests on each open multiplexed connection.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    conns: Mutex<Vec<Weak<ConnStats>>>,
+}
+
+/// The requests on a single connection.
+#[derive(Debug)]
+pub struct ConnStats {
+    addr: SocketAddr,
+    queued: AtomicUsize,
+    in_flight: AtomicUsize,
+}
+
+/// Allocates request IDs. IDs wrap once they are exhausted, skipping those that are still in
+/// flight.
+#[derive(Debug)]
+struct Ids {
+    next: u64,
+    max: u64,
+}
+
 #[derive(Default, Debug)]
 pub struct FramedEncode<E> {
     inner: E,
@@ -33,51 +87,54 @@ enum DecodeState {
     Head { id: u64 },
 }
 
+/// Spawns a task that multiplexes requests over a connection. When `cancel` is set, the server is
+/// notified of requests whose callers stop waiting for their responses.
+///
+/// Callers must count each request that they send in `stats` as queued; the muxer counts it as
+/// in flight once it is dispatched.
 pub fn spawn_client<Req, Rsp, W, R>(
     mut write: W,
     mut read: R,
-    buffer_capacity: usize,
+    settings: Settings,
+    cancel: bool,
+    stats: Arc<ConnStats>,
 ) -> mpsc::Sender<(Req, oneshot::Sender<Rsp>)>
 where
     Req: Send + 'static,
     Rsp: Send + 'static,
-    W: Sink<Frame<Req>, Error = io::Error> + Send + Unpin + 'static,
-    R: Stream<Item = io::Result<Frame<Rsp>>> + Send + Unpin + 'static,
+    W: Sink<Frame<Message<Req>>, Error = io::Error> + Send + Unpin + 'static,
+    R: Stream<Item = io::Result<Frame<Message<Rsp>>>> + Send + Unpin + 'static,
 {
-    let (req_tx, mut req_rx) = mpsc::channel(buffer_capacity);
+    let (req_tx, mut req_rx) = mpsc::channel(settings.buffer_capacity);
 
     tokio::spawn(
         async move {
-            let mut next_id = 1u64;
+            let mut ids = Ids::default();
             let mut in_flight = HashMap::<u64, oneshot::Sender<Rsp>>::new();
+            // Forwards each response to its caller, yielding the request's ID if the caller
+            // stops waiting for it.
+            let mut forwarding = FuturesUnordered::new();
 
             loop {
-                if next_id == std::u64::MAX {
-                    info!("Client exhausted request IDs");
-                    break;
-                }
+                stats.set_in_flight(in_flight.len());
 
                 tokio::select! {
-                    // Read requests from the stream and write them on the socket.
-                    // Stash the response oneshot for when the response is read.
-                    req = req_rx.recv() => match req {
+                    // Read requests from the stream and write them on the socket, unless too many
+                    // requests are in flight. Stash the response oneshot for when the response is
+                    // read.
+                    req = req_rx.recv(), if in_flight.len() < settings.max_in_flight => match req {
                         Some((value, rsp_tx)) => {
-                            let id = next_id;
-                            next_id += 1;
+                            stats.dequeued();
+                            let id = ids.next(&in_flight);
                             trace!(id, "Dispatching request");
-                            let f = in_flight.entry(id);
-                            if let std::collections::hash_map::Entry::Occupied(_) = f {
-                                error!(id, "Request ID already in-flight");
-                                return Err(io::Error::new(
-                                    io::ErrorKind::InvalidInput,
-                                    "Request ID is already in-flight",
-                                ));
-                            }
+                            let value = Message::Data(value);
                             if let Err(error) = write.send(Frame { id, value }).await {
                                 error!(id, %error, "Failed to write response");
                                 return Err(error);
                             }
-                            f.or_insert(rsp_tx);
+                            let (fwd_tx, fwd_rx) = oneshot::channel();
+                            in_flight.insert(id, fwd_tx);
+                            forwarding.push(forward(id, fwd_rx, rsp_tx));
                         }
                         None => {
                             debug!("Client dropped its send handle");
@@ -85,20 +142,22 @@ where
                         }
                     },
 
+                    // Notify the server when a caller stops waiting for a response.
+                    id = next_or_pending(&mut forwarding) => if let Some(id) = id.filter(|_| cancel) {
+                        trace!(id, "Canceling request");
+                        let value = Message::Cancel;
+                        if let Err(error) = write.send(Frame { id, value }).await {
+                            error!(id, %error, "Failed to write cancelation");
+                            return Err(error);
+                        }
+                    },
+
                     // Read responses from the socket and send them back to the
                     // client.
                     rsp = read.try_next() => match rsp? {
                         Some(Frame { id, value }) => {
                             trace!(id, "Dispatching response");
-                            match in_flight.remove(&id) {
-                                Some(tx) => {
-                                    let _ = tx.send(value);
-                                }
-                                None => return Err(io::Error::new(
-                                    io::ErrorKind::InvalidInput,
-                                    "Response for unknown request",
-                                )),
-                            }
+                            dispatch(&mut in_flight, id, value)?;
                         }
                         None => {
                             debug!(in_flight=in_flight.len(), "Server closed");
@@ -120,20 +179,12 @@ where
             // We shan't be sending any more requests. Keep reading
             // responses, though.
             drop((req_rx, write));
+            tokio::spawn(async move { while forwarding.next().await.is_some() {} });
 
             // Satisfy remaining responses.
             while let Some(Frame { id, value }) = read.try_next().await? {
-                match in_flight.remove(&id) {
-                    Some(tx) => {
-                        let _ = tx.send(value);
-                    }
-                    None => {
-                        return Err(io::Error::new(
-                            io::ErrorKind::InvalidInput,
-                            "Response for unknown request",
-                        ));
-                    }
-                }
+                dispatch(&mut in_flight, id, value)?;
+                stats.set_in_flight(in_flight.len());
             }
             if !in_flight.is_empty() {
                 return Err(io::Error::new(
@@ -150,91 +201,193 @@ where
     req_tx
 }
 
+/// Forwards a response to its caller. If the caller stops waiting for the response, the request's
+/// ID is returned so that the request may be canceled.
+async fn forward<Rsp>(
+    id: u64,
+    rsp_rx: oneshot::Receiver<Rsp>,
+    mut rsp_tx: oneshot::Sender<Rsp>,
+) -> Option<u64> {
+    let rsp = tokio::select! {
+        rsp = rsp_rx => rsp,
+        _ = rsp_tx.closed() => return Some(id),
+    };
+    // If the request was canceled by the server, there is no response to forward.
+    if let Ok(rsp) = rsp {
+        let _ = rsp_tx.send(rsp);
+    }
+    None
+}
+
+/// Completes an in-flight request with a response read from the server.
+fn dispatch<Rsp>(
+    in_flight: &mut HashMap<u64, oneshot::Sender<Rsp>>,
+    id: u64,
+    value: Message<Rsp>,
+) -> io::Result<()> {
+    let tx = in_flight.remove(&id).ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidInput, "Response for unknown request")
+    })?;
+    match value {
+        Message::Data(value) => {
+            let _ = tx.send(value);
+        }
+        Message::Cancel => trace!(id, "Request canceled"),
+    }
+    Ok(())
+}
+
 type Channel<Req, Rsp> = mpsc::Receiver<(Req, oneshot::Sender<Rsp>)>;
 type JoinHandle = tokio::task::JoinHandle<io::Result<()>>;
 
+/// Spawns a task that reads requests from a connection and writes their responses. Requests are
+/// served by reading from the returned channel. When `cancel` is set, the client understands
+/// cancelations, so requests that are not served are canceled; otherwise they are dropped when the
+/// connection closes.
 pub fn spawn_server<Req, Rsp, R, W>(
     mut read: R,
     mut write: W,
     drain: Drain,
-    buffer_capacity: usize,
+    settings: Settings,
+    cancel: bool,
+    stats: Arc<ConnStats>,
 ) -> (Channel<Req, Rsp>, JoinHandle)
 where
     Req: Send + 'static,
     Rsp: Send + 'static,
-    R: Stream<Item = io::Result<Frame<Req>>> + Send + Unpin + 'static,
-    W: Sink<Frame<Rsp>, Error = io::Error> + Send + Unpin + 'static,
+    R: Stream<Item = io::Result<Frame<Message<Req>>>> + Send + Unpin + 'static,
+    W: Sink<Frame<Message<Rsp>>, Error = io::Error> + Send + Unpin + 'static,
 {
-    let (tx, rx) = mpsc::channel(buffer_capacity);
+    let (tx, rx) = mpsc::channel(settings.buffer_capacity);
 
     let handle = tokio::spawn(async move {
         tokio::pin! {
             let closed = drain.signaled();
         }
 
-        let mut last_id = 0u64;
         let mut in_flight = FuturesUnordered::new();
+        // Cancels in-flight requests by ID. When a request is canceled, its response receiver is
+        // dropped so that the service may stop serving it. Entries are held until the request's
+        // response or cancelation is written so that its ID is not reused while it is in flight.
+        let mut cancels = HashMap::<u64, Option<oneshot::Sender<()>>>::new();
+        // Requests that are held back while too many are in flight, in the order they were read.
+        // Frames continue to be read so that cancelations are handled promptly. A held request
+        // that is canceled is removed from `held_reqs`, leaving a stale ID in `held`.
+        let mut held = VecDeque::<u64>::new();
+        let mut held_reqs = HashMap::<u64, Req>::new();
+        let mut reading = true;
         loop {
+            while in_flight.len() < settings.max_in_flight {
+                let (id, value) = match held.pop_front() {
+                    Some(id) => match held_reqs.remove(&id) {
+                        Some(value) => (id, value),
+                        None => continue,
+                    },
+                    None => break,
+                };
+
+                trace!(id, "Dispatching request");
+                let (rsp_tx, rsp_rx) = oneshot::channel();
+                if tx.send((value, rsp_tx)).await.is_err() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::ConnectionAborted,
+                        "Lost service",
+                    ));
+                }
+                let (cancel_tx, cancel_rx) = oneshot::channel();
+                cancels.insert(id, Some(cancel_tx));
+                in_flight.push(async move {
+                    tokio::select! {
+                        rsp = rsp_rx => match rsp {
+                            Ok(value) => Ok(Frame { id, value: Message::Data(value) }),
+                            Err(_) => Err(io::Error::new(
+                                io::ErrorKind::ConnectionAborted,
+                                "Server dropped response",
+                            )),
+                        },
+                        Ok(()) = cancel_rx => Ok(Frame { id, value: Message::Cancel }),
+                    }
+                });
+            }
+
+            // Requests are only held while others are in flight.
+            if !reading && in_flight.is_empty() {
+                trace!("In-flight responses completed after client stream completed");
+                return Ok(());
+            }
+
+            stats.set_in_flight(in_flight.len());
+            stats.queued.store(
+                settings.buffer_capacity - tx.capacity() + held_reqs.len(),
+                Ordering::Relaxed,
+            );
+
             tokio::select! {
                 shutdown = (&mut closed) => {
                     debug!("Shutdown signaled; draining in-flight requests");
                     drop(read);
                     drop(tx);
-                    while let Some(Frame { id, value }) = in_flight.try_next().await? {
-                        trace!(id, "In-flight response completed");
-                        write.send(Frame { id, value }).await?;
+                    // Held requests were never dispatched, so they are canceled if the client
+                    // understands cancelations.
+                    for id in held.drain(..).filter(|id| held_reqs.remove(id).is_some()) {
+                        if cancel {
+                            trace!(id, "Canceling held request");
+                            write.send(Frame { id, value: Message::Cancel }).await?;
+                        } else {
+                            trace!(id, "Dropping held request");
+                        }
+                    }
+                    while let Some(frame) = in_flight.try_next().await? {
+                        trace!(id = frame.id, "In-flight response completed");
+                        write.send(frame).await?;
                     }
                     debug!("In-flight requests completed");
                     drop(shutdown);
                     return Ok(());
                 }
 
-                req = next_or_pending(&mut in_flight) => {
-                    let Frame { id, value } = req?;
-                    trace!(id, "In-flight response completed");
-                    if let Err(error) = write.send(Frame { id, value }).await {
+                rsp = next_or_pending(&mut in_flight) => {
+                    let frame = rsp?;
+                    trace!(id = frame.id, "In-flight response completed");
+                    cancels.remove(&frame.id);
+                    if let Err(error) = write.send(frame).await {
                         error!(%error, "Write failed");
                         return Err(error);
                     }
                 }
 
-                msg = read.try_next() => {
-                    let Frame { id, value } = match msg? {
-                        Some(f) => f,
-                        None => {
-                            trace!("Draining in-flight responses after client stream completed.");
-                            while let Some(Frame { id, value }) = in_flight.try_next().await? {
-                                trace!(id, "In-flight response completed");
-                                write.send(Frame { id, value }).await?;
+                // Stop reading once the held requests fill the buffer.
+                msg = read.try_next(), if reading && held.len() < settings.buffer_capacity => match msg? {
+                    Some(Frame { id, value: Message::Data(value) }) => {
+                        if cancels.contains_key(&id) || held_reqs.contains_key(&id) {
+                            return Err(io::Error::new(
+                                io::ErrorKind::InvalidInput,
+                                "Request ID is already in-flight",
+                            ));
+                        }
+                        held.push_back(id);
+                        held_reqs.insert(id, value);
+                    }
+                    // Cancelations cannot be acknowledged on connections that do not support them.
+                    Some(Frame { id, value: Message::Cancel }) if !cancel => {
+                        trace!(id, "Ignoring unsupported cancelation");
+                    }
+                    Some(Frame { id, value: Message::Cancel }) => {
+                        trace!(id, "Client canceled request");
+                        if held_reqs.remove(&id).is_some() {
+                            if let Err(error) = write.send(Frame { id, value: Message::Cancel }).await {
+                                error!(%error, "Write failed");
+                                return Err(error);
                             }
-                            return Ok(());
+                        } else if let Some(cancel) = cancels.get_mut(&id).and_then(Option::take) {
+                            let _ = cancel.send(());
                         }
-                    };
-
-                    if id <= last_id {
-                        return Err(io::Error::new(
-                            io::ErrorKind::InvalidInput,
-                            "Request ID too low",
-                        ));
                     }
-                    last_id = id;
-
-                    trace!(id, "Dispatching request");
-                    let (rsp_tx, rsp_rx) = oneshot::channel();
-                    if tx.send((value, rsp_tx)).await.is_err() {
-                        return Err(io::Error::new(
-                            io::ErrorKind::ConnectionAborted,
-                            "Lost service",
-                        ));
+                    None => {
+                        trace!("Client stream completed; draining in-flight requests");
+                        reading = false;
                     }
-                    in_flight.push(rsp_rx.map(move |v| match v {
-                        Ok(value) => Ok(Frame { id, value }),
-                        Err(_) => Err(io::Error::new(
-                            io::ErrorKind::ConnectionAborted,
-                            "Server dropped response",
-                        )),
-                    }));
-                }
+                },
             }
         }
     }.instrument(debug_span!("mux")));
@@ -242,6 +395,99 @@ where
     (rx, handle)
 }
 
+// === impl Settings ===
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            buffer_capacity: 100_000,
+            max_in_flight: 10_000,
+        }
+    }
+}
+
+// === impl Metrics ===
+
+impl Metrics {
+    /// Tracks a new connection, identified by its address.
+    pub fn register(&self, addr: SocketAddr) -> Arc<ConnStats> {
+        let stats = Arc::new(ConnStats {
+            addr,
+            queued: AtomicUsize::new(0),
+            in_flight: AtomicUsize::new(0),
+        });
+        self.conns
+            .lock()
+            .expect("lock must not be poisoned")
+            .push(Arc::downgrade(&stats));
+        stats
+    }
+
+    /// Returns the stats of each open connection.
+    pub fn conns(&self) -> Vec<Arc<ConnStats>> {
+        let mut conns = self.conns.lock().expect("lock must not be poisoned");
+        conns.retain(|c| c.strong_count() > 0);
+        conns.iter().filter_map(Weak::upgrade).collect()
+    }
+}
+
+// === impl ConnStats ===
+
+impl ConnStats {
+    /// The connection's remote address on servers and its local address on clients.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// The number of requests waiting to be dispatched.
+    pub fn queued(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
+    }
+
+    /// The number of requests that have been dispatched but not yet answered.
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::Relaxed)
+    }
+
+    /// Counts a request that was sent to a client muxer.
+    pub fn enqueued(&self) {
+        self.queued.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn dequeued(&self) {
+        self.queued.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn set_in_flight(&self, n: usize) {
+        self.in_flight.store(n, Ordering::Relaxed);
+    }
+}
+
+// === impl Ids ===
+
+impl Default for Ids {
+    fn default() -> Self {
+        Self {
+            next: 1,
+            max: CANCEL - 1,
+        }
+    }
+}
+
+impl Ids {
+    /// Returns the next ID that is not in flight. The caller must ensure that not all IDs are in
+    /// flight.
+    fn next<V>(&mut self, in_flight: &HashMap<u64, V>) -> u64 {
+        loop {
+            let id = self.next;
+            self.next = if id >= self.max { 1 } else { id + 1 };
+            if !in_flight.contains_key(&id) {
+                return id;
+            }
+        }
+    }
+}
+
 // === impl FramedDecode ===
 
 impl<D> From<D> for FramedDecode<D> {
@@ -260,16 +506,23 @@ impl<D: Default> Default for FramedDecode<D> {
 }
 
 impl<D: Decoder> Decoder for FramedDecode<D> {
-    type Item = Frame<D::Item>;
+    type Item = Frame<Message<D::Item>>;
     type Error = D::Error;
 
-    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Frame<D::Item>>, D::Error> {
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Frame<Message<D::Item>>>, D::Error> {
         let id = match self.state {
             DecodeState::Init => {
                 if src.len() < 8 {
                     return Ok(None);
                 }
-                src.get_u64()
+                let id = src.get_u64();
+                if id & CANCEL != 0 {
+                    return Ok(Some(Frame {
+                        id: id & !CANCEL,
+                        value: Message::Cancel,
+                    }));
+                }
+                id
             }
             DecodeState::Head { id } => {
                 self.state = DecodeState::Init;
@@ -278,7 +531,10 @@ impl<D: Decoder> Decoder for FramedDecode<D> {
         };
 
         match self.inner.decode(src)? {
-            Some(value) => Ok(Some(Frame { id, value })),
+            Some(value) => Ok(Some(Frame {
+                id,
+                value: Message::Data(value),
+            })),
             None => {
                 self.state = DecodeState::Head { id };
                 Ok(None)
@@ -295,17 +551,25 @@ impl<E> From<E> for FramedEncode<E> {
     }
 }
 
-impl<T, C: Encoder<T>> Encoder<Frame<T>> for FramedEncode<C> {
+impl<T, C: Encoder<T>> Encoder<Frame<Message<T>>> for FramedEncode<C> {
     type Error = C::Error;
 
     fn encode(
         &mut self,
-        Frame { id, value }: Frame<T>,
+        Frame { id, value }: Frame<Message<T>>,
         dst: &mut BytesMut,
     ) -> Result<(), C::Error> {
         dst.reserve(8);
-        dst.put_u64(id);
-        self.inner.encode(value, dst)
+        match value {
+            Message::Data(value) => {
+                dst.put_u64(id);
+                self.inner.encode(value, dst)
+            }
+            Message::Cancel => {
+                dst.put_u64(id | CANCEL);
+                Ok(())
+            }
+        }
     }
 }
 
@@ -315,6 +579,63 @@ mod tests {
     use bytes::Bytes;
     use tokio_util::codec::LengthDelimitedCodec;
 
+    #[test]
+    fn ids_wrap() {
+        let mut ids = Ids { next: 1, max: 3 };
+        let mut in_flight = HashMap::new();
+        for expected in [1, 2, 3, 1] {
+            assert_eq!(ids.next(&in_flight), expected);
+        }
+        in_flight.insert(2, ());
+        in_flight.insert(3, ());
+        assert_eq!(ids.next(&in_flight), 1);
+        assert_eq!(ids.next(&in_flight), 1);
+    }
+
+    #[tokio::test]
+    async fn reads_cancelations_at_in_flight_limit() {
+        let (req_tx, req_rx) = futures::channel::mpsc::unbounded();
+        let (rsp_tx, mut rsp_rx) = futures::channel::mpsc::unbounded();
+        let (_close, closed) = drain::channel();
+        let settings = Settings {
+            buffer_capacity: 10,
+            max_in_flight: 1,
+        };
+        let stats = Metrics::default().register(([127, 0, 0, 1], 0).into());
+        let (mut reqs, _mux) = spawn_server::<u32, u32, _, _>(
+            req_rx,
+            rsp_tx.sink_map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe)),
+            closed,
+            settings,
+            true,
+            stats,
+        );
+        let send = |id, value| {
+            req_tx
+                .unbounded_send(Ok(Frame { id, value }))
+                .expect("must send")
+        };
+
+        send(1, Message::Data(10));
+        send(2, Message::Data(20));
+        let (req, mut rsp) = reqs.recv().await.expect("must dispatch");
+        assert_eq!(req, 10);
+
+        // The second request is held back, but its cancelation is read.
+        send(2, Message::Cancel);
+        let frame = rsp_rx.next().await.expect("must write");
+        assert_eq!((frame.id, frame.value), (2, Message::Cancel));
+
+        send(1, Message::Cancel);
+        rsp.closed().await;
+        let frame = rsp_rx.next().await.expect("must write");
+        assert_eq!((frame.id, frame.value), (1, Message::Cancel));
+
+        send(3, Message::Data(30));
+        let (req, _rsp) = reqs.recv().await.expect("must dispatch");
+        assert_eq!(req, 30);
+    }
+
     #[tokio::test]
     async fn roundtrip() {
         let b0 = Bytes::from_static(b"abcde");
@@ -326,7 +647,7 @@ mod tests {
         enc.encode(
             Frame {
                 id: 1,
-                value: b0.clone(),
+                value: Message::Data(b0.clone()),
             },
             &mut buf,
         )
@@ -334,7 +655,15 @@ mod tests {
         enc.encode(
             Frame {
                 id: 2,
-                value: b1.clone(),
+                value: Message::Cancel,
+            },
+            &mut buf,
+        )
+        .expect("must encode");
+        enc.encode(
+            Frame {
+                id: 3,
+                value: Message::Data(b1.clone()),
             },
             &mut buf,
         )
@@ -349,9 +678,15 @@ mod tests {
             .decode(&mut buf)
             .expect("must decode")
             .expect("must decode");
+        let d2 = dec
+            .decode(&mut buf)
+            .expect("must decode")
+            .expect("must decode");
         assert_eq!(d0.id, 1);
-        assert_eq!(d0.value.freeze(), b0);
+        assert_eq!(d0.value, Message::Data(b0.into()));
         assert_eq!(d1.id, 2);
-        assert_eq!(d1.value.freeze(), b1);
+        assert_eq!(d1.value, Message::Cancel);
+        assert_eq!(d2.id, 3);
+        assert_eq!(d2.value, Message::Data(b1.into()));
     }
 }
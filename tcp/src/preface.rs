@@ -1,8 +1,21 @@
 use bytes::{Buf, BufMut, BytesMut};
-use tokio::io;
+use std::str::FromStr;
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio_util::codec::{Decoder, Encoder};
 
-static PREFACE: &[u8] = b"ort.olix0r.net/load\r\n\r\n";
+/// Starts version 1 connections.
+const PREFACE: &[u8] = b"ort.olix0r.net/load\r\n\r\n";
+
+/// Starts a versioned hello, which continues with the sender's decimal protocol version, `\r\n`,
+/// and its 4-byte capabilities.
+///
+/// Clients send a hello with the greatest version they support and wait for the server's hello
+/// before sending requests. Servers reply with the lesser of the client's version and the
+/// greatest version they support, and with the capabilities that both peers support.
+const HELLO: &[u8] = b"ort.olix0r.net/load/";
+
+/// The longest version that may be sent in a hello, including its `\r\n`.
+const MAX_VERSION_LEN: usize = 12;
 
 #[derive(Debug)]
 pub struct Codec<C> {
@@ -17,6 +30,33 @@ enum State {
     Prefaced,
 }
 
+/// A version of the wire protocol.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Version {
+    /// Connections start with a fixed preface. Requests carry only their latency in milliseconds
+    /// and their response size, each as 4 bytes, and replies carry only their data. Requests may
+    /// neither fail nor be canceled without failing the connection.
+    V1 = 1,
+    /// Connections start with a hello that negotiates capabilities. Frames carry a header that
+    /// describes their payload so that it may be extended. See the `v2` module.
+    V2 = 2,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct InvalidVersion(());
+
+/// Optional protocol features. Each peer advertises the capabilities it supports; only those
+/// supported by both peers are used.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Capabilities(u32);
+
+/// The outcome of a connection's negotiation.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Negotiated {
+    pub version: Version,
+    pub capabilities: Capabilities,
+}
+
 // === impl Codec ===
 
 impl<C> From<C> for Codec<C> {
@@ -82,6 +122,168 @@ impl<T, E: Encoder<T>> Encoder<T> for Codec<E> {
     }
 }
 
+/// Sends a client's hello and reads the server's, returning the negotiated version and
+/// capabilities.
+///
+/// Version 1 connections are not negotiated: their preface is written with the first request, so
+/// only version 2 clients should call this.
+pub async fn connect<R, W>(
+    rio: &mut R,
+    wio: &mut W,
+    capabilities: Capabilities,
+) -> io::Result<Negotiated>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    write_hello(wio, Version::V2, capabilities).await?;
+
+    let mut head = [0u8; HELLO.len()];
+    rio.read_exact(&mut head).await?;
+    if head != HELLO {
+        return Err(invalid("Invalid protocol header"));
+    }
+    let version = read_version(rio).await?;
+    if version != Version::V2 as u32 {
+        return Err(invalid("Unsupported protocol version"));
+    }
+    let accepted = Capabilities(rio.read_u32().await?);
+    Ok(Negotiated {
+        version: Version::V2,
+        capabilities: accepted.intersection(capabilities),
+    })
+}
+
+/// Reads a client's preface or hello. Versioned clients are sent the server's hello.
+pub async fn accept<R, W>(
+    rio: &mut R,
+    wio: &mut W,
+    capabilities: Capabilities,
+) -> io::Result<Negotiated>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    // The preface and the hello differ in the byte that follows their common prefix.
+    let mut head = [0u8; HELLO.len()];
+    rio.read_exact(&mut head).await?;
+    if head == PREFACE[..HELLO.len()] {
+        let mut rest = [0u8; PREFACE.len() - HELLO.len()];
+        rio.read_exact(&mut rest).await?;
+        if rest != PREFACE[HELLO.len()..] {
+            return Err(invalid("Invalid protocol header"));
+        }
+        return Ok(Negotiated {
+            version: Version::V1,
+            capabilities: Capabilities::default(),
+        });
+    }
+    if head != HELLO {
+        return Err(invalid("Invalid protocol header"));
+    }
+
+    // Versioned clients support at least version 2.
+    if read_version(rio).await? < Version::V2 as u32 {
+        return Err(invalid("Unsupported protocol version"));
+    }
+    let requested = Capabilities(rio.read_u32().await?);
+    let capabilities = requested.intersection(capabilities);
+    write_hello(wio, Version::V2, capabilities).await?;
+    Ok(Negotiated {
+        version: Version::V2,
+        capabilities,
+    })
+}
+
+async fn write_hello<W: AsyncWrite + Unpin>(
+    wio: &mut W,
+    version: Version,
+    capabilities: Capabilities,
+) -> io::Result<()> {
+    let mut buf = BytesMut::with_capacity(HELLO.len() + MAX_VERSION_LEN + 4);
+    buf.put(HELLO);
+    buf.put(format!("{}\r\n", version).as_bytes());
+    buf.put_u32(capabilities.0);
+    wio.write_all(&buf).await?;
+    wio.flush().await
+}
+
+async fn read_version<R: AsyncRead + Unpin>(rio: &mut R) -> io::Result<u32> {
+    let mut line = Vec::with_capacity(MAX_VERSION_LEN);
+    while !line.ends_with(b"\r\n") {
+        if line.len() == MAX_VERSION_LEN {
+            return Err(invalid("Invalid protocol version"));
+        }
+        line.push(rio.read_u8().await?);
+    }
+    std::str::from_utf8(&line[..line.len() - 2])
+        .ok()
+        .and_then(|v| v.parse::<u32>().ok())
+        .ok_or_else(|| invalid("Invalid protocol version"))
+}
+
+fn invalid(msg: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+// === impl Version ===
+
+impl Default for Version {
+    fn default() -> Self {
+        Self::V2
+    }
+}
+
+impl std::fmt::Display for Version {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", *self as u32)
+    }
+}
+
+impl FromStr for Version {
+    type Err = InvalidVersion;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "1" => Ok(Self::V1),
+            "2" => Ok(Self::V2),
+            _ => Err(InvalidVersion(())),
+        }
+    }
+}
+
+impl std::fmt::Display for InvalidVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid protocol version; expected 1 or 2")
+    }
+}
+
+impl std::error::Error for InvalidVersion {}
+
+// === impl Capabilities ===
+
+impl Capabilities {
+    /// Requests may be canceled before their responses are sent.
+    pub const CANCEL: Self = Self(1 << 0);
+
+    /// The connection carries one request at a time, and requests are served strictly in order.
+    /// Clients advertise it only when they issue requests serially.
+    pub const SERIAL: Self = Self(1 << 1);
+
+    /// All of the capabilities that this implementation supports.
+    pub fn supported() -> Self {
+        Self(Self::CANCEL.0 | Self::SERIAL.0)
+    }
+
+    pub fn contains(&self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    fn intersection(self, other: Self) -> Self {
+        Self(self.0 & other.0)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -111,4 +313,39 @@ mod tests {
         assert_eq!(d0.freeze(), b0);
         assert_eq!(d1.freeze(), b1);
     }
+
+    #[tokio::test]
+    async fn negotiate() {
+        let (client, server) = io::duplex(100);
+        let (mut crio, mut cwio) = io::split(client);
+        let (mut srio, mut swio) = io::split(server);
+        let (c, s) = tokio::join!(
+            connect(&mut crio, &mut cwio, Capabilities::supported()),
+            accept(&mut srio, &mut swio, Capabilities::default()),
+        );
+        let expected = Negotiated {
+            version: Version::V2,
+            capabilities: Capabilities::default(),
+        };
+        assert_eq!(c.expect("must connect"), expected);
+        assert_eq!(s.expect("must accept"), expected);
+
+        // Version 1 clients write the preface with their first request.
+        let (mut client, server) = io::duplex(100);
+        let (mut srio, mut swio) = io::split(server);
+        let mut buf = BytesMut::new();
+        Codec::from(LengthDelimitedCodec::default())
+            .encode(Bytes::from_static(b"abcde"), &mut buf)
+            .expect("must encode");
+        client.write_all(&buf).await.expect("must write");
+        let s = accept(&mut srio, &mut swio, Capabilities::supported())
+            .await
+            .expect("must accept");
+        assert_eq!(s.version, Version::V1);
+        let mut dec = LengthDelimitedCodec::default();
+        let mut buf = BytesMut::new();
+        while dec.decode(&mut buf).expect("must decode").is_none() {
+            srio.read_buf(&mut buf).await.expect("must read");
+        }
+    }
 }
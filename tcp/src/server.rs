@@ -1,28 +1,70 @@
-use crate::{muxer, next_or_pending, preface, ReplyCodec, SpecCodec};
+use crate::{
+    muxer::{self, Frame, Message},
+    next_or_pending,
+    preface::{self, Capabilities, Version},
+    v2, ReplyCodec, Response, SpecCodec,
+};
+use bytes::Bytes;
 use drain::Watch as Drain;
 use futures::{prelude::*, stream::FuturesUnordered};
-use ort_core::{Error, Ort};
-use std::net::SocketAddr;
+use ort_core::{
+    fault::{FaultIo, Injector},
+    limit::Overloaded,
+    ConnMetrics, CountedIo, Error, Failure, Ort, PacedIo, Pacing, Reply, Spec,
+};
+use std::{net::SocketAddr, sync::Arc};
+use tokio::io::{self, AsyncRead, AsyncWrite, AsyncWriteExt};
 use tokio_util::codec::{FramedRead, FramedWrite};
 use tracing::{debug, debug_span, error, trace, Instrument};
 
 pub struct Server<O> {
     inner: O,
-    buffer_capacity: usize,
+    instance: Option<String>,
+    banner: Option<Bytes>,
+    muxer: muxer::Settings,
+    pacing: Option<Pacing>,
+    conns: Arc<ConnMetrics>,
+    metrics: Arc<muxer::Metrics>,
 }
 
 impl<O: Ort> Server<O> {
-    pub fn new(inner: O) -> Self {
+    /// Creates a server that stamps `instance` into each reply. When a banner is configured, it is
+    /// written, followed by `\r\n`, as soon as each connection is accepted. When pacing is
+    /// configured, all writes on each connection are trickled. Connections are recorded in
+    /// `conns` and each connection's requests are recorded in `metrics`.
+    pub fn new(
+        inner: O,
+        instance: Option<String>,
+        banner: Option<String>,
+        muxer: muxer::Settings,
+        pacing: Option<Pacing>,
+        conns: Arc<ConnMetrics>,
+        metrics: Arc<muxer::Metrics>,
+    ) -> Self {
         Self {
             inner,
-            buffer_capacity: 100_000,
+            instance,
+            banner: banner.map(|b| Bytes::from(format!("{}\r\n", b))),
+            muxer,
+            pacing,
+            conns,
+            metrics,
         }
     }
 
     pub async fn serve(self, addr: SocketAddr, drain: Drain) -> Result<(), Error> {
-        let mut serving = FuturesUnordered::new();
         let lis = tokio::net::TcpListener::bind(addr).await?;
-        tracing::info!("Listening on {}", addr);
+        self.serve_listener(lis, drain).await
+    }
+
+    /// Serves connections accepted by an already-bound listener.
+    pub async fn serve_listener(
+        self,
+        lis: tokio::net::TcpListener,
+        drain: Drain,
+    ) -> Result<(), Error> {
+        let mut serving = FuturesUnordered::new();
+        tracing::info!("Listening on {}", lis.local_addr()?);
 
         tokio::pin! {
             let closed = drain.clone().signaled();
@@ -37,13 +79,20 @@ impl<O: Ort> Server<O> {
                     return Ok(());
                 }
 
-                _ = next_or_pending(&mut serving) => {}
+                res = next_or_pending(&mut serving) => match res {
+                    Ok(Ok(())) => {}
+                    Ok(Err(error)) => debug!(%error, "Connection failed"),
+                    Err(error) => error!(%error, "Connection task failed"),
+                },
 
                 acc = lis.accept() => {
-                    let ((rio, wio), peer) = match acc {
+                    let (mut io, injector, peer) = match acc {
                         Ok((sock, peer)) => {
                             debug!(%peer, "Client connected");
-                            (sock.into_split(), peer)
+                            let io = FaultIo::new(sock);
+                            let injector = io.injector().clone();
+                            let io = CountedIo::new(PacedIo::new(io, self.pacing), self.conns.clone());
+                            (io, injector, peer)
                         }
                         Err(error) => {
                             error!(%error, "Failed to accept connection");
@@ -51,72 +100,274 @@ impl<O: Ort> Server<O> {
                         }
                     };
 
-                    let span = debug_span!("conn", %peer);
-
-                    let decode = preface::Codec::from(muxer::FramedDecode::from(SpecCodec::default()));
-                    let encode = muxer::FramedEncode::from(ReplyCodec::default());
-                    let (mut rx, muxer) = span.in_scope(|| muxer::spawn_server(
-                        FramedRead::new(rio, decode),
-                        FramedWrite::new(wio, encode),
-                        drain.clone(),
-                        self.buffer_capacity,
-                    ));
-
+                    let banner = self.banner.clone();
                     let srv = self.inner.clone();
+                    let instance = self.instance.clone();
                     let drain = drain.clone();
-
-                    let server = tokio::spawn(async move {
-                        tokio::pin! {
-                            let closed = drain.signaled();
+                    let muxer = self.muxer;
+                    let stats = self.metrics.register(peer);
+                    let conn = async move {
+                        if let Some(banner) = banner {
+                            io.write_all(&banner).await?;
+                            trace!("Wrote banner");
                         }
+                        serve_conn(io, srv, injector, instance, drain, muxer, stats).await
+                    };
+                    serving.push(tokio::spawn(conn.instrument(debug_span!("conn", %peer))));
+                }
+            }
+        }
+    }
+}
 
-                        let mut in_flight = FuturesUnordered::new();
-                        loop {
-                            tokio::select! {
-                                shutdown = (&mut closed) => {
-                                    debug!("Draining inflight requests before shutdown");
-                                    drop(rx);
-                                    while let Some(()) = in_flight.next().await {};
-                                    drop(shutdown);
-                                    return;
-                                }
-
-                                _ = next_or_pending(&mut in_flight) => {
-                                    trace!("Response completed");
-                                }
-
-                                next = rx.recv() => match next {
-                                    None => {
-                                        debug!("Client closed; draining in-flight requests");
-                                        while let Some(()) = in_flight.next().await {};
+/// Negotiates a connection's protocol and serves its requests.
+async fn serve_conn<O, T>(
+    io: T,
+    srv: O,
+    injector: Injector,
+    instance: Option<String>,
+    drain: Drain,
+    settings: muxer::Settings,
+    stats: Arc<muxer::ConnStats>,
+) -> Result<(), Error>
+where
+    O: Ort,
+    T: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (mut rio, mut wio) = tokio::io::split(io);
+    let negotiated = tokio::select! {
+        res = preface::accept(&mut rio, &mut wio, Capabilities::supported()) => res?,
+        shutdown = drain.clone().signaled() => {
+            debug!("Closing connection before protocol negotiation");
+            drop(shutdown);
+            return Ok(());
+        }
+    };
+    debug!(?negotiated, "Negotiated protocol");
+
+    if negotiated.capabilities.contains(Capabilities::SERIAL) {
+        let read = FramedRead::new(rio, v2::Codec::default());
+        let write = FramedWrite::new(wio, v2::Codec::default());
+        return serve_serial(read, write, srv, injector, instance, drain, stats).await;
+    }
+
+    let cancel = negotiated.capabilities.contains(Capabilities::CANCEL);
+    let (mut rx, muxer) = match negotiated.version {
+        Version::V1 => muxer::spawn_server(
+            FramedRead::new(rio, muxer::FramedDecode::from(SpecCodec::default())),
+            FramedWrite::new(wio, muxer::FramedEncode::from(ReplyCodec::default())),
+            drain.clone(),
+            settings,
+            cancel,
+            stats,
+        ),
+        Version::V2 => muxer::spawn_server(
+            FramedRead::new(rio, v2::Codec::default()),
+            FramedWrite::new(wio, v2::Codec::default()),
+            drain.clone(),
+            settings,
+            cancel,
+            stats,
+        ),
+    };
+
+    let server = tokio::spawn(
+        async move {
+            tokio::pin! {
+                let closed = drain.signaled();
+            }
+
+            let mut in_flight = FuturesUnordered::new();
+            loop {
+                tokio::select! {
+                    shutdown = (&mut closed) => {
+                        debug!("Draining inflight requests before shutdown");
+                        drop(rx);
+                        while let Some(()) = in_flight.next().await {};
+                        drop(shutdown);
+                        return;
+                    }
+
+                    _ = next_or_pending(&mut in_flight) => {
+                        trace!("Response completed");
+                    }
+
+                    next = rx.recv() => match next {
+                        None => {
+                            debug!("Client closed; draining in-flight requests");
+                            while let Some(()) = in_flight.next().await {};
+                            return;
+                        }
+                        Some((spec, mut tx)) => {
+                            let mut srv = srv.clone();
+                            let injector = injector.clone();
+                            let instance = instance.clone();
+                            let mut h = tokio::spawn(async move {
+                                let res = srv.ort(spec).await;
+                                response(res, &injector, spec.response_size, instance)
+                            }.instrument(debug_span!("req")));
+                            in_flight.push(async move {
+                                // Stop serving the request if the client cancels it.
+                                let rsp = tokio::select! {
+                                    res = &mut h => res.unwrap_or_else(|error| {
+                                        error!(%error, "Task failed");
+                                        Response::Error("request task failed".to_string())
+                                    }),
+                                    _ = tx.closed() => {
+                                        debug!("Request canceled");
+                                        h.abort();
                                         return;
                                     }
-                                    Some((spec, tx)) => {
-                                        let mut srv = srv.clone();
-                                        let h = tokio::spawn(async move {
-                                            let reply = srv.ort(spec).await?;
-                                            let _ = tx.send(reply);
-                                            Ok::<(), Error>(())
-                                        }.instrument(debug_span!("req")));
-                                        in_flight.push(h.map(|res| match res {
-                                            Ok(Ok(())) => {},
-                                            Ok(Err(error)) => error!(%error, "Service failed"),
-                                            Err(error) => error!(%error, "Task failed"),
-                                        }));
-                                    }
-                                }
-                            }
+                                };
+                                let _ = tx.send(rsp);
+                            });
                         }
-                    }.instrument(span));
-
-                    serving.push(async move {
-                        let (m, r) = tokio::join!(muxer, server);
-                        debug!(?m, ?r, "Connection complete");
-                        let () = r?;
-                        m
-                    })
+                    }
                 }
             }
         }
+        .in_current_span(),
+    );
+
+    let (m, r) = tokio::join!(muxer, server);
+    debug!(?m, ?r, "Connection complete");
+    let () = r?;
+    m??;
+    Ok(())
+}
+
+/// Serves requests one at a time and in order, writing each response before the next request is
+/// read.
+async fn serve_serial<O, R, W>(
+    mut read: R,
+    mut write: W,
+    mut srv: O,
+    injector: Injector,
+    instance: Option<String>,
+    drain: Drain,
+    stats: Arc<muxer::ConnStats>,
+) -> Result<(), Error>
+where
+    O: Ort,
+    R: Stream<Item = io::Result<Frame<Message<Spec>>>> + Unpin,
+    W: Sink<Frame<Message<Response>>, Error = io::Error> + Unpin,
+{
+    tokio::pin! {
+        let closed = drain.signaled();
+    }
+
+    loop {
+        let frame = tokio::select! {
+            shutdown = (&mut closed) => {
+                debug!("Closing connection for shutdown");
+                drop(shutdown);
+                return Ok(());
+            }
+            frame = read.try_next() => frame?,
+        };
+        let (id, spec) = match frame {
+            Some(Frame {
+                id,
+                value: Message::Data(spec),
+            }) => (id, spec),
+            // Each response is written before the next request is read, so there is never a
+            // request to cancel.
+            Some(Frame {
+                id,
+                value: Message::Cancel,
+            }) => {
+                trace!(id, "Ignoring cancelation");
+                continue;
+            }
+            None => {
+                debug!("Client closed");
+                return Ok(());
+            }
+        };
+
+        trace!(id, "Serving request");
+        stats.set_in_flight(1);
+        let res = srv.ort(spec).await;
+        let rsp = response(res, &injector, spec.response_size, instance.clone());
+        write
+            .send(Frame {
+                id,
+                value: Message::Data(rsp),
+            })
+            .await?;
+        stats.set_in_flight(0);
+    }
+}
+
+/// Builds the response frame for a request. Failures, shed requests, and other errors are sent as
+/// error frames so that only the request fails; faults are injected into the connection.
+fn response(
+    res: Result<Reply, Error>,
+    injector: &Injector,
+    response_size: usize,
+    instance: Option<String>,
+) -> Response {
+    let error = match res {
+        Ok(reply) => return Response::Reply(Reply { instance, ..reply }),
+        Err(error) => error,
+    };
+    if let Some(failure) = error.downcast_ref::<Failure>() {
+        return Response::Failure(*failure);
+    }
+    if error.is::<Overloaded>() {
+        return Response::Overloaded;
+    }
+    match injector.recover(error, response_size) {
+        Ok(reply) => Response::Reply(Reply { instance, ..reply }),
+        Err(error) => {
+            debug!(%error, "Request failed");
+            Response::Error(error.to_string())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+
+    #[derive(Clone)]
+    struct Replies;
+
+    #[async_trait::async_trait]
+    impl Ort for Replies {
+        async fn ort(&mut self, _: Spec) -> Result<Reply, Error> {
+            Ok(Reply::default())
+        }
+    }
+
+    #[tokio::test]
+    async fn writes_banner() {
+        let lis = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("must bind");
+        let addr = lis.local_addr().expect("must have an address");
+        let (_close, closed) = drain::channel();
+        let server = Server::new(
+            Replies,
+            None,
+            Some("hello".to_string()),
+            muxer::Settings::default(),
+            None,
+            Default::default(),
+            Default::default(),
+        );
+        tokio::spawn(server.serve_listener(lis, closed));
+
+        for _ in 0..3 {
+            let mut sock = tokio::net::TcpStream::connect(addr)
+                .await
+                .expect("must connect");
+            let mut banner = [0u8; 7];
+            sock.read_exact(&mut banner)
+                .await
+                .expect("must read banner");
+            assert_eq!(&banner, b"hello\r\n");
+        }
     }
 }
@@ -0,0 +1,474 @@
+//! Raw byte streams for measuring throughput.
+//!
+//! Request/response framing hides the cost of moving bytes through an opaque proxy, so a
+//! throughput connection carries no framing at all. The client writes a header—the preface, a
+//! 1-byte direction, the 8-byte target rate in bytes per second (zero for unlimited), and the
+//! 8-byte duration in milliseconds—and then bytes flow in the requested directions until the
+//! duration elapses. Each side shuts down its write side once it is done sending, and reads until
+//! its peer does the same.
+
+use crate::next_or_pending;
+use drain::Watch as Drain;
+use futures::{prelude::*, stream::FuturesUnordered};
+use ort_core::Error;
+use std::{net::SocketAddr, str::FromStr};
+use tokio::{
+    io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    time,
+};
+use tracing::{debug, debug_span, error, info, Instrument};
+
+/// Serves throughput connections.
+#[derive(Clone, Debug, Default)]
+pub struct Server(());
+
+/// Describes a throughput test.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Settings {
+    pub direction: Direction,
+    /// Limits the number of bytes each side writes per second.
+    pub bytes_per_sec: Option<u64>,
+    pub duration: time::Duration,
+    /// The size of each write.
+    pub chunk_size: usize,
+}
+
+/// The directions in which bytes flow.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Direction {
+    /// The client writes to the server.
+    Upload,
+    /// The server writes to the client.
+    Download,
+    Both,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct InvalidDirection(());
+
+/// Describes the bytes transferred by one or more throughput connections.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Report {
+    pub sent: u64,
+    pub received: u64,
+    pub elapsed: time::Duration,
+    pub reads: ReadSizes,
+}
+
+/// Summarizes the sizes of reads so that bursty delivery can be distinguished from a smooth
+/// stream.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct ReadSizes {
+    count: u64,
+    sum: f64,
+    sum_squares: f64,
+}
+
+const PREFACE: &[u8] = b"ort.olix0r.net/throughput\r\n";
+
+const HEADER_LEN: usize = PREFACE.len() + 1 + 8 + 8;
+
+const READ_BUFFER_LEN: usize = 64 * 1024;
+
+/// The longest test that a server runs.
+const MAX_DURATION: time::Duration = time::Duration::from_secs(24 * 60 * 60);
+
+// === impl Server ===
+
+impl Server {
+    pub async fn serve(self, addr: SocketAddr, drain: Drain) -> Result<(), Error> {
+        let mut serving = FuturesUnordered::new();
+        let lis = tokio::net::TcpListener::bind(addr).await?;
+        info!("Listening on {}", addr);
+
+        tokio::pin! {
+            let closed = drain.clone().signaled();
+        }
+
+        loop {
+            tokio::select! {
+                shutdown = (&mut closed) => {
+                    // Throughput tests are bounded by their duration, so let them finish.
+                    debug!("Letting all connections complete before shutdown");
+                    while serving.next().await.is_some() {}
+                    drop(shutdown);
+                    return Ok(());
+                }
+
+                res = next_or_pending(&mut serving) => match res {
+                    Ok(Ok(_)) => {}
+                    Ok(Err(error)) => debug!(%error, "Connection failed"),
+                    Err(error) => error!(%error, "Connection task failed"),
+                },
+
+                acc = lis.accept() => match acc {
+                    Ok((sock, peer)) => {
+                        debug!(%peer, "Client connected");
+                        let conn = accept(sock).instrument(debug_span!("conn", %peer));
+                        serving.push(tokio::spawn(conn));
+                    }
+                    Err(error) => error!(%error, "Failed to accept connection"),
+                },
+            }
+        }
+    }
+}
+
+/// Reads a connection's header and streams bytes as it describes.
+async fn accept<T: AsyncRead + AsyncWrite>(io: T) -> io::Result<Report> {
+    let (mut rio, wio) = io::split(io);
+    let mut header = [0u8; HEADER_LEN];
+    rio.read_exact(&mut header).await?;
+    let settings = Settings::decode(&header)?;
+    debug!(?settings, "Streaming");
+
+    let report = transfer(
+        rio,
+        wio,
+        settings.direction.downloads(),
+        settings.bytes_per_sec,
+        settings.duration,
+        settings.chunk_size,
+    )
+    .await?;
+    debug!(%report, "Connection complete");
+    Ok(report)
+}
+
+// === impl Settings ===
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            direction: Direction::Download,
+            bytes_per_sec: None,
+            duration: time::Duration::from_secs(10),
+            chunk_size: 16 * 1024,
+        }
+    }
+}
+
+impl Settings {
+    /// Connects to `addr`, failing if the connection is not established within `timeout`, and
+    /// runs the test over the connection.
+    pub async fn connect(self, addr: &str, timeout: time::Duration) -> io::Result<Report> {
+        let sock = time::timeout(timeout, tokio::net::TcpStream::connect(addr))
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "connect timed out"))??;
+        sock.set_nodelay(true)?;
+        self.run(sock).await
+    }
+
+    /// Runs the test over an established connection.
+    pub async fn run<T: AsyncRead + AsyncWrite>(self, io: T) -> io::Result<Report> {
+        let (rio, mut wio) = io::split(io);
+        wio.write_all(&self.encode()).await?;
+        transfer(
+            rio,
+            wio,
+            self.direction.uploads(),
+            self.bytes_per_sec,
+            self.duration,
+            self.chunk_size,
+        )
+        .await
+    }
+
+    fn encode(&self) -> [u8; HEADER_LEN] {
+        let mut header = [0u8; HEADER_LEN];
+        let (preface, rest) = header.split_at_mut(PREFACE.len());
+        preface.copy_from_slice(PREFACE);
+        rest[0] = match self.direction {
+            Direction::Upload => 0,
+            Direction::Download => 1,
+            Direction::Both => 2,
+        };
+        rest[1..9].copy_from_slice(&self.bytes_per_sec.unwrap_or(0).to_be_bytes());
+        rest[9..17].copy_from_slice(&(self.duration.as_millis() as u64).to_be_bytes());
+        header
+    }
+
+    /// Decodes a client's header. The server writes in chunks of its own choosing.
+    fn decode(header: &[u8; HEADER_LEN]) -> io::Result<Self> {
+        let (preface, rest) = header.split_at(PREFACE.len());
+        if preface != PREFACE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "invalid throughput preface",
+            ));
+        }
+        let direction = match rest[0] {
+            0 => Direction::Upload,
+            1 => Direction::Download,
+            2 => Direction::Both,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "invalid throughput direction",
+                ))
+            }
+        };
+        let mut rate = [0u8; 8];
+        rate.copy_from_slice(&rest[1..9]);
+        let mut millis = [0u8; 8];
+        millis.copy_from_slice(&rest[9..17]);
+        let duration = time::Duration::from_millis(u64::from_be_bytes(millis));
+        if duration > MAX_DURATION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "throughput duration too long",
+            ));
+        }
+        Ok(Self {
+            direction,
+            bytes_per_sec: Some(u64::from_be_bytes(rate)).filter(|r| *r != 0),
+            duration,
+            ..Self::default()
+        })
+    }
+}
+
+/// Writes for `duration` (if `send` is set) while reading until the peer closes its write side.
+async fn transfer<R, W>(
+    rio: R,
+    mut wio: W,
+    send: bool,
+    bytes_per_sec: Option<u64>,
+    duration: time::Duration,
+    chunk_size: usize,
+) -> io::Result<Report>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let t0 = time::Instant::now();
+    let deadline = t0.checked_add(duration).ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidInput, "throughput duration too long")
+    })?;
+    let write = async move {
+        let sent = if send {
+            write_for(&mut wio, bytes_per_sec, deadline, chunk_size).await?
+        } else {
+            0
+        };
+        wio.shutdown().await?;
+        Ok::<_, io::Error>(sent)
+    };
+    let ((received, reads), sent) = tokio::try_join!(read_to_end(rio), write)?;
+    Ok(Report {
+        sent,
+        received,
+        elapsed: t0.elapsed(),
+        reads,
+    })
+}
+
+/// Writes chunks until `deadline`, pausing as needed so that at most `bytes_per_sec` are written
+/// each second.
+async fn write_for<W: AsyncWrite + Unpin>(
+    wio: &mut W,
+    bytes_per_sec: Option<u64>,
+    deadline: time::Instant,
+    chunk_size: usize,
+) -> io::Result<u64> {
+    let chunk = vec![0u8; chunk_size.max(1)];
+    let t0 = time::Instant::now();
+    let mut sent = 0u64;
+    loop {
+        if let Some(rate) = bytes_per_sec {
+            let due = t0 + time::Duration::from_secs_f64(sent as f64 / rate as f64);
+            if due >= deadline {
+                return Ok(sent);
+            }
+            time::sleep_until(due).await;
+        }
+        tokio::select! {
+            res = wio.write(&chunk) => match res? {
+                0 => return Err(io::ErrorKind::WriteZero.into()),
+                n => sent += n as u64,
+            },
+            _ = time::sleep_until(deadline) => return Ok(sent),
+        }
+    }
+}
+
+async fn read_to_end<R: AsyncRead + Unpin>(mut rio: R) -> io::Result<(u64, ReadSizes)> {
+    let mut buf = vec![0u8; READ_BUFFER_LEN];
+    let mut received = 0u64;
+    let mut reads = ReadSizes::default();
+    loop {
+        match rio.read(&mut buf).await? {
+            0 => return Ok((received, reads)),
+            n => {
+                received += n as u64;
+                reads.record(n);
+            }
+        }
+    }
+}
+
+// === impl Direction ===
+
+impl Direction {
+    fn uploads(self) -> bool {
+        matches!(self, Self::Upload | Self::Both)
+    }
+
+    fn downloads(self) -> bool {
+        matches!(self, Self::Download | Self::Both)
+    }
+}
+
+impl FromStr for Direction {
+    type Err = InvalidDirection;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "upload" => Ok(Self::Upload),
+            "download" => Ok(Self::Download),
+            "both" => Ok(Self::Both),
+            _ => Err(InvalidDirection(())),
+        }
+    }
+}
+
+impl std::fmt::Display for InvalidDirection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid direction; expected upload, download, or both")
+    }
+}
+
+impl std::error::Error for InvalidDirection {}
+
+// === impl Report ===
+
+impl Report {
+    /// Combines the reports of concurrent connections.
+    pub fn merge(self, other: Self) -> Self {
+        Self {
+            sent: self.sent + other.sent,
+            received: self.received + other.received,
+            elapsed: self.elapsed.max(other.elapsed),
+            reads: ReadSizes {
+                count: self.reads.count + other.reads.count,
+                sum: self.reads.sum + other.reads.sum,
+                sum_squares: self.reads.sum_squares + other.reads.sum_squares,
+            },
+        }
+    }
+
+    pub fn sent_per_sec(&self) -> f64 {
+        self.sent as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+
+    pub fn received_per_sec(&self) -> f64 {
+        self.received as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+}
+
+impl std::fmt::Display for Report {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "sent={}B received={}B elapsed={:?} sent_per_sec={:.0}B received_per_sec={:.0}B \
+             reads={} read_size_mean={:.0}B read_size_stddev={:.0}B",
+            self.sent,
+            self.received,
+            self.elapsed,
+            self.sent_per_sec(),
+            self.received_per_sec(),
+            self.reads.count,
+            self.reads.mean(),
+            self.reads.stddev(),
+        )
+    }
+}
+
+// === impl ReadSizes ===
+
+impl ReadSizes {
+    fn record(&mut self, size: usize) {
+        let size = size as f64;
+        self.count += 1;
+        self.sum += size;
+        self.sum_squares += size * size;
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn mean(&self) -> f64 {
+        if self.count == 0 {
+            return 0.0;
+        }
+        self.sum / self.count as f64
+    }
+
+    /// The standard deviation of read sizes.
+    pub fn stddev(&self) -> f64 {
+        if self.count == 0 {
+            return 0.0;
+        }
+        let mean = self.mean();
+        (self.sum_squares / self.count as f64 - mean * mean)
+            .max(0.0)
+            .sqrt()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn both_directions() {
+        let (client, server) = io::duplex(1024);
+        let settings = Settings {
+            direction: Direction::Both,
+            bytes_per_sec: None,
+            duration: time::Duration::from_millis(20),
+            chunk_size: 100,
+        };
+        let (c, s) = tokio::join!(settings.run(client), accept(server));
+        let (c, s) = (
+            c.expect("client must succeed"),
+            s.expect("server must succeed"),
+        );
+        assert!(c.sent > 0 && s.sent > 0);
+        assert_eq!(c.sent, s.received);
+        assert_eq!(s.sent, c.received);
+        assert!(c.reads.count() > 0 && c.reads.mean() > 0.0);
+    }
+
+    #[tokio::test]
+    async fn upload_only() {
+        let (client, server) = io::duplex(1024);
+        let settings = Settings {
+            direction: Direction::Upload,
+            duration: time::Duration::from_millis(20),
+            ..Settings::default()
+        };
+        let (c, s) = tokio::join!(settings.run(client), accept(server));
+        let (c, s) = (
+            c.expect("client must succeed"),
+            s.expect("server must succeed"),
+        );
+        assert_eq!(c.sent, s.received);
+        assert_eq!((c.received, s.sent), (0, 0));
+    }
+
+    #[test]
+    fn rejects_long_durations() {
+        let settings = Settings {
+            duration: MAX_DURATION,
+            ..Settings::default()
+        };
+        assert!(Settings::decode(&settings.encode()).is_ok());
+        let settings = Settings {
+            duration: time::Duration::from_millis(u64::MAX),
+            ..Settings::default()
+        };
+        assert!(Settings::decode(&settings.encode()).is_err());
+    }
+}
@@ -0,0 +1,435 @@
+//! Frames for version 2 of the protocol.
+//!
+//! Requests and responses share a 16-byte header: an 8-byte request ID, a 1-byte kind, 1 byte of
+//! flags, a 2-byte status, and the 4-byte length of the payload that follows. Receivers ignore
+//! flags that they do not understand.
+//!
+//! Payloads may be extended by appending fields: receivers ignore trailing fields that they do not
+//! understand and use defaults for fields that are absent.
+
+use crate::{
+    muxer::{Frame, Message},
+    Response,
+};
+use bytes::{Buf, BufMut, BytesMut};
+use ort_core::{Failure, Hops, Reply, Spec, StreamSpec};
+use std::marker::PhantomData;
+use tokio::{io, time};
+use tokio_util::codec::{Decoder, Encoder};
+
+/// Encodes and decodes frames carrying a `B`.
+#[derive(Debug)]
+pub(crate) struct Codec<B>(PhantomData<fn(B) -> B>);
+
+/// The contents of a frame.
+pub(crate) trait Body: Sized {
+    /// Writes the body's payload, returning the frame's kind and status.
+    fn encode(self, dst: &mut BytesMut) -> io::Result<Head>;
+
+    fn decode(head: Head, payload: BytesMut) -> io::Result<Self>;
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+pub(crate) struct Head {
+    kind: u8,
+    flags: u8,
+    status: u16,
+}
+
+const HEAD_LEN: usize = 8 + 1 + 1 + 2 + 4;
+
+/// The most buffer space that the decoder reserves ahead of a frame's payload. A frame's length is
+/// chosen by the peer, so larger payloads are buffered only as their bytes arrive.
+const MAX_RESERVE: usize = 64 * 1024;
+
+const KIND_DATA: u8 = 0;
+const KIND_CANCEL: u8 = 1;
+const KIND_FAILURE: u8 = 2;
+const KIND_OVERLOADED: u8 = 3;
+const KIND_ERROR: u8 = 4;
+
+// === impl Codec ===
+
+impl<B> Default for Codec<B> {
+    fn default() -> Self {
+        Self(PhantomData)
+    }
+}
+
+impl<B: Body> Decoder for Codec<B> {
+    type Item = Frame<Message<B>>;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<Frame<Message<B>>>> {
+        if src.len() < HEAD_LEN {
+            return Ok(None);
+        }
+        let len = (&src[HEAD_LEN - 4..HEAD_LEN]).get_u32() as usize;
+        if src.len() < HEAD_LEN + len {
+            src.reserve((HEAD_LEN + len - src.len()).min(MAX_RESERVE));
+            return Ok(None);
+        }
+
+        let id = src.get_u64();
+        let head = Head {
+            kind: src.get_u8(),
+            flags: src.get_u8(),
+            status: src.get_u16(),
+        };
+        src.advance(4);
+        let payload = src.split_to(len);
+        let value = match head.kind {
+            KIND_CANCEL => Message::Cancel,
+            _ => Message::Data(B::decode(head, payload)?),
+        };
+        Ok(Some(Frame { id, value }))
+    }
+}
+
+impl<B: Body> Encoder<Frame<Message<B>>> for Codec<B> {
+    type Error = io::Error;
+
+    fn encode(
+        &mut self,
+        Frame { id, value }: Frame<Message<B>>,
+        dst: &mut BytesMut,
+    ) -> io::Result<()> {
+        let mut payload = BytesMut::new();
+        let head = match value {
+            Message::Data(body) => body.encode(&mut payload)?,
+            Message::Cancel => Head {
+                kind: KIND_CANCEL,
+                ..Head::default()
+            },
+        };
+        if payload.len() > std::u32::MAX as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "frame payload too long",
+            ));
+        }
+
+        dst.reserve(HEAD_LEN + payload.len());
+        dst.put_u64(id);
+        dst.put_u8(head.kind);
+        dst.put_u8(head.flags);
+        dst.put_u16(head.status);
+        dst.put_u32(payload.len() as u32);
+        dst.put(payload);
+        Ok(())
+    }
+}
+
+// === impl Spec ===
+
+/// A request's payload holds its latency in microseconds, its response size, the number of
+/// streamed messages, and the stream interval in microseconds, each as 8 bytes. The stream fields
+/// may be omitted.
+impl Body for Spec {
+    fn encode(self, dst: &mut BytesMut) -> io::Result<Head> {
+        dst.reserve(8 * 4);
+        dst.put_u64(self.latency.as_micros() as u64);
+        dst.put_u64(self.response_size as u64);
+        dst.put_u64(self.stream.messages as u64);
+        dst.put_u64(self.stream.interval.as_micros() as u64);
+        Ok(Head::default())
+    }
+
+    fn decode(head: Head, mut payload: BytesMut) -> io::Result<Self> {
+        if head.kind != KIND_DATA {
+            return Err(invalid("unknown request frame"));
+        }
+        if payload.len() < 8 + 8 {
+            return Err(invalid("request frame too short"));
+        }
+        let latency = time::Duration::from_micros(payload.get_u64());
+        let response_size = payload.get_u64() as usize;
+        let mut stream = StreamSpec::default();
+        if payload.len() >= 8 + 8 {
+            stream.messages = payload.get_u64() as usize;
+            stream.interval = time::Duration::from_micros(payload.get_u64());
+        }
+        Ok(Spec {
+            latency,
+            response_size,
+            stream,
+        })
+    }
+}
+
+// === impl Response ===
+
+/// A reply's payload starts with the 2-byte length of its metadata, followed by the metadata and
+/// then the reply's data. The metadata holds the server's instance ID and its hops (formatted as
+/// `Hops`), each prefixed by a 2-byte length.
+///
+/// A failure's status is carried in the frame's header. Failures and overloaded frames have no
+/// payload. An error's payload is its UTF-8 message.
+impl Body for Response {
+    fn encode(self, dst: &mut BytesMut) -> io::Result<Head> {
+        match self {
+            Response::Reply(Reply {
+                data,
+                instance,
+                hops,
+            }) => {
+                let instance = instance.unwrap_or_default();
+                let hops = Hops(hops).to_string();
+                let meta_len = 2 + instance.len() + 2 + hops.len();
+                if meta_len > std::u16::MAX as usize {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "reply metadata too long",
+                    ));
+                }
+                dst.reserve(2 + meta_len + data.len());
+                dst.put_u16(meta_len as u16);
+                put_str(dst, &instance);
+                put_str(dst, &hops);
+                dst.put_slice(&data);
+                Ok(Head::default())
+            }
+            Response::Failure(Failure { status }) => Ok(Head {
+                kind: KIND_FAILURE,
+                status: status.unwrap_or(0),
+                ..Head::default()
+            }),
+            Response::Overloaded => Ok(Head {
+                kind: KIND_OVERLOADED,
+                ..Head::default()
+            }),
+            Response::Error(message) => {
+                dst.put_slice(message.as_bytes());
+                Ok(Head {
+                    kind: KIND_ERROR,
+                    ..Head::default()
+                })
+            }
+        }
+    }
+
+    fn decode(head: Head, mut payload: BytesMut) -> io::Result<Self> {
+        match head.kind {
+            KIND_DATA => {
+                if payload.len() < 2 {
+                    return Err(invalid("reply frame too short"));
+                }
+                let meta_len = payload.get_u16() as usize;
+                if payload.len() < meta_len {
+                    return Err(invalid("reply metadata exceeds frame"));
+                }
+                let mut meta = payload.split_to(meta_len);
+                let instance = get_str(&mut meta)?;
+                let hops = get_str(&mut meta)?
+                    .parse::<Hops>()
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                Ok(Response::Reply(Reply {
+                    data: payload.freeze(),
+                    instance: Some(instance).filter(|i| !i.is_empty()),
+                    hops: hops.0,
+                }))
+            }
+            KIND_FAILURE => Ok(Response::Failure(Failure {
+                status: Some(head.status).filter(|s| *s != 0),
+            })),
+            KIND_OVERLOADED => Ok(Response::Overloaded),
+            KIND_ERROR => String::from_utf8(payload.to_vec())
+                .map(Response::Error)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+            _ => Err(invalid("unknown response frame")),
+        }
+    }
+}
+
+fn invalid(msg: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Reads a string prefixed by its 2-byte length.
+fn get_str(buf: &mut BytesMut) -> io::Result<String> {
+    if buf.len() < 2 {
+        return Err(invalid("reply metadata too short"));
+    }
+    let len = buf.get_u16() as usize;
+    if buf.len() < len {
+        return Err(invalid("reply field exceeds metadata"));
+    }
+    String::from_utf8(buf.split_to(len).to_vec())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Writes a string prefixed by its 2-byte length. The caller must ensure that the string fits.
+fn put_str(buf: &mut BytesMut, s: &str) {
+    buf.put_u16(s.len() as u16);
+    buf.put_slice(s.as_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use ort_core::Hop;
+
+    fn decode<B: Body>(buf: &mut BytesMut) -> Message<B> {
+        Codec::<B>::default()
+            .decode(buf)
+            .expect("must decode")
+            .expect("must decode")
+            .value
+    }
+
+    #[test]
+    fn roundtrip_spec() {
+        let spec = Spec {
+            latency: time::Duration::from_micros(1500),
+            response_size: 1 << 40,
+            stream: StreamSpec {
+                messages: 3,
+                interval: time::Duration::from_millis(10),
+            },
+        };
+        let mut buf = BytesMut::new();
+        let mut enc = Codec::default();
+        enc.encode(
+            Frame {
+                id: 1,
+                value: Message::Data(spec),
+            },
+            &mut buf,
+        )
+        .expect("must encode");
+        enc.encode(
+            Frame {
+                id: 2,
+                value: Message::Cancel,
+            },
+            &mut buf,
+        )
+        .expect("must encode");
+
+        let mut dec = Codec::<Spec>::default();
+        let f0 = dec
+            .decode(&mut buf)
+            .expect("must decode")
+            .expect("must decode");
+        let f1 = dec
+            .decode(&mut buf)
+            .expect("must decode")
+            .expect("must decode");
+        assert_eq!(f0.id, 1);
+        assert_eq!(f0.value, Message::Data(spec));
+        assert_eq!(f1.id, 2);
+        assert_eq!(f1.value, Message::Cancel);
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn extended_spec() {
+        // Stream fields may be omitted, and unknown trailing fields are ignored.
+        let mut buf = BytesMut::new();
+        buf.put_u64(1);
+        buf.put_slice(&[KIND_DATA, 0xff, 0, 0]);
+        buf.put_u32(8 + 8);
+        buf.put_u64(2000);
+        buf.put_u64(3);
+        buf.put_u64(2);
+        buf.put_slice(&[KIND_DATA, 0, 0, 0]);
+        buf.put_u32(8 * 5);
+        for field in [2000, 3, 4, 5, 6] {
+            buf.put_u64(field);
+        }
+
+        assert_eq!(
+            decode::<Spec>(&mut buf),
+            Message::Data(Spec {
+                latency: time::Duration::from_millis(2),
+                response_size: 3,
+                ..Spec::default()
+            })
+        );
+        assert_eq!(
+            decode::<Spec>(&mut buf),
+            Message::Data(Spec {
+                latency: time::Duration::from_millis(2),
+                response_size: 3,
+                stream: StreamSpec {
+                    messages: 4,
+                    interval: time::Duration::from_micros(5),
+                },
+            })
+        );
+    }
+
+    #[test]
+    fn roundtrip_responses() {
+        let rsps = vec![
+            Response::Reply(Reply {
+                data: Bytes::from_static(b"abcdef"),
+                instance: Some("server-0".to_string()),
+                hops: vec![Hop {
+                    instance: Some("server-1".to_string()),
+                    latency: time::Duration::from_micros(1500),
+                }],
+            }),
+            Response::Reply(Reply {
+                data: Bytes::from_static(b"ghijkl"),
+                ..Reply::default()
+            }),
+            Response::Failure(Failure { status: Some(14) }),
+            Response::Failure(Failure { status: None }),
+            Response::Overloaded,
+            Response::Error("upstream unavailable".to_string()),
+        ];
+
+        let mut buf = BytesMut::new();
+        let mut enc = Codec::default();
+        for rsp in rsps.iter().cloned() {
+            enc.encode(
+                Frame {
+                    id: 1,
+                    value: Message::Data(rsp),
+                },
+                &mut buf,
+            )
+            .expect("must encode");
+        }
+        for rsp in rsps.into_iter() {
+            assert_eq!(decode::<Response>(&mut buf), Message::Data(rsp));
+        }
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn bounds_reservations() {
+        let mut buf = BytesMut::new();
+        buf.put_u64(1);
+        buf.put_u8(KIND_DATA);
+        buf.put_u8(0);
+        buf.put_u16(0);
+        buf.put_u32(u32::MAX);
+        let frame = Codec::<Spec>::default()
+            .decode(&mut buf)
+            .expect("must accept the frame's head");
+        assert!(frame.is_none());
+        assert!(buf.capacity() <= HEAD_LEN + MAX_RESERVE);
+    }
+
+    #[test]
+    fn roundtrip_long_replies() {
+        let rsp = Response::Reply(Reply {
+            data: vec![7; 9 * 1024 * 1024].into(),
+            ..Reply::default()
+        });
+        let mut buf = BytesMut::new();
+        Codec::default()
+            .encode(
+                Frame {
+                    id: 1,
+                    value: Message::Data(rsp.clone()),
+                },
+                &mut buf,
+            )
+            .expect("must encode");
+        assert_eq!(decode::<Response>(&mut buf), Message::Data(rsp));
+    }
+}